@@ -0,0 +1,307 @@
+use crate::cache::CacheLookup;
+use crate::events::ActorEvent;
+use crate::golem_isolate::{GolemIsolate, GolemSnapshot};
+use crate::lifecycle::LifecycleHook;
+use crate::message::Message;
+use crate::ops;
+use crate::registry::Version;
+use crate::replies::ReplyId;
+use crate::resources::Resources;
+use crate::sockets::{SocketEvent, SocketId};
+use crate::state::State;
+use crate::streams::{BodyReceiver, IncomingBody};
+use crate::topics::Delivery;
+use crate::transaction;
+use deno_core::ErrBox;
+use serde_json::Value;
+
+pub type ActorId = String;
+
+/// A running actor: an isolate restored from its type's snapshot together
+/// with the identity that is exposed to its handlers through `ctx`.
+pub struct Actor {
+    id: ActorId,
+    isolate: Box<GolemIsolate>,
+    resources: Resources,
+    state: State,
+}
+
+fn create_isolate(id: &str, snapshot: GolemSnapshot, state: &State) -> (Box<GolemIsolate>, Resources) {
+    let mut isolate = GolemIsolate::new(snapshot);
+    let resources = ops::init(&mut isolate, state, id);
+    (isolate, resources)
+}
+
+impl Actor {
+    /// Creates a new actor, its initial state is computed by `init`.
+    pub fn spawn(id: ActorId, snapshot: GolemSnapshot, state: &State) -> Result<Self, ErrBox> {
+        let (isolate, resources) = create_isolate(&id, snapshot, state);
+        let mut actor = Self {
+            isolate,
+            resources,
+            id,
+            state: state.clone(),
+        };
+        let ctx = actor.context();
+        actor.isolate.invoke_hook(LifecycleHook::Init, &ctx)?;
+        Ok(actor)
+    }
+
+    /// Brings a passivated actor back with the state it was persisted with.
+    pub fn rehydrate(id: ActorId, snapshot: GolemSnapshot, state: &State, actor_state: &Message) -> Result<Self, ErrBox> {
+        let (isolate, resources) = create_isolate(&id, snapshot, state);
+        let mut actor = Self {
+            isolate,
+            resources,
+            id,
+            state: state.clone(),
+        };
+        actor.isolate.set_state(actor_state)?;
+        let ctx = actor.context();
+        actor.isolate.invoke_hook(LifecycleHook::Activate, &ctx)?;
+        Ok(actor)
+    }
+
+    pub fn id(&self) -> &str {
+        &self.id
+    }
+
+    pub fn isolate(&mut self) -> &mut GolemIsolate {
+        &mut self.isolate
+    }
+
+    pub fn context(&self) -> Value {
+        json!({ "actorId": self.id })
+    }
+
+    pub fn send(&mut self, msg: &Message) -> Result<Message, ErrBox> {
+        let ctx = self.context();
+        self.invoke(msg, &ctx)
+    }
+
+    /// Like `send`, for a caller that is registered in the replies table
+    /// under `reply_id`, which the handler can answer through `ctx`.
+    pub fn ask(&mut self, msg: &Message, reply_id: ReplyId) -> Result<Message, ErrBox> {
+        let mut ctx = self.context();
+        ctx["replyId"] = json!(reply_id);
+        self.invoke(msg, &ctx)
+    }
+
+    /// Delivers a message published to a topic the actor subscribed to.
+    /// `ctx.deliveryId` is the same for every attempt to deliver it.
+    pub fn deliver(&mut self, delivery: &Delivery) -> Result<Message, ErrBox> {
+        let mut ctx = self.context();
+        ctx["topic"] = json!(delivery.topic);
+        ctx["deliveryId"] = json!(delivery.id);
+        self.invoke(&delivery.message, &ctx)
+    }
+
+    /// Passes an HTTP request to the actor's `fetch` export, whose
+    /// `Response` answers the caller registered under `reply_id`. `request`
+    /// holds the method, URL and headers, the body is read by the actor
+    /// as it arrives.
+    pub fn serve(&mut self, request: &Value, body: BodyReceiver, reply_id: ReplyId) -> Result<Value, ErrBox> {
+        let rid = self.resources.borrow_mut().add(Box::new(IncomingBody::new(body)))?;
+        let mut request = request.clone();
+        request["bodyRid"] = json!(rid);
+        let mut ctx = self.context();
+        ctx["replyId"] = json!(reply_id);
+        self.transact(true, |isolate| isolate.invoke_fetch(&request, &ctx))
+    }
+
+    /// Passes an event of a WebSocket connected to the actor to `main`,
+    /// with `ctx.socket` identifying the connection.
+    pub fn socket_event(&mut self, msg: &Message, socket_id: SocketId, event: SocketEvent) -> Result<Message, ErrBox> {
+        let mut ctx = self.context();
+        ctx["socket"] = json!({ "id": socket_id, "event": event.name() });
+        self.invoke(msg, &ctx)
+    }
+
+    /// Runs `main`, unless the actor memoized the reply to the message, in
+    /// which case it is returned without starting a transaction. Read-only
+    /// messages run without the state being copied, see `crate::cache`.
+    fn invoke(&mut self, msg: &Message, ctx: &Value) -> Result<Message, ErrBox> {
+        let directive = match self.isolate.lookup_cache(msg, ctx)? {
+            CacheLookup::Hit(reply) => return Ok(reply),
+            CacheLookup::Miss(directive) => Some(directive),
+            CacheLookup::Bypass => None,
+        };
+
+        let state = self.state.clone();
+        let reply_id = ctx.get("replyId").and_then(Value::as_u64);
+        self.transact(directive.is_none(), |isolate| {
+            let response = isolate.invoke_main(msg, ctx)?;
+            if let Some(directive) = directive.as_ref() {
+                let reply = reply_id.and_then(|reply_id| transaction::sent_reply(&state, reply_id));
+                isolate.memoize(directive, reply.unwrap_or_else(|| response.clone()));
+            }
+            Ok(response)
+        })
+    }
+
+    /// Runs a handler in a transaction. If it throws, its storage writes and
+    /// published messages are discarded and the state is reset to what it
+    /// was before, even if the handler mutated it in place. If it returns,
+    /// a change of the state is sent to the actor's event listeners.
+    /// Without `copy_state`, changes made in place are not undone, see
+    /// `GolemIsolate::checkpoint_state`.
+    fn transact<F, T>(&mut self, copy_state: bool, handler: F) -> Result<T, ErrBox>
+        where
+            F: FnOnce(&mut GolemIsolate) -> Result<T, ErrBox>,
+    {
+        self.isolate.checkpoint_state(copy_state)?;
+        transaction::begin(&self.state, &self.id);
+        let result = handler(&mut self.isolate);
+
+        match result {
+            Ok(_) => {
+                transaction::commit(&self.state);
+                let listening = self.state.borrow().events.has_listeners(&self.id);
+                match self.isolate.commit_state(listening) {
+                    Ok(Some(state)) => {
+                        self.state.borrow_mut().events.send(&self.id, ActorEvent::State(state.into_json()));
+                    }
+                    Ok(None) => {}
+                    Err(error) => warn!("actor {} state could not be sent to its listeners: {}", self.id, error),
+                }
+            }
+            Err(_) => {
+                transaction::rollback(&self.state);
+                self.isolate.rollback_state();
+            }
+        }
+        result
+    }
+
+    /// Moves the actor onto a new version of its script. The state is taken
+    /// from the old isolate after `onPassivate`, transformed by the new
+    /// script's `migrate(oldState, fromVersion)` export if it has one, and
+    /// handed to `onActivate`. The old isolate is kept if any step fails,
+    /// and the error of that step returned.
+    pub fn hot_swap(&mut self, snapshot: GolemSnapshot, from_version: Version) -> Result<(), ErrBox> {
+        let ctx = self.context();
+        self.isolate.invoke_hook(LifecycleHook::Passivate, &ctx)?;
+        let result = self.isolate.persisted_state().and_then(|state| {
+            let (mut isolate, resources) = create_isolate(&self.id, snapshot, &self.state);
+            isolate
+                .set_state(&state)
+                .and_then(|_| isolate.invoke_migrate(from_version, &ctx))
+                .and_then(|_| isolate.invoke_hook(LifecycleHook::Activate, &ctx))
+                .map(|_| (isolate, resources))
+        });
+
+        match result {
+            Ok((isolate, resources)) => {
+                self.isolate = isolate;
+                self.resources = resources;
+                Ok(())
+            }
+            Err(error) => {
+                if let Err(activate_error) = self.isolate.invoke_hook(LifecycleHook::Activate, &ctx) {
+                    error!("actor {} failed to reactivate on its previous version: {}", self.id, activate_error);
+                }
+                Err(error)
+            }
+        }
+    }
+
+    /// Releases the isolate, returning the state that should be persisted,
+    /// unless encoding it threw. The actor is passivated even if
+    /// `onPassivate` fails.
+    pub fn passivate(mut self) -> (Result<Message, ErrBox>, Result<(), ErrBox>) {
+        let ctx = self.context();
+        let result = self.isolate.invoke_hook(LifecycleHook::Passivate, &ctx);
+        (self.isolate.persisted_state(), result)
+    }
+
+    /// Gives the actor a last chance to clean up before it is deleted.
+    pub fn stop(mut self) -> Result<(), ErrBox> {
+        let ctx = self.context();
+        self.isolate.invoke_hook(LifecycleHook::Stop, &ctx)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::events::event_channel;
+    use crate::runtime::testing;
+    use deno_core::Script;
+
+    /// Changes the state in place and writes to storage before it throws.
+    const ACTOR: &str = "function init() { return { count: 0, names: [] }; }\n\
+        function main(state, msg, ctx) {\n\
+            state.count += 1;\n\
+            state.names.push(msg.name);\n\
+            ctx.storage.put(msg.name, state.count);\n\
+            if (msg.fail) { throw new Error('boom'); }\n\
+            return msg.keep ? state : { ...state };\n\
+        }";
+
+    /// Counts in storage how often `main` runs for the read-only "get".
+    const CACHED: &str = "function init() { return { count: 1 }; }\n\
+        function cache(msg) { return msg.get ? 'get' : null; }\n\
+        function main(state, msg, ctx) {\n\
+            if (msg.get) { ctx.storage.put('reads', (ctx.storage.get('reads') || 0) + 1); }\n\
+            return state;\n\
+        }";
+
+    fn spawn(source: &'static str) -> (Actor, State) {
+        let state = testing::state();
+        let snapshot = GolemIsolate::try_create_snapshot(Script { source, filename: "actor.js" }).unwrap();
+        let actor = Actor::spawn("a".to_string(), snapshot, &state).unwrap();
+        (actor, state)
+    }
+
+    #[test]
+    fn test_failed_invocation_rolls_back_state_and_storage() {
+        let (mut actor, state) = spawn(ACTOR);
+        actor.send(&Message::Json(json!({ "name": "first" }))).unwrap();
+        assert!(actor.send(&Message::Json(json!({ "name": "second", "fail": true }))).is_err());
+
+        assert_eq!(actor.isolate().get_state().unwrap(), Message::Json(json!({ "count": 1, "names": ["first"] })));
+        let mut state = state.borrow_mut();
+        let storage = state.storage.actor("a");
+        assert_eq!(storage.get("first"), Some(&json!(1)));
+        assert_eq!(storage.get("second"), None);
+    }
+
+    #[test]
+    fn test_invocation_after_rollback_sees_previous_state() {
+        let (mut actor, _state) = spawn(ACTOR);
+        actor.send(&Message::Json(json!({ "name": "first", "keep": true }))).unwrap();
+        assert!(actor.send(&Message::Json(json!({ "name": "second", "fail": true }))).is_err());
+        actor.send(&Message::Json(json!({ "name": "third", "keep": true }))).unwrap();
+
+        let expected = json!({ "count": 2, "names": ["first", "third"] });
+        assert_eq!(actor.isolate().get_state().unwrap(), Message::Json(expected));
+    }
+
+    #[test]
+    fn test_listeners_receive_changed_state() {
+        let (mut actor, state) = spawn(ACTOR);
+        let (listener, mut events) = event_channel();
+        state.borrow_mut().events.listen("a", listener);
+
+        actor.send(&Message::Json(json!({ "name": "first" }))).unwrap();
+        assert!(actor.send(&Message::Json(json!({ "name": "second", "fail": true }))).is_err());
+
+        let expected = ActorEvent::State(json!({ "count": 1, "names": ["first"] }));
+        assert_eq!(events.try_next().unwrap(), Some(expected));
+        assert!(events.try_next().is_err());
+    }
+
+    #[test]
+    fn test_cache_hit_does_not_run_main() {
+        let (mut actor, state) = spawn(CACHED);
+        let (listener, mut events) = event_channel();
+        state.borrow_mut().events.listen("a", listener);
+
+        let get = Message::Json(json!({ "get": true }));
+        assert_eq!(actor.send(&get).unwrap(), Message::Json(json!({ "count": 1 })));
+        assert_eq!(actor.send(&get).unwrap(), Message::Json(json!({ "count": 1 })));
+
+        assert_eq!(state.borrow_mut().storage.actor("a").get("reads"), Some(&json!(1)));
+        assert!(events.try_next().is_err());
+    }
+}
@@ -0,0 +1,201 @@
+//! Memoization of `main` responses driven by the actor's `cache` export.
+//!
+//! `cache(msg, ctx)` is called before `main`. Returning `undefined` or `null`
+//! marks the message as one that may change state, anything else marks it
+//! as read-only and names the key its response is memoized under:
+//!
+//! ```js
+//! function cache(msg, ctx) {
+//!     if (msg.type === "get") {
+//!         return { key: "get", ttl: 1000 };
+//!     }
+//! }
+//! ```
+//!
+//! A bare string can be returned instead of an object when no TTL is needed.
+//! Since read-only messages leave the state untouched, cached responses stay
+//! valid until a message that is not cacheable runs, which clears the cache.
+//! So does anything else that may change the state: `fetch`, lifecycle
+//! hooks, migrations and restoring a persisted state.
+//!
+//! The response memoized is the reply the caller received, i.e. what `main`
+//! passed to `ctx.reply` or, without such a call, what it returned.
+//!
+//! `cache` runs before the invocation's transaction is started (see
+//! `crate::transaction`), and a memoized reply is returned without starting
+//! one. A read-only message that misses runs `main` on the state itself
+//! rather than on a copy, so changes it makes in place are not undone.
+
+use crate::message::Message;
+use serde_json::Value;
+use std::collections::HashMap;
+use std::time::{Duration, Instant};
+
+#[derive(Deserialize, Debug, Clone, PartialEq)]
+#[serde(untagged)]
+pub enum CacheDirective {
+    Key(String),
+    Entry {
+        key: String,
+        /// Time to live in milliseconds, entries never expire if absent.
+        ttl: Option<u64>,
+    },
+}
+
+impl CacheDirective {
+    /// Interprets the value returned by the `cache` export.
+    pub fn from_value(value: Value) -> Result<Option<Self>, serde_json::Error> {
+        if value.is_null() {
+            return Ok(None);
+        }
+        serde_json::from_value(value).map(Some)
+    }
+
+    pub fn key(&self) -> &str {
+        match self {
+            CacheDirective::Key(key) => key,
+            CacheDirective::Entry { key, .. } => key,
+        }
+    }
+
+    pub fn ttl(&self) -> Option<Duration> {
+        match self {
+            CacheDirective::Key(_) => None,
+            CacheDirective::Entry { ttl, .. } => ttl.map(Duration::from_millis),
+        }
+    }
+}
+
+/// What `GolemIsolate::lookup_cache` found for a message.
+#[derive(Debug, Clone, PartialEq)]
+pub enum CacheLookup {
+    /// The memoized reply, `main` does not run.
+    Hit(Message),
+    /// The message is read-only, but `main` has to run. Its reply is
+    /// memoized under the directive.
+    Miss(CacheDirective),
+    /// The message may change state.
+    Bypass,
+}
+
+#[derive(Default, Debug, Clone, Copy, PartialEq, Serialize)]
+pub struct CacheMetrics {
+    pub hits: u64,
+    pub misses: u64,
+    /// Messages for which `cache` declined to provide a key.
+    pub bypasses: u64,
+}
+
+struct CacheEntry {
+    response: Message,
+    expires_at: Option<Instant>,
+}
+
+#[derive(Default)]
+pub struct ResponseCache {
+    entries: HashMap<String, CacheEntry>,
+    metrics: CacheMetrics,
+}
+
+impl ResponseCache {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn metrics(&self) -> CacheMetrics {
+        self.metrics
+    }
+
+    /// Looks up a response, counting the lookup as a hit or a miss.
+    pub fn get(&mut self, directive: &CacheDirective, now: Instant) -> Option<Message> {
+        let expired = match self.entries.get(directive.key()) {
+            Some(CacheEntry { expires_at: Some(expires_at), .. }) => *expires_at <= now,
+            _ => false,
+        };
+        if expired {
+            self.entries.remove(directive.key());
+        }
+
+        match self.entries.get(directive.key()) {
+            Some(entry) => {
+                self.metrics.hits += 1;
+                Some(entry.response.clone())
+            }
+            None => {
+                self.metrics.misses += 1;
+                None
+            }
+        }
+    }
+
+    pub fn insert(&mut self, directive: &CacheDirective, response: Message, now: Instant) {
+        let entry = CacheEntry {
+            response,
+            expires_at: directive.ttl().map(|ttl| now + ttl),
+        };
+        self.entries.insert(directive.key().to_string(), entry);
+    }
+
+    /// Called when a message that may have changed state is processed.
+    pub fn invalidate(&mut self) {
+        self.metrics.bypasses += 1;
+        self.clear();
+    }
+
+    /// Called when the state is changed other than by a message, e.g. by
+    /// `fetch`, a lifecycle hook or a migration.
+    pub fn clear(&mut self) {
+        self.entries.clear();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_directive_from_value() {
+        assert_eq!(CacheDirective::from_value(json!(null)).unwrap(), None);
+        assert_eq!(
+            CacheDirective::from_value(json!("count")).unwrap(),
+            Some(CacheDirective::Key("count".to_string()))
+        );
+        let directive = CacheDirective::from_value(json!({ "key": "count", "ttl": 50 }))
+            .unwrap()
+            .unwrap();
+        assert_eq!(directive.key(), "count");
+        assert_eq!(directive.ttl(), Some(Duration::from_millis(50)));
+        assert!(CacheDirective::from_value(json!(42)).is_err());
+    }
+
+    #[test]
+    fn test_hit_miss_and_expiry() {
+        let mut cache = ResponseCache::new();
+        let now = Instant::now();
+        let directive = CacheDirective::Entry {
+            key: "count".to_string(),
+            ttl: Some(10),
+        };
+
+        assert_eq!(cache.get(&directive, now), None);
+        cache.insert(&directive, Message::Json(json!(3)), now);
+        assert_eq!(cache.get(&directive, now), Some(Message::Json(json!(3))));
+        assert_eq!(cache.get(&directive, now + Duration::from_millis(10)), None);
+
+        let metrics = cache.metrics();
+        assert_eq!(metrics.hits, 1);
+        assert_eq!(metrics.misses, 2);
+    }
+
+    #[test]
+    fn test_invalidate_clears_entries() {
+        let mut cache = ResponseCache::new();
+        let now = Instant::now();
+        let directive = CacheDirective::Key("count".to_string());
+
+        cache.insert(&directive, Message::Json(json!(3)), now);
+        cache.invalidate();
+        assert_eq!(cache.get(&directive, now), None);
+        assert_eq!(cache.metrics().bypasses, 1);
+    }
+}
@@ -13,3 +13,852 @@
 // pub async fn createActor(body: web::Json<serde_json::Value>) -> impl Responder {
 //     format!("Hello!\n You sent the following body: {}", body)
 // }
+
+use actix_codec::{Decoder, Encoder};
+use actix_http::ws;
+use actix_web::http::{header, StatusCode};
+use actix_web::dev::{MessageBody, ServiceFactory, ServiceRequest, ServiceResponse};
+use actix_web::{delete, get, post, put, web, App, HttpRequest, HttpResponse, Responder, ResponseError};
+use bytes::{Bytes, BytesMut};
+use deno_core::ErrBox;
+use futures::{SinkExt, Stream, StreamExt};
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+use crate::dead_letter::{self, DeadLetterStore};
+use crate::golem_isolate::NoFetchHandler;
+use crate::message::{accepts, is_json, Message, BINARY_CONTENT_TYPE, JSON_CONTENT_TYPE};
+use crate::replies::Reply;
+use crate::routes::{RouteConfig, RouteRequest, RouteTable, RouteTarget};
+use crate::runtime::Runtime;
+use crate::sockets::{
+    socket_channel, SocketFrame, SocketId, SocketReceiver, SocketSender, CLOSE_INVALID_PAYLOAD, CLOSE_NORMAL,
+    CLOSE_TOO_BIG,
+};
+use crate::streams::{body_channel, BodyReceiver};
+use crate::supervisor::{ActorExists, SendError};
+use crate::topics::{self, TopicStore};
+
+pub const DEFAULT_REPLY_TIMEOUT_MS: u64 = 30_000;
+
+const IDEMPOTENCY_KEY_HEADER: &str = "Idempotency-Key";
+
+/// The largest message a client may send over a WebSocket, also when it is
+/// split into several frames.
+const MAX_SOCKET_MESSAGE: usize = 64 * 1024;
+
+/// The largest body a client may ask an actor with. Such a body is read
+/// whole, and copied once more into the isolate, see `crate::message`.
+pub const MAX_MESSAGE_SIZE: usize = 16 * 1024 * 1024;
+
+/// What the handlers share: the runtime, the dead-letter and topic stores
+/// it was started with, and the routing table.
+#[derive(Clone)]
+pub struct AppState {
+    runtime: web::Data<Runtime>,
+    dead_letters: web::Data<Mutex<DeadLetterStore>>,
+    topics: web::Data<Mutex<TopicStore>>,
+    routes: web::Data<Mutex<RouteTable>>,
+}
+
+impl AppState {
+    /// Starts a runtime on stores that the API shares with it.
+    pub fn start(worker_count: usize, routes: RouteTable) -> Result<Self, ErrBox> {
+        let dead_letters = web::Data::new(Mutex::new(DeadLetterStore::new(dead_letter::DEFAULT_CAPACITY)));
+        let topic_store = TopicStore::new(topics::DEFAULT_BACKLOG, dead_letters.clone().into_inner());
+        let topics = web::Data::new(Mutex::new(topic_store));
+        let runtime = Runtime::start(worker_count, dead_letters.clone().into_inner(), topics.clone().into_inner())?;
+        Ok(Self {
+            runtime: web::Data::new(runtime),
+            dead_letters,
+            topics,
+            routes: web::Data::new(Mutex::new(routes)),
+        })
+    }
+
+    pub fn runtime(&self) -> Arc<Runtime> {
+        self.runtime.clone().into_inner()
+    }
+
+    /// Registers the shared state and the API, see `configure`.
+    pub fn mount<T, B>(&self, app: App<T, B>) -> App<T, B>
+        where
+            B: MessageBody,
+            T: ServiceFactory<
+                Config = (),
+                Request = ServiceRequest,
+                Response = ServiceResponse<B>,
+                Error = actix_web::Error,
+                InitError = (),
+            >,
+    {
+        app.app_data(self.runtime.clone())
+            .app_data(self.dead_letters.clone())
+            .app_data(self.topics.clone())
+            .app_data(self.routes.clone())
+            .app_data(web::PayloadConfig::new(MAX_MESSAGE_SIZE))
+            .configure(configure)
+    }
+}
+
+/// Registers the API. The routing table's catch-all resource comes last, so
+/// that routes cannot shadow the API.
+pub fn configure(cfg: &mut web::ServiceConfig) {
+    cfg.service(ask_actor)
+        .service(list_dead_letters)
+        .service(get_dead_letter)
+        .service(delete_dead_letter)
+        .service(redrive_dead_letter)
+        .service(publish)
+        .service(list_subscribers)
+        .service(list_routes)
+        .service(replace_routes)
+        .service(connect_actor)
+        .service(actor_events)
+        .service(web::resource("/actor/{id}/http{tail:(/.*)?}").to(serve_actor))
+        .service(web::resource("/{path:.*}").to(route_request));
+}
+
+#[derive(Deserialize)]
+struct AskQuery {
+    /// How long to wait for the actor's reply, in milliseconds.
+    timeout: Option<u64>,
+}
+
+/// Sends the body to the actor and responds with its reply. The actor may
+/// keep the caller waiting with `ctx.defer` and answer later with
+/// `ctx.reply`, until the timeout elapses. Requests retried
+/// with the same `Idempotency-Key` header receive the original reply. A
+/// reply the actor streams is sent with chunked transfer encoding as it is
+/// written.
+///
+/// A JSON body, or one without a `Content-Type`, is passed to `main` as
+/// JSON, any other body as a `Uint8Array`. Bodies larger than
+/// `MAX_MESSAGE_SIZE` get `413 Payload Too Large`. Replies of bytes are
+/// sent as `application/octet-stream`, and replies the `Accept` header
+/// rules out as `406 Not Acceptable`.
+#[post("/actor/{id}")]
+async fn ask_actor(
+    request: HttpRequest,
+    runtime: web::Data<Runtime>,
+    id: web::Path<String>,
+    query: web::Query<AskQuery>,
+    body: web::Bytes,
+) -> impl Responder {
+    let timeout = Duration::from_millis(query.timeout.unwrap_or(DEFAULT_REPLY_TIMEOUT_MS));
+    let idempotency_key = match request.headers().get(IDEMPOTENCY_KEY_HEADER) {
+        Some(key) => match key.to_str() {
+            Ok(key) => Some(key.to_string()),
+            Err(_) => return HttpResponse::BadRequest().body("the idempotency key must be visible ASCII"),
+        },
+        None => None,
+    };
+    let msg = match request_message(&request, body) {
+        Ok(msg) => msg,
+        Err(response) => return response,
+    };
+    match runtime.ask(&id, msg, idempotency_key, timeout).await {
+        Ok(reply) => negotiate(&request, reply),
+        Err(error) => error_response(error),
+    }
+}
+
+fn request_message(request: &HttpRequest, body: Bytes) -> Result<Message, HttpResponse> {
+    let content_type = request.headers().get(header::CONTENT_TYPE).map(|value| value.to_str().unwrap_or_default());
+    match content_type {
+        Some(content_type) if !is_json(content_type) => Ok(Message::Binary(body)),
+        _ => serde_json::from_slice(&body)
+            .map(Message::Json)
+            .map_err(|error| HttpResponse::BadRequest().body(format!("the body is not valid JSON: {}", error))),
+    }
+}
+
+/// Responds with a reply to `ask`, unless the client does not accept its
+/// content type.
+fn negotiate(request: &HttpRequest, reply: Reply) -> HttpResponse {
+    let content_type = match &reply {
+        Reply::Value(_) => JSON_CONTENT_TYPE,
+        Reply::Binary(_) | Reply::Stream(_) => BINARY_CONTENT_TYPE,
+        Reply::Response(_) => return reply_response(reply),
+    };
+    let accept = request.headers().get(header::ACCEPT).and_then(|accept| accept.to_str().ok());
+    match accept {
+        Some(accept) if !accepts(accept, content_type) => {
+            HttpResponse::NotAcceptable().body(format!("the actor replied with {}", content_type))
+        }
+        _ => reply_response(reply),
+    }
+}
+
+/// Passes any request below `/actor/{id}/http` to the actor's `fetch`
+/// export as a `Request` for the rest of the path, and responds with the
+/// `Response` it returns. Actors without `fetch` respond with
+/// `501 Not Implemented`.
+async fn serve_actor(
+    request: HttpRequest,
+    runtime: web::Data<Runtime>,
+    query: web::Query<AskQuery>,
+    payload: web::Payload,
+) -> HttpResponse {
+    let id = request.match_info().get("id").unwrap_or_default().to_string();
+    let path = format!("/{}", request.match_info().get("tail").unwrap_or_default().trim_start_matches('/'));
+    let timeout = Duration::from_millis(query.timeout.unwrap_or(DEFAULT_REPLY_TIMEOUT_MS));
+    forward_request(&runtime, &id, None, &request, &path, payload, timeout).await
+}
+
+/// Connects a WebSocket to the actor, see `crate::sockets`.
+#[get("/actor/{id}/socket")]
+async fn connect_actor(
+    request: HttpRequest,
+    runtime: web::Data<Runtime>,
+    id: web::Path<String>,
+    payload: web::Payload,
+) -> HttpResponse {
+    connect_socket(runtime, &id, None, &request, payload).await
+}
+
+/// Streams the actor's state changes and the events it emits with
+/// `ctx.emit` as Server-Sent Events, see `crate::events`.
+#[get("/actor/{id}/events")]
+async fn actor_events(runtime: web::Data<Runtime>, id: web::Path<String>) -> HttpResponse {
+    match runtime.listen(&id).await {
+        Ok(events) => HttpResponse::Ok()
+            .content_type("text/event-stream")
+            .header("Cache-Control", "no-cache")
+            .streaming(events.map(|event| Ok::<_, actix_web::Error>(event.encode()))),
+        Err(error) => error_response(error),
+    }
+}
+
+/// Sends requests that match a route to the actor it resolves to, see
+/// `crate::routes`. WebSocket upgrades connect to the actor instead of
+/// going to its `fetch` export.
+async fn route_request(
+    request: HttpRequest,
+    runtime: web::Data<Runtime>,
+    routes: web::Data<Mutex<RouteTable>>,
+    payload: web::Payload,
+) -> HttpResponse {
+    let host = request.connection_info().host().to_string();
+    let table = match routes.lock() {
+        Ok(table) => table,
+        Err(_) => return poisoned("routing table"),
+    };
+    let target = table.resolve(&RouteRequest {
+        method: request.method().as_str(),
+        host: &host,
+        path: request.path(),
+        query: request.query_string(),
+        headers: &request_headers(&request),
+    });
+    drop(table);
+    let target = match target {
+        Some(target) => target,
+        None => return HttpResponse::NotFound().finish(),
+    };
+    if is_websocket(&request) {
+        return connect_socket(runtime, &target.actor_id, Some(&target), &request, payload).await;
+    }
+    let timeout = Duration::from_millis(target.timeout.unwrap_or(DEFAULT_REPLY_TIMEOUT_MS));
+    let path = request.path().to_string();
+    forward_request(&runtime, &target.actor_id, Some(&target), &request, &path, payload, timeout).await
+}
+
+#[get("/routes")]
+async fn list_routes(routes: web::Data<Mutex<RouteTable>>) -> impl Responder {
+    match routes.lock() {
+        Ok(routes) => HttpResponse::Ok().json(routes.configs()),
+        Err(_) => poisoned("routing table"),
+    }
+}
+
+/// Replaces the whole routing table, unless one of the routes is invalid.
+#[put("/routes")]
+async fn replace_routes(routes: web::Data<Mutex<RouteTable>>, body: web::Json<Vec<RouteConfig>>) -> impl Responder {
+    match RouteTable::new(body.into_inner()) {
+        Ok(table) => match routes.lock() {
+            Ok(mut routes) => {
+                *routes = table;
+                HttpResponse::NoContent().finish()
+            }
+            Err(_) => poisoned("routing table"),
+        },
+        Err(error) => HttpResponse::BadRequest().body(error.to_string()),
+    }
+}
+
+/// The response to a request that found the lock of `what`, e.g. the
+/// routing table, poisoned by a panic.
+fn poisoned(what: &str) -> HttpResponse {
+    error!("the {} lock is poisoned", what);
+    HttpResponse::InternalServerError().finish()
+}
+
+fn request_headers(request: &HttpRequest) -> Vec<(String, String)> {
+    request
+        .headers()
+        .iter()
+        .filter_map(|(name, value)| Some((name.as_str().to_string(), value.to_str().ok()?.to_string())))
+        .collect()
+}
+
+/// The method, URL and headers of a request, with `path` as the path of
+/// the URL.
+fn request_head(request: &HttpRequest, path: &str) -> serde_json::Value {
+    let mut url = {
+        let connection = request.connection_info();
+        format!("{}://{}{}", connection.scheme(), connection.host(), path)
+    };
+    if !request.query_string().is_empty() {
+        url.push('?');
+        url.push_str(request.query_string());
+    }
+    json!({
+        "method": request.method().as_str(),
+        "url": url,
+        "headers": request_headers(request),
+    })
+}
+
+/// Sends a request to an actor's `fetch` export, which sees `path` as the
+/// path of the request's URL.
+async fn forward_request(
+    runtime: &Runtime,
+    id: &str,
+    route: Option<&RouteTarget>,
+    request: &HttpRequest,
+    path: &str,
+    payload: web::Payload,
+    timeout: Duration,
+) -> HttpResponse {
+    let head = request_head(request, path);
+    match runtime.serve(id, route, head, stream_payload(payload), timeout).await {
+        Ok(reply) => reply_response(reply),
+        Err(error) => error_response(error),
+    }
+}
+
+/// Forwards the request body to the actor as it arrives. A body that fails
+/// to arrive ends early.
+fn stream_payload(mut payload: web::Payload) -> BodyReceiver {
+    let (mut sender, receiver) = body_channel();
+    actix_rt::spawn(async move {
+        while let Some(chunk) = payload.next().await {
+            match chunk {
+                Ok(chunk) => {
+                    if sender.send(chunk).await.is_err() {
+                        return;
+                    }
+                }
+                Err(error) => {
+                    warn!("failed to read a request body: {}", error);
+                    return;
+                }
+            }
+        }
+    });
+    receiver
+}
+
+fn is_websocket(request: &HttpRequest) -> bool {
+    match request.headers().get(header::UPGRADE) {
+        Some(upgrade) => upgrade.as_bytes().eq_ignore_ascii_case(b"websocket"),
+        None => false,
+    }
+}
+
+/// Completes the WebSocket handshake once the actor accepted the
+/// connection. Refused connections get the actor's error as the response.
+async fn connect_socket(
+    runtime: web::Data<Runtime>,
+    id: &str,
+    route: Option<&RouteTarget>,
+    request: &HttpRequest,
+    payload: web::Payload,
+) -> HttpResponse {
+    let mut response = match ws::handshake(request.head()) {
+        Ok(response) => response,
+        Err(error) => return error.error_response(),
+    };
+    let (sender, receiver) = socket_channel();
+    let head = request_head(request, request.path());
+    let socket_id = match runtime.open_socket(id, route, head, sender.clone()).await {
+        Ok(socket_id) => socket_id,
+        Err(error) => return error_response(error),
+    };
+    actix_rt::spawn(read_frames(runtime, id.to_string(), socket_id, payload, sender));
+    response.streaming(write_frames(receiver))
+}
+
+fn close_frame(code: u16, reason: &str) -> SocketFrame {
+    SocketFrame::Close { code, reason: reason.to_string() }
+}
+
+/// Passes the messages the client sends to the actor, until either side
+/// closes the connection. Pings are answered here.
+async fn read_frames(
+    runtime: web::Data<Runtime>,
+    id: String,
+    socket_id: SocketId,
+    mut payload: web::Payload,
+    mut sender: SocketSender,
+) {
+    let mut codec = ws::Codec::new().max_size(MAX_SOCKET_MESSAGE);
+    let mut buf = BytesMut::new();
+    // The message being reassembled from fragments, and whether it is text.
+    let mut fragments: Option<(bool, BytesMut)> = None;
+
+    'read: while let Some(chunk) = payload.next().await {
+        match chunk {
+            Ok(chunk) => buf.extend_from_slice(&chunk),
+            Err(_) => break,
+        }
+        loop {
+            let frame = match codec.decode(&mut buf) {
+                Ok(Some(frame)) => frame,
+                Ok(None) => break,
+                Err(error) => {
+                    warn!("socket {} of actor {} sent an invalid frame: {}", socket_id, id, error);
+                    break 'read;
+                }
+            };
+            let (is_text, data) = match frame {
+                ws::Frame::Text(text) => (true, text),
+                ws::Frame::Binary(bytes) => (false, bytes),
+                ws::Frame::Continuation(ws::Item::FirstText(text)) => {
+                    fragments = Some((true, BytesMut::from(&text[..])));
+                    continue;
+                }
+                ws::Frame::Continuation(ws::Item::FirstBinary(bytes)) => {
+                    fragments = Some((false, BytesMut::from(&bytes[..])));
+                    continue;
+                }
+                ws::Frame::Continuation(ws::Item::Continue(data)) => {
+                    if let Some((_, fragments)) = fragments.as_mut() {
+                        fragments.extend_from_slice(&data);
+                        if fragments.len() > MAX_SOCKET_MESSAGE {
+                            sender.try_send(close_frame(CLOSE_TOO_BIG, "the message is too big")).ok();
+                            break 'read;
+                        }
+                    }
+                    continue;
+                }
+                ws::Frame::Continuation(ws::Item::Last(data)) => match fragments.take() {
+                    Some((is_text, mut fragments)) => {
+                        fragments.extend_from_slice(&data);
+                        (is_text, fragments.freeze())
+                    }
+                    None => continue,
+                },
+                ws::Frame::Ping(bytes) => {
+                    sender.try_send(SocketFrame::Pong(bytes)).ok();
+                    continue;
+                }
+                ws::Frame::Pong(_) => continue,
+                ws::Frame::Close(_) => {
+                    sender.try_send(close_frame(CLOSE_NORMAL, "")).ok();
+                    break 'read;
+                }
+            };
+            let msg = if is_text {
+                match String::from_utf8(data.to_vec()) {
+                    Ok(text) => Message::Json(json!(text)),
+                    Err(_) => {
+                        sender.try_send(close_frame(CLOSE_INVALID_PAYLOAD, "text messages must be UTF-8")).ok();
+                        break 'read;
+                    }
+                }
+            } else {
+                Message::Binary(data)
+            };
+            if runtime.socket_message(&id, socket_id, msg).is_err() {
+                break 'read;
+            }
+        }
+    }
+
+    sender.close_channel();
+    runtime.socket_closed(&id, socket_id).ok();
+}
+
+/// Encodes the frames queued for a WebSocket.
+fn write_frames(receiver: SocketReceiver) -> impl Stream<Item=Result<Bytes, actix_web::Error>> {
+    let mut codec = ws::Codec::new();
+    receiver.map(move |frame| {
+        let message = match frame {
+            SocketFrame::Text(text) => ws::Message::Text(text),
+            SocketFrame::Binary(bytes) => ws::Message::Binary(bytes),
+            SocketFrame::Pong(bytes) => ws::Message::Pong(bytes),
+            SocketFrame::Close { code, reason } => ws::Message::Close(Some(ws::CloseReason {
+                code: ws::CloseCode::from(code),
+                description: Some(reason),
+            })),
+        };
+        let mut buf = BytesMut::new();
+        codec.encode(message, &mut buf)?;
+        Ok(buf.freeze())
+    })
+}
+
+fn reply_response(reply: Reply) -> HttpResponse {
+    match reply {
+        Reply::Value(value) => HttpResponse::Ok().json(value),
+        Reply::Binary(bytes) => HttpResponse::Ok().content_type(BINARY_CONTENT_TYPE).body(bytes),
+        Reply::Stream(body) => HttpResponse::Ok()
+            .content_type(BINARY_CONTENT_TYPE)
+            .streaming(body.map(Ok::<_, actix_web::Error>)),
+        Reply::Response(response) => {
+            let status = match StatusCode::from_u16(response.status) {
+                Ok(status) => status,
+                Err(_) => {
+                    return HttpResponse::InternalServerError()
+                        .body(format!("the actor responded with the invalid status {}", response.status));
+                }
+            };
+            let mut builder = HttpResponse::build(status);
+            for (name, value) in response.headers {
+                builder.header(name.as_str(), value);
+            }
+            match response.body {
+                Some(body) => builder.streaming(body.map(Ok::<_, actix_web::Error>)),
+                None => builder.finish(),
+            }
+        }
+    }
+}
+
+fn error_response(error: SendError) -> HttpResponse {
+    match error {
+        SendError::NotFound => HttpResponse::NotFound().finish(),
+        SendError::Unavailable { retry_after } => HttpResponse::ServiceUnavailable()
+            .header("Retry-After", ((retry_after.as_millis() + 999) / 1000).to_string())
+            .finish(),
+        SendError::TimedOut => HttpResponse::GatewayTimeout().finish(),
+        SendError::Failed(error) if error.downcast_ref::<NoFetchHandler>().is_some() => {
+            HttpResponse::NotImplemented().body(error.to_string())
+        }
+        SendError::Failed(error) if error.downcast_ref::<ActorExists>().is_some() => {
+            HttpResponse::Conflict().body(error.to_string())
+        }
+        SendError::Failed(error) => HttpResponse::InternalServerError().body(error.to_string()),
+    }
+}
+
+#[derive(Deserialize)]
+struct DeadLetterQuery {
+    actor: Option<String>,
+}
+
+#[get("/dead-letters")]
+async fn list_dead_letters(store: web::Data<Mutex<DeadLetterStore>>, query: web::Query<DeadLetterQuery>) -> impl Responder {
+    match store.lock() {
+        Ok(store) => HttpResponse::Ok().json(store.list(query.actor.as_deref())),
+        Err(_) => poisoned("dead-letter store"),
+    }
+}
+
+#[get("/dead-letters/{id}")]
+async fn get_dead_letter(store: web::Data<Mutex<DeadLetterStore>>, id: web::Path<u64>) -> impl Responder {
+    match store.lock() {
+        Ok(store) => match store.get(*id) {
+            Some(letter) => HttpResponse::Ok().json(letter),
+            None => HttpResponse::NotFound().finish(),
+        },
+        Err(_) => poisoned("dead-letter store"),
+    }
+}
+
+#[delete("/dead-letters/{id}")]
+async fn delete_dead_letter(store: web::Data<Mutex<DeadLetterStore>>, id: web::Path<u64>) -> impl Responder {
+    match store.lock() {
+        Ok(mut store) => match store.remove(*id) {
+            Some(_) => HttpResponse::NoContent().finish(),
+            None => HttpResponse::NotFound().finish(),
+        },
+        Err(_) => poisoned("dead-letter store"),
+    }
+}
+
+/// Sends a dead letter to its actor again. Delivery happens the next time
+/// the supervisor processes its mailboxes, hence the `202 Accepted`.
+#[post("/dead-letters/{id}/redrive")]
+async fn redrive_dead_letter(store: web::Data<Mutex<DeadLetterStore>>, id: web::Path<u64>) -> impl Responder {
+    match store.lock() {
+        Ok(mut store) if store.redrive(*id) => HttpResponse::Accepted().finish(),
+        Ok(_) => HttpResponse::NotFound().finish(),
+        Err(_) => poisoned("dead-letter store"),
+    }
+}
+
+/// Queues the body for every subscriber of the topic, as JSON or bytes
+/// like the body of `POST /actor/{id}`. Delivery happens asynchronously,
+/// hence the `202 Accepted`.
+#[post("/topics/{topic}")]
+async fn publish(
+    request: HttpRequest,
+    topics: web::Data<Mutex<TopicStore>>,
+    topic: web::Path<String>,
+    body: web::Bytes,
+) -> impl Responder {
+    let msg = match request_message(&request, body) {
+        Ok(msg) => msg,
+        Err(response) => return response,
+    };
+    match topics.lock() {
+        Ok(mut topics) => HttpResponse::Accepted().json(json!({ "subscribers": topics.publish(&topic, msg) })),
+        Err(_) => poisoned("topic store"),
+    }
+}
+
+#[get("/topics/{topic}/subscribers")]
+async fn list_subscribers(topics: web::Data<Mutex<TopicStore>>, topic: web::Path<String>) -> impl Responder {
+    match topics.lock() {
+        Ok(topics) => HttpResponse::Ok().json(topics.subscribers(&topic)),
+        Err(_) => poisoned("topic store"),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::golem_isolate::GolemIsolate;
+    use crate::supervisor::SupervisionPolicy;
+    use actix_web::body::{Body, ResponseBody};
+    use actix_web::dev::Service;
+    use actix_web::test::{self, TestRequest};
+    use deno_core::Script;
+
+    const ACTOR: &str = "function init() { return { count: 0 }; }\n\
+        function main(state, msg, ctx) {\n\
+            if (ctx.socket) {\n\
+                if (ctx.socket.event === 'open') { ctx.socket.send('welcome'); }\n\
+                return state;\n\
+            }\n\
+            if (msg instanceof Uint8Array) { ctx.reply(msg.reverse()); return state; }\n\
+            if (msg.op === 'add') { state.count += msg.n; }\n\
+            return state;\n\
+        }\n\
+        function fetch(state, request) { return new Response(request.method + ' ' + request.url); }";
+
+    async fn started() -> AppState {
+        let state = AppState::start(1, RouteTable::default()).unwrap();
+        let runtime = state.runtime();
+        let snapshot = GolemIsolate::try_create_snapshot(Script { source: ACTOR, filename: "actor.js" }).unwrap();
+        runtime.deploy("counter", snapshot).await.unwrap();
+        runtime.spawn("a", "counter", SupervisionPolicy::default(), None).await.unwrap();
+        state
+    }
+
+    async fn app(
+        state: &AppState,
+    ) -> impl Service<Request = actix_http::Request, Response = ServiceResponse, Error = actix_web::Error> {
+        test::init_service(state.mount(App::new())).await
+    }
+
+    async fn first_chunk(body: &mut ResponseBody<Body>) -> Bytes {
+        body.next().await.unwrap().unwrap()
+    }
+
+    #[actix_rt::test]
+    async fn test_dead_letters() {
+        let state = started().await;
+        let mut app = app(&state).await;
+        state.runtime().tell("ghost", Message::Json(json!(1))).unwrap();
+        state.runtime().get_state("a").await.unwrap();
+
+        let request = TestRequest::get().uri("/dead-letters?actor=ghost").to_request();
+        let letters: serde_json::Value = test::read_response_json(&mut app, request).await;
+        assert_eq!(letters[0]["reason"], json!("actorNotFound"));
+        let id = letters[0]["id"].as_u64().unwrap();
+
+        let request = TestRequest::get().uri(&format!("/dead-letters/{}", id)).to_request();
+        assert_eq!(test::call_service(&mut app, request).await.status(), StatusCode::OK);
+        let request = TestRequest::post().uri(&format!("/dead-letters/{}/redrive", id)).to_request();
+        assert_eq!(test::call_service(&mut app, request).await.status(), StatusCode::ACCEPTED);
+        let request = TestRequest::delete().uri(&format!("/dead-letters/{}", id)).to_request();
+        assert_eq!(test::call_service(&mut app, request).await.status(), StatusCode::NOT_FOUND);
+    }
+
+    #[actix_rt::test]
+    async fn test_poisoned_stores() {
+        let state = started().await;
+        let mut app = app(&state).await;
+        let dead_letters = state.dead_letters.clone();
+        let topics = state.topics.clone();
+        std::thread::spawn(move || {
+            let _dead_letters = dead_letters.lock().unwrap();
+            let _topics = topics.lock().unwrap();
+            panic!("poisoning the stores");
+        })
+        .join()
+        .unwrap_err();
+
+        let request = TestRequest::get().uri("/dead-letters").to_request();
+        assert_eq!(test::call_service(&mut app, request).await.status(), StatusCode::INTERNAL_SERVER_ERROR);
+        let request = TestRequest::post().uri("/topics/news").set_json(&json!(1)).to_request();
+        assert_eq!(test::call_service(&mut app, request).await.status(), StatusCode::INTERNAL_SERVER_ERROR);
+    }
+
+    #[actix_rt::test]
+    async fn test_ask() {
+        let state = started().await;
+        let mut app = app(&state).await;
+
+        let request = TestRequest::post().uri("/actor/a").set_json(&json!({ "op": "add", "n": 2 })).to_request();
+        let reply: serde_json::Value = test::read_response_json(&mut app, request).await;
+        assert_eq!(reply, json!({ "count": 2 }));
+
+        let request = TestRequest::post()
+            .uri("/actor/a")
+            .header(header::CONTENT_TYPE, BINARY_CONTENT_TYPE)
+            .set_payload(&b"\x01\x02\x03"[..])
+            .to_request();
+        assert_eq!(test::read_response(&mut app, request).await, Bytes::from_static(b"\x03\x02\x01"));
+
+        let request = TestRequest::post()
+            .uri("/actor/a")
+            .header(header::ACCEPT, BINARY_CONTENT_TYPE)
+            .set_json(&json!({}))
+            .to_request();
+        assert_eq!(test::call_service(&mut app, request).await.status(), StatusCode::NOT_ACCEPTABLE);
+
+        let request = TestRequest::post().uri("/actor/missing").set_json(&json!({})).to_request();
+        assert_eq!(test::call_service(&mut app, request).await.status(), StatusCode::NOT_FOUND);
+    }
+
+    #[actix_rt::test]
+    async fn test_ask_with_large_body() {
+        let state = started().await;
+        let mut app = app(&state).await;
+
+        // Larger than the extractor's default limit of 256 KiB.
+        let body = vec![7; 300 * 1024];
+        let request = TestRequest::post()
+            .uri("/actor/a")
+            .header(header::CONTENT_TYPE, BINARY_CONTENT_TYPE)
+            .set_payload(body.clone())
+            .to_request();
+        assert_eq!(test::read_response(&mut app, request).await, Bytes::from(body));
+
+        let request = TestRequest::post()
+            .uri("/actor/a")
+            .header(header::CONTENT_TYPE, BINARY_CONTENT_TYPE)
+            .set_payload(vec![0; MAX_MESSAGE_SIZE + 1])
+            .to_request();
+        assert_eq!(test::call_service(&mut app, request).await.status(), StatusCode::PAYLOAD_TOO_LARGE);
+    }
+
+    #[actix_rt::test]
+    async fn test_idempotency_key() {
+        let state = started().await;
+        let mut app = app(&state).await;
+
+        for _ in 0..2 {
+            let request = TestRequest::post()
+                .uri("/actor/a")
+                .header(IDEMPOTENCY_KEY_HEADER, "add-once")
+                .set_json(&json!({ "op": "add", "n": 5 }))
+                .to_request();
+            let reply: serde_json::Value = test::read_response_json(&mut app, request).await;
+            assert_eq!(reply, json!({ "count": 5 }));
+        }
+        assert_eq!(state.runtime().get_state("a").await.unwrap(), Message::Json(json!({ "count": 5 })));
+    }
+
+    #[actix_rt::test]
+    async fn test_topics() {
+        let state = started().await;
+        let mut app = app(&state).await;
+        state.topics.lock().unwrap().subscribe("news", "a");
+
+        let request = TestRequest::post().uri("/topics/news").set_json(&json!({ "op": "add", "n": 1 })).to_request();
+        let response: serde_json::Value = test::read_response_json(&mut app, request).await;
+        assert_eq!(response, json!({ "subscribers": 1 }));
+
+        let request = TestRequest::get().uri("/topics/news/subscribers").to_request();
+        let subscribers: serde_json::Value = test::read_response_json(&mut app, request).await;
+        assert_eq!(subscribers, json!(["a"]));
+    }
+
+    #[actix_rt::test]
+    async fn test_fetch() {
+        let state = started().await;
+        let mut app = app(&state).await;
+
+        let request = TestRequest::get().uri("/actor/a/http/hello?x=1").to_request();
+        let body = test::read_response(&mut app, request).await;
+        assert!(body.starts_with(b"GET http://"));
+        assert!(body.ends_with(b"/hello?x=1"));
+    }
+
+    #[actix_rt::test]
+    async fn test_routes() {
+        let state = started().await;
+        let mut app = app(&state).await;
+
+        let routes = json!([{ "path": "/rooms/{room}/*", "actorType": "counter", "actorId": { "segment": "room" } }]);
+        let request = TestRequest::put().uri("/routes").set_json(&routes).to_request();
+        assert_eq!(test::call_service(&mut app, request).await.status(), StatusCode::NO_CONTENT);
+        let request = TestRequest::get().uri("/routes").to_request();
+        let listed: serde_json::Value = test::read_response_json(&mut app, request).await;
+        assert_eq!(listed[0]["actorType"], json!("counter"));
+
+        let request = TestRequest::post().uri("/rooms/lobby/messages").to_request();
+        let body = test::read_response(&mut app, request).await;
+        assert!(body.ends_with(b"/rooms/lobby/messages"));
+        assert_eq!(state.runtime().get_state("lobby").await.unwrap(), Message::Json(json!({ "count": 0 })));
+
+        let request = TestRequest::get().uri("/elsewhere").to_request();
+        assert_eq!(test::call_service(&mut app, request).await.status(), StatusCode::NOT_FOUND);
+    }
+
+    #[actix_rt::test]
+    async fn test_route_without_spawning() {
+        let state = started().await;
+        let mut app = app(&state).await;
+
+        let routes = json!([{ "path": "/fixed/{id}", "actorType": "counter", "actorId": { "segment": "id" }, "spawn": false }]);
+        let request = TestRequest::put().uri("/routes").set_json(&routes).to_request();
+        assert_eq!(test::call_service(&mut app, request).await.status(), StatusCode::NO_CONTENT);
+
+        let request = TestRequest::get().uri("/fixed/a").to_request();
+        assert_eq!(test::call_service(&mut app, request).await.status(), StatusCode::OK);
+        let request = TestRequest::get().uri("/fixed/new%20actor").to_request();
+        assert_eq!(test::call_service(&mut app, request).await.status(), StatusCode::NOT_FOUND);
+        assert!(matches!(state.runtime().get_state("new actor").await, Err(SendError::NotFound)));
+    }
+
+    #[actix_rt::test]
+    async fn test_socket() {
+        let state = started().await;
+        let mut app = app(&state).await;
+
+        let request = TestRequest::get()
+            .uri("/actor/a/socket")
+            .header(header::UPGRADE, "websocket")
+            .header(header::CONNECTION, "Upgrade")
+            .header(header::SEC_WEBSOCKET_VERSION, "13")
+            .header(header::SEC_WEBSOCKET_KEY, "dGhlIHNhbXBsZSBub25jZQ==")
+            .to_request();
+        let mut response = test::call_service(&mut app, request).await;
+        assert_eq!(response.status(), StatusCode::SWITCHING_PROTOCOLS);
+
+        let mut buf = BytesMut::from(&first_chunk(&mut response.take_body()).await[..]);
+        let frame = ws::Codec::new().client_mode().decode(&mut buf).unwrap();
+        assert_eq!(frame, Some(ws::Frame::Text(Bytes::from_static(b"welcome"))));
+    }
+
+    #[actix_rt::test]
+    async fn test_events() {
+        let state = started().await;
+        let mut app = app(&state).await;
+
+        let request = TestRequest::get().uri("/actor/a/events").to_request();
+        let mut response = test::call_service(&mut app, request).await;
+        assert_eq!(response.headers().get(header::CONTENT_TYPE).unwrap(), "text/event-stream");
+        let mut body = response.take_body();
+        assert_eq!(first_chunk(&mut body).await, Bytes::from("event: state\ndata: {\"count\":0}\n\n"));
+
+        state.runtime().ask("a", Message::Json(json!({ "op": "add", "n": 3 })), None, Duration::from_secs(5)).await.unwrap();
+        assert_eq!(first_chunk(&mut body).await, Bytes::from("event: state\ndata: {\"count\":3}\n\n"));
+    }
+}
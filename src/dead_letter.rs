@@ -0,0 +1,199 @@
+//! Messages that could not be delivered, or whose processing kept failing,
+//! are kept here instead of being dropped. They can be inspected and sent
+//! to their actor again through the HTTP API.
+
+use crate::actor::ActorId;
+use crate::message::Message;
+use crate::op_error::OpError;
+use deno_core::{ErrBox, JSError};
+use std::collections::VecDeque;
+use std::sync::{Arc, Mutex};
+use std::time::{SystemTime, UNIX_EPOCH};
+
+pub const DEFAULT_CAPACITY: usize = 10_000;
+
+pub type DeadLetters = Arc<Mutex<DeadLetterStore>>;
+
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub enum DeadLetterReason {
+    ActorNotFound,
+    ActorStopped,
+    MailboxOverflow,
+    RetriesExhausted,
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct ErrorInfo {
+    /// The `ErrorKind` of an `OpError`, absent for JavaScript exceptions.
+    pub kind: Option<u32>,
+    pub message: String,
+    pub stack: Option<String>,
+}
+
+impl ErrorInfo {
+    pub fn from_err_box(error: &ErrBox) -> Self {
+        if let Some(js_error) = error.downcast_ref::<JSError>() {
+            return Self {
+                kind: None,
+                message: js_error.message.clone(),
+                stack: Some(js_error.to_string()),
+            };
+        }
+        if let Some(op_error) = error.downcast_ref::<OpError>() {
+            return Self {
+                kind: Some(op_error.kind as u32),
+                message: op_error.msg.clone(),
+                stack: None,
+            };
+        }
+        Self {
+            kind: None,
+            message: error.to_string(),
+            stack: None,
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct DeadLetter {
+    pub id: u64,
+    pub actor_id: ActorId,
+    pub message: Message,
+    pub reason: DeadLetterReason,
+    pub error: Option<ErrorInfo>,
+    pub attempts: u32,
+    /// Milliseconds since the unix epoch.
+    pub recorded_at: u64,
+}
+
+/// A bounded store, the oldest dead letters are discarded first once it
+/// reaches its capacity.
+pub struct DeadLetterStore {
+    letters: VecDeque<DeadLetter>,
+    redrives: Vec<DeadLetter>,
+    capacity: usize,
+    next_id: u64,
+}
+
+impl DeadLetterStore {
+    pub fn new(capacity: usize) -> Self {
+        Self {
+            letters: VecDeque::new(),
+            redrives: Vec::new(),
+            capacity,
+            next_id: 1,
+        }
+    }
+
+    pub fn shared(capacity: usize) -> DeadLetters {
+        Arc::new(Mutex::new(Self::new(capacity)))
+    }
+
+    pub fn record(
+        &mut self,
+        actor_id: &str,
+        message: Message,
+        reason: DeadLetterReason,
+        error: Option<ErrorInfo>,
+        attempts: u32,
+    ) -> u64 {
+        let id = self.next_id;
+        self.next_id += 1;
+
+        let recorded_at = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .map(|d| d.as_millis() as u64)
+            .unwrap_or(0);
+
+        warn!("dead letter {} for actor {}: {:?}", id, actor_id, reason);
+        if self.letters.len() >= self.capacity {
+            self.letters.pop_front();
+        }
+        self.letters.push_back(DeadLetter {
+            id,
+            actor_id: actor_id.to_string(),
+            message,
+            reason,
+            error,
+            attempts,
+            recorded_at,
+        });
+        id
+    }
+
+    pub fn list(&self, actor_id: Option<&str>) -> Vec<DeadLetter> {
+        self.letters
+            .iter()
+            .filter(|letter| actor_id.map_or(true, |actor_id| letter.actor_id == actor_id))
+            .cloned()
+            .collect()
+    }
+
+    pub fn get(&self, id: u64) -> Option<&DeadLetter> {
+        self.letters.iter().find(|letter| letter.id == id)
+    }
+
+    pub fn remove(&mut self, id: u64) -> Option<DeadLetter> {
+        let index = self.letters.iter().position(|letter| letter.id == id)?;
+        self.letters.remove(index)
+    }
+
+    /// Queues a dead letter to be delivered to its actor again.
+    pub fn redrive(&mut self, id: u64) -> bool {
+        match self.remove(id) {
+            Some(letter) => {
+                self.redrives.push(letter);
+                true
+            }
+            None => false,
+        }
+    }
+
+    pub fn take_redrives(&mut self) -> Vec<DeadLetter> {
+        std::mem::replace(&mut self.redrives, Vec::new())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_record_and_list() {
+        let mut store = DeadLetterStore::new(10);
+        store.record("a", json!(1).into(), DeadLetterReason::ActorNotFound, None, 0);
+        store.record("b", json!(2).into(), DeadLetterReason::MailboxOverflow, None, 0);
+
+        assert_eq!(store.list(None).len(), 2);
+        let letters = store.list(Some("b"));
+        assert_eq!(letters.len(), 1);
+        assert_eq!(letters[0].message, Message::Json(json!(2)));
+    }
+
+    #[test]
+    fn test_capacity_discards_oldest() {
+        let mut store = DeadLetterStore::new(1);
+        let first = store.record("a", json!(1).into(), DeadLetterReason::ActorNotFound, None, 0);
+        let second = store.record("a", json!(2).into(), DeadLetterReason::ActorNotFound, None, 0);
+
+        assert!(store.get(first).is_none());
+        assert!(store.get(second).is_some());
+    }
+
+    #[test]
+    fn test_redrive() {
+        let mut store = DeadLetterStore::new(10);
+        let id = store.record("a", json!(1).into(), DeadLetterReason::RetriesExhausted, None, 3);
+
+        assert!(store.redrive(id));
+        assert!(!store.redrive(id));
+        assert!(store.list(None).is_empty());
+        let redrives = store.take_redrives();
+        assert_eq!(redrives.len(), 1);
+        assert_eq!(redrives[0].id, id);
+        assert!(store.take_redrives().is_empty());
+    }
+}
@@ -0,0 +1,149 @@
+//! Streams of an actor's state changes and of the events it emits, served
+//! as Server-Sent Events by `GET /actor/{id}/events`.
+//!
+//! A listener first receives the actor's current state, then a `state`
+//! event whenever an invocation changes it, and an unnamed event, which
+//! `EventSource` passes to `onmessage`, for every `ctx.emit(event)`. Both
+//! are only sent once the invocation's transaction is committed, see
+//! `crate::transaction`.
+//!
+//! Listeners are fed through bounded channels. A listener that does not keep
+//! up is dropped, ending its stream, rather than buffered for.
+
+use crate::actor::ActorId;
+use bytes::Bytes;
+use futures::channel::mpsc;
+use serde_json::Value;
+use std::collections::HashMap;
+
+/// The events buffered for a listener.
+pub const EVENT_CAPACITY: usize = 64;
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum ActorEvent {
+    State(Value),
+    Emitted(Value),
+}
+
+impl ActorEvent {
+    /// The event in the `text/event-stream` format. Serialized JSON never
+    /// contains a line break, so the data fits on a single line.
+    pub fn encode(&self) -> Bytes {
+        let encoded = match self {
+            ActorEvent::State(state) => format!("event: state\ndata: {}\n\n", state),
+            ActorEvent::Emitted(event) => format!("data: {}\n\n", event),
+        };
+        Bytes::from(encoded)
+    }
+}
+
+pub type EventSender = mpsc::Sender<ActorEvent>;
+
+pub type EventReceiver = mpsc::Receiver<ActorEvent>;
+
+pub fn event_channel() -> (EventSender, EventReceiver) {
+    mpsc::channel(EVENT_CAPACITY)
+}
+
+/// The listeners of the actors owned by a worker.
+#[derive(Default)]
+pub struct Events {
+    listeners: HashMap<ActorId, Vec<EventSender>>,
+}
+
+impl Events {
+    pub fn listen(&mut self, actor_id: &str, listener: EventSender) {
+        self.listeners.entry(actor_id.to_string()).or_insert_with(Vec::new).push(listener);
+    }
+
+    /// Whether anyone listens to the actor, so that state changes are only
+    /// looked for when they would be sent.
+    pub fn has_listeners(&self, actor_id: &str) -> bool {
+        self.listeners.contains_key(actor_id)
+    }
+
+    /// Sends an event to the actor's listeners, dropping those that have
+    /// gone away or fallen behind.
+    pub fn send(&mut self, actor_id: &str, event: ActorEvent) {
+        let listeners = match self.listeners.get_mut(actor_id) {
+            Some(listeners) => listeners,
+            None => return,
+        };
+        let mut kept = Vec::with_capacity(listeners.len());
+        for mut listener in listeners.drain(..) {
+            match listener.try_send(event.clone()) {
+                Ok(()) => kept.push(listener),
+                Err(error) => {
+                    if error.is_full() {
+                        warn!("a listener of actor {} is not keeping up, dropping it", actor_id);
+                        listener.close_channel();
+                    }
+                }
+            }
+        }
+        *listeners = kept;
+        if listeners.is_empty() {
+            self.listeners.remove(actor_id);
+        }
+    }
+
+    /// Ends the streams of a stopped actor.
+    pub fn remove_actor(&mut self, actor_id: &str) {
+        if let Some(listeners) = self.listeners.remove(actor_id) {
+            for mut listener in listeners {
+                listener.close_channel();
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_encode() {
+        let state = ActorEvent::State(json!({ "count": 1 }));
+        assert_eq!(state.encode(), Bytes::from("event: state\ndata: {\"count\":1}\n\n"));
+        let emitted = ActorEvent::Emitted(json!("a\nb"));
+        assert_eq!(emitted.encode(), Bytes::from("data: \"a\\nb\"\n\n"));
+    }
+
+    #[test]
+    fn test_events_reach_every_listener() {
+        let mut events = Events::default();
+        let (first, mut first_events) = event_channel();
+        let (second, mut second_events) = event_channel();
+        events.listen("a", first);
+        events.listen("a", second);
+
+        events.send("a", ActorEvent::Emitted(json!(1)));
+        assert_eq!(first_events.try_next().unwrap(), Some(ActorEvent::Emitted(json!(1))));
+        assert_eq!(second_events.try_next().unwrap(), Some(ActorEvent::Emitted(json!(1))));
+
+        events.remove_actor("a");
+        assert!(!events.has_listeners("a"));
+        assert_eq!(first_events.try_next().unwrap(), None);
+    }
+
+    #[test]
+    fn test_listeners_that_went_away_are_dropped() {
+        let mut events = Events::default();
+        let (listener, receiver) = event_channel();
+        events.listen("a", listener);
+        drop(receiver);
+        events.send("a", ActorEvent::Emitted(json!(1)));
+        assert!(!events.has_listeners("a"));
+    }
+
+    #[test]
+    fn test_slow_listeners_are_dropped() {
+        let mut events = Events::default();
+        let (listener, _receiver) = event_channel();
+        events.listen("a", listener);
+        for i in 0..EVENT_CAPACITY + 2 {
+            events.send("a", ActorEvent::Emitted(json!(i)));
+        }
+        assert!(!events.has_listeners("a"));
+    }
+}
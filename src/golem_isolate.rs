@@ -1,10 +1,12 @@
 use std::mem;
 
-use crate::golem_isolate::IsolateCreationError::{FailedToCompileCode, NoMain};
+use crate::golem_isolate::IsolateCreationError::{
+    FailedToCompileCode, FailedToLoadModule, FailedToRestoreSnapshot, FailedToTranspile, InvalidManifest, NoMain,
+};
 use std::convert::TryFrom;
 use std::borrow::BorrowMut;
 use std::sync::{Arc, Mutex};
-use deno_core::{Script, Snapshot, CoreIsolate, ErrBox, ZeroCopyBuf, Op, OpId};
+use deno_core::{Script, Snapshot, CoreIsolate, EsIsolate, ErrBox, JSError, ModuleSpecifier, ZeroCopyBuf, Op, OpId};
 use rusty_v8::{self as v8, Function, Global, Local, ContextScope, HandleScope, Value};
 use deno_core::Snapshot::{JustCreated, Static};
 use futures::io::IoSlice;
@@ -16,23 +18,73 @@ use std::future::Future;
 use futures::task::{Context, Poll};
 use futures::{TryFuture, FutureExt, TryFutureExt};
 use std::io::Error;
+use std::rc::Rc;
+use crate::module_loader::{BundleModuleLoader, ModuleBundle, ENTRY_MODULE};
+use crate::source_maps::{apply_source_maps, SourceMaps};
+use crate::typescript::TypeScriptCompiler;
+use crate::cache::{CacheDirective, CacheLookup, CacheMetrics, ResponseCache};
+use crate::manifest::{ActorManifest, Export, ManifestError};
+use std::time::Instant;
+use std::collections::HashMap;
+use crate::lifecycle::{LifecycleHook, LifecycleLimits, TimedOut, Watchdog};
+use crate::message::Message;
+use bytes::Bytes;
 
 
+const PRELUDE_SOURCE: &str = include_str!("js/golem.js");
+
+#[derive(Debug)]
 pub enum IsolateCreationError {
     NoMain,
     FailedToRestoreSnapshot,
-    FailedToCompileCode,
+    /// The prelude or the script threw, or a module failed to evaluate.
+    FailedToCompileCode(ErrBox),
+    FailedToLoadModule(ErrBox),
+    FailedToTranspile(ErrBox),
+    InvalidManifest(Vec<ManifestError>),
+}
+
+impl std::error::Error for IsolateCreationError {}
+
+impl std::fmt::Display for IsolateCreationError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            NoMain => write!(f, "the script does not define a main function"),
+            FailedToRestoreSnapshot => write!(f, "the snapshot could not be restored"),
+            FailedToCompileCode(error) => write!(f, "the script failed to run: {}", error),
+            FailedToLoadModule(error) => write!(f, "the modules failed to load: {}", error),
+            FailedToTranspile(error) => write!(f, "the TypeScript sources failed to transpile: {}", error),
+            InvalidManifest(errors) => {
+                let errors: Vec<String> = errors.iter().map(ToString::to_string).collect();
+                write!(f, "the script's exports are invalid: {}", errors.join("; "))
+            }
+        }
+    }
+}
+
+/// An HTTP request was passed to an actor that does not export `fetch`.
+#[derive(Debug)]
+pub struct NoFetchHandler;
+
+impl std::error::Error for NoFetchHandler {}
+
+impl std::fmt::Display for NoFetchHandler {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "the actor does not export a fetch handler")
+    }
 }
 
 enum StartupData<'a> {
     Script(Script<'a>),
-    NativeSnapshot(rusty_v8::StartupData),
+    Module(&'a ModuleBundle),
     Snapshot(&'a GolemSnapshot),
 }
 
 #[derive(Debug)]
 pub struct GolemSnapshot {
-    data: Vec<u8>
+    data: Vec<u8>,
+    source_maps: SourceMaps,
+    manifest: ActorManifest,
 }
 
 impl Clone for GolemSnapshot {
@@ -40,7 +92,9 @@ impl Clone for GolemSnapshot {
         let mut data = vec![0; self.data.len()];
         data.copy_from_slice(&self.data);
         GolemSnapshot {
-            data
+            data,
+            source_maps: self.source_maps.clone(),
+            manifest: self.manifest.clone(),
         }
     }
 }
@@ -55,7 +109,21 @@ struct SnapshotData {
 
 impl GolemSnapshot {
     pub fn new(data: Vec<u8>) -> Self {
-        Self { data }
+        Self { data, source_maps: SourceMaps::new(), manifest: ActorManifest::default() }
+    }
+
+    pub fn with_manifest(mut self, manifest: ActorManifest) -> Self {
+        self.manifest = manifest;
+        self
+    }
+
+    pub fn manifest(&self) -> &ActorManifest {
+        &self.manifest
+    }
+
+    pub fn with_source_maps(mut self, source_maps: SourceMaps) -> Self {
+        self.source_maps = source_maps;
+        self
     }
 
     pub fn to_startup_data(&self) -> rusty_v8::StartupData {
@@ -80,13 +148,193 @@ impl GolemSnapshot {
     }
 }
 
+/// How the actor's state takes part in a function call.
+enum StateBinding<'a> {
+    None,
+    /// Passed as the first argument and replaced with the return value.
+    Update(&'a mut Global<Value>),
+    /// Replaced with the return value without being passed in.
+    Initialize(&'a mut Global<Value>),
+    /// Passed as the first argument, the return value is not kept.
+    Observe(&'a Global<Value>),
+}
+
+/// The prelude's `__golemCodec`, looked up once when the isolate is
+/// created. `encode` and `decode` represent what a structured clone keeps,
+/// e.g. `Map`s and cycles, as JSON and are used for the state the runtime
+/// keeps, `clone` copies it, `plain` turns a value into the JSON clients
+/// are sent.
+struct Codec {
+    encode: Global<Function>,
+    decode: Global<Function>,
+    clone: Global<Function>,
+    plain: Global<Function>,
+}
+
+/// Calls one of the codec's functions. Isolates restored from a snapshot
+/// without the codec get the value back unchanged. None if the function
+/// threw, with the exception left to the caller's `TryCatch`.
+fn call_codec<'sc>(
+    scope: &mut impl v8::ToLocal<'sc>,
+    context: Local<'sc, v8::Context>,
+    function: Option<&Global<Function>>,
+    value: Local<'sc, Value>,
+) -> Option<Local<'sc, Value>> {
+    match function {
+        Some(function) => {
+            let function = function.get(scope).unwrap();
+            let this: Local<Value> = v8::undefined(scope).into();
+            function.call(scope, context, this, &[value])
+        }
+        None => Some(value),
+    }
+}
+
+/// Creates the value a message is passed to JavaScript as. JSON is parsed
+/// as is and encoded JSON decoded by the codec, bytes are copied once into
+/// a buffer owned by a `Uint8Array`. Fails if the string is too long for
+/// V8, if the codec threw or if execution was terminated.
+fn message_to_v8<'sc>(
+    scope: &mut impl v8::ToLocal<'sc>,
+    context: Local<'sc, v8::Context>,
+    message: &Message,
+    codec: Option<&Codec>,
+) -> Option<Local<'sc, Value>> {
+    let value = match message {
+        Message::Json(value) => {
+            let json = v8::String::new(scope, &value.to_string())?;
+            v8::json::parse(context, json)?
+        }
+        Message::Encoded(value) => {
+            let json = v8::String::new(scope, &value.to_string())?;
+            let value = v8::json::parse(context, json)?;
+            call_codec(scope, context, codec.map(|codec| &codec.decode), value)?
+        }
+        Message::Binary(bytes) if bytes.is_empty() => {
+            let buffer = v8::ArrayBuffer::new(scope, 0);
+            v8::Uint8Array::new(buffer, 0, 0).unwrap().into()
+        }
+        Message::Binary(bytes) => {
+            let backing_store = v8::ArrayBuffer::new_backing_store_from_boxed_slice(bytes.to_vec().into_boxed_slice());
+            let mut backing_store = backing_store.make_shared();
+            let buffer = v8::ArrayBuffer::with_backing_store(scope, &mut backing_store);
+            v8::Uint8Array::new(buffer, 0, bytes.len()).unwrap().into()
+        }
+    };
+    Some(value)
+}
+
+/// The error for a call that returned no value. A terminated isolate has
+/// no exception to inspect, so it is reported as `Terminated`.
+fn caught_error<'sc>(scope: &mut impl v8::ToLocal<'sc>, tc: &v8::TryCatch) -> JSError {
+    match tc.exception() {
+        Some(exception) if !tc.has_terminated() => JSError::from_v8_exception(scope, exception),
+        _ => JSError {
+            message: Terminated.to_string(),
+            source_line: None,
+            script_resource_name: None,
+            line_number: None,
+            start_column: None,
+            end_column: None,
+            frames: vec![],
+        },
+    }
+}
+
+/// Execution stopped without an exception, e.g. because it was terminated
+/// from another thread.
+#[derive(Debug)]
+pub struct Terminated;
+
+impl std::error::Error for Terminated {}
+
+impl std::fmt::Display for Terminated {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "execution was terminated")
+    }
+}
+
+/// Creates the value of a state the runtime kept, which was encoded as
+/// `GolemIsolate::persisted_state` encodes it. Unlike in messages, where
+/// only `Message::Encoded` is, JSON is decoded.
+fn state_to_v8<'sc>(
+    scope: &mut impl v8::ToLocal<'sc>,
+    context: Local<'sc, v8::Context>,
+    state: &Message,
+    codec: Option<&Codec>,
+) -> Option<Local<'sc, Value>> {
+    let value = message_to_v8(scope, context, state, codec)?;
+    match state {
+        Message::Json(_) => call_codec(scope, context, codec.map(|codec| &codec.decode), value),
+        Message::Binary(_) | Message::Encoded(_) => Some(value),
+    }
+}
+
+/// Takes a value back from JavaScript to be sent to a client.
+/// `ArrayBuffer`s and their views are read as bytes, anything else is
+/// turned into plain JSON by the codec, with `undefined` mapping to `null`.
+/// None if the codec threw or execution was terminated.
+fn message_from_v8<'sc>(
+    scope: &mut impl v8::ToLocal<'sc>,
+    context: Local<'sc, v8::Context>,
+    value: Local<'sc, Value>,
+    codec: Option<&Codec>,
+) -> Option<Message> {
+    to_message(scope, context, value, codec.map(|codec| &codec.plain))
+}
+
+/// Takes a value back from JavaScript, passing anything that is not bytes
+/// through `convert` before it is stringified: `plain` for clients,
+/// `encode` for the state the runtime keeps, which `state_to_v8` restores.
+fn to_message<'sc>(
+    scope: &mut impl v8::ToLocal<'sc>,
+    context: Local<'sc, v8::Context>,
+    value: Local<'sc, Value>,
+    convert: Option<&Global<Function>>,
+) -> Option<Message> {
+    if value.is_array_buffer() {
+        let buffer = Local::<v8::ArrayBuffer>::try_from(value).unwrap();
+        let view: Local<Value> = v8::Uint8Array::new(buffer, 0, buffer.byte_length()).unwrap().into();
+        return to_message(scope, context, view, convert);
+    }
+    if value.is_array_buffer_view() {
+        let view = Local::<v8::ArrayBufferView>::try_from(value).unwrap();
+        return Some(Message::Binary(Bytes::copy_from_slice(&ZeroCopyBuf::new(view))));
+    }
+    if value.is_undefined() {
+        return Some(Message::Json(serde_json::Value::Null));
+    }
+    let value = call_codec(scope, context, convert, value)?;
+    let json = v8::json::stringify(context, value)?.to_rust_string_lossy(scope);
+    let json = serde_json::from_str(&json).unwrap_or(serde_json::Value::Null);
+    Some(Message::Json(json))
+}
+
 trait Invokeable {
-    fn invoke_function(self: &mut Self, handle: &Global<Function>);
+    fn invoke_function(
+        self: &mut Self,
+        handle: &Global<Function>,
+        state: StateBinding,
+        args: &[Message],
+        context_factory: Option<&Global<Function>>,
+        codec: Option<&Codec>,
+    ) -> Result<Message, JSError>;
 }
 
 impl Invokeable for CoreIsolate {
-    fn invoke_function(self: &mut Self, handle: &Global<Function>) {
-        // let js_error_create_fn = &*self.js_error_create_fn;
+    /// Calls the function behind `handle` with `args`, binding the actor's
+    /// state as described by `state`. The last argument is the invocation
+    /// context, which is passed through `context_factory` when given so
+    /// that `ctx` carries the runtime's methods. The return value is handed
+    /// back as described by `message_from_v8`.
+    fn invoke_function(
+        self: &mut Self,
+        handle: &Global<Function>,
+        state: StateBinding,
+        args: &[Message],
+        context_factory: Option<&Global<Function>>,
+        codec: Option<&Codec>,
+    ) -> Result<Message, JSError> {
         let v8_isolate = self.v8_isolate.as_mut().unwrap();
 
         let mut hs = v8::HandleScope::new(v8_isolate);
@@ -96,41 +344,74 @@ impl Invokeable for CoreIsolate {
         let mut cs = v8::ContextScope::new(scope, context);
         let scope = cs.enter();
 
-
         let mut try_catch = v8::TryCatch::new(scope);
         let tc = try_catch.enter();
 
-        let arg1 = v8::Local::from(v8::Number::new(scope, 1.0));
-        let arg2 = v8::Local::from(v8::Number::new(scope, 2.0));
-
-        // let global = context.global(scope);
+        let mut call_args: Vec<Local<Value>> = Vec::with_capacity(args.len() + 1);
+        match &state {
+            StateBinding::Update(state) => {
+                call_args.push(state.get(scope).unwrap_or_else(|| v8::undefined(scope).into()));
+            }
+            StateBinding::Observe(state) => {
+                call_args.push(state.get(scope).unwrap_or_else(|| v8::undefined(scope).into()));
+            }
+            StateBinding::None | StateBinding::Initialize(_) => {}
+        }
+        for arg in args {
+            match message_to_v8(scope, context, arg, codec) {
+                Some(arg) => call_args.push(arg),
+                None => return Err(caught_error(scope, tc)),
+            }
+        }
 
-        // // v8::Local::
-        // let main = global.get(scope, context, function_name);
+        let this = v8::Object::new(scope);
+        if let (Some(factory), Some(raw_context)) = (context_factory, call_args.pop()) {
+            let factory: v8::Local<v8::Function> = factory.get(scope).unwrap();
+            match factory.call(scope, context, this.into(), &[raw_context]) {
+                Some(ctx) => call_args.push(ctx),
+                None => return Err(caught_error(scope, tc)),
+            }
+        }
 
         let function: v8::Local<v8::Function> = handle.get(scope).unwrap();
-        // v8::Local::<v8::Function>::try_from(handle).unwrap();
-        // let main: v8::Local<v8::Function> = v8::Local::<v8::Function>::try_from(handle).unwrap();
+        let result = match function.call(scope, context, this.into(), &call_args) {
+            Some(result) => result,
+            None => return Err(caught_error(scope, tc)),
+        };
 
-        // let global = context.global(scope);
-        let this = v8::Object::new(scope);
-        let result = function.call(scope, context, this.into(), &[arg1, arg2]).unwrap();
-        // let result = scope.escape(result);
-        // let result: Global<Value> = v8::Global::new_from(scope, result);
+        let response = match message_from_v8(scope, context, result, codec) {
+            Some(response) => response,
+            None => return Err(caught_error(scope, tc)),
+        };
 
-        let str = result.to_string(scope).unwrap();
+        match state {
+            StateBinding::Update(state) | StateBinding::Initialize(state) => state.set(scope, result),
+            StateBinding::None | StateBinding::Observe(_) => {}
+        }
 
-        // Result::Ok(result)
+        Ok(response)
     }
 }
 
 
 pub struct GolemIsolate {
     core_isolate: CoreIsolate,
+    manifest: ActorManifest,
     main_handle: Global<Function>,
+    /// The prelude's `__golemServe`, if the actor exports `fetch`.
+    serve_handle: Option<Global<Function>>,
     cache_handle: Option<Global<Function>>,
+    migrate_handle: Option<Global<Function>>,
+    context_handle: Option<Global<Function>>,
+    codec: Option<Codec>,
+    lifecycle_handles: HashMap<LifecycleHook, Global<Function>>,
+    lifecycle_limits: LifecycleLimits,
+    response_cache: ResponseCache,
     snapshot: Option<GolemSnapshot>,
+    source_maps: SourceMaps,
     state: Global<Value>,
+    /// The state at the last checkpoint, empty outside of a transaction.
+    previous_state: Global<Value>,
 }
 
 // fn register_op<F>(
@@ -199,55 +480,69 @@ pub struct GolemIsolate {
 // These requirements are largely encoded in the GolemIsolate struct,
 // but the most important of which is that the code contains a main method
 
-fn create_and_setup_isolate(startup_data: StartupData) -> Result<Box<CoreIsolate>, IsolateCreationError> {
-    let (core_startup_data, script) = match startup_data {
-        StartupData::Script(script) => (deno_core::StartupData::None, Some(script)), //Won't run this script here as it may panic
-        StartupData::Snapshot(snapshot) => {
-            let data = snapshot.to_startup_data();
-            (deno_core::StartupData::Snapshot(JustCreated(data)), None)
-        }
-        StartupData::NativeSnapshot(data) => {
-            (deno_core::StartupData::Snapshot(JustCreated(data)), None)
-        }
-    };
+fn create_script_isolate(script: Script) -> Result<Box<CoreIsolate>, IsolateCreationError> {
+    let mut core_isolate = CoreIsolate::new(deno_core::StartupData::None, true);
+    core_isolate.execute("golem.js", PRELUDE_SOURCE).map_err(FailedToCompileCode)?;
+    core_isolate.execute(script.filename, script.source).map_err(FailedToCompileCode)?;
+    Ok(core_isolate)
+}
 
-    let mut core_isolate = CoreIsolate::new(core_startup_data, script.is_some());
+fn restore_isolate(data: rusty_v8::StartupData) -> Box<CoreIsolate> {
+    CoreIsolate::new(deno_core::StartupData::Snapshot(JustCreated(data)), false)
+}
 
+// Modules can only be instantiated on an EsIsolate. Once the module graph
+// has been evaluated, its exports are reachable from the global object, so
+// the snapshot taken from here can be restored as a plain CoreIsolate.
+fn create_module_isolate(bundle: &ModuleBundle) -> Result<Box<EsIsolate>, IsolateCreationError> {
+    let loader = Rc::new(BundleModuleLoader::new(bundle.clone()));
+    let mut es_isolate = EsIsolate::new(loader, deno_core::StartupData::None, true);
+    es_isolate.execute("golem.js", PRELUDE_SOURCE).map_err(FailedToCompileCode)?;
 
-    match script {
-        Some(script) => {
-            let result = core_isolate.execute(script.filename, script.source);
-            match result {
-                Err(_) => Err(FailedToCompileCode),
-                Ok(_) => Ok(())
-            }
-        }
-        None => Ok(())
-    }?;
+    let entry_source = bundle.entry_source().map_err(FailedToLoadModule)?;
+    let entry = ModuleSpecifier::resolve_url(ENTRY_MODULE).unwrap();
 
-    Ok(core_isolate)
+    let module_id = futures::executor::block_on(es_isolate.load_module(&entry, Some(entry_source)))
+        .map_err(FailedToLoadModule)?;
+
+    es_isolate.mod_evaluate(module_id).map_err(FailedToCompileCode)?;
+
+    Ok(es_isolate)
 }
 
 
 impl GolemIsolate {
     fn try_new(startup_data: StartupData) -> Result<Box<Self>, IsolateCreationError> {
         let (snapshot, startup_data) = match startup_data {
-            StartupData::Snapshot(snapshot) => {
-                Ok((None, StartupData::Snapshot(snapshot)))
-            }
-            StartupData::NativeSnapshot(_) => {
-                Ok((None, startup_data))
-            }
+            StartupData::Snapshot(snapshot) => (None, snapshot.to_startup_data()),
             StartupData::Script(script) => {
-                let mut core_isolate = create_and_setup_isolate(StartupData::Script(script))?;
+                let mut core_isolate = create_script_isolate(script)?;
                 let snapshot = core_isolate.snapshot();
-                let golem_snapshot = GolemSnapshot::from_startup_data(&snapshot);
-
-                Ok((Some(golem_snapshot), StartupData::NativeSnapshot(snapshot)))
+                (Some(GolemSnapshot::from_startup_data(&snapshot)), snapshot)
             }
-        }?;
+            StartupData::Module(bundle) => {
+                let mut es_isolate = create_module_isolate(bundle)?;
+                let snapshot = es_isolate.snapshot();
+                (Some(GolemSnapshot::from_startup_data(&snapshot)), snapshot)
+            }
+        };
 
-        let mut core_isolate = create_and_setup_isolate(startup_data)?;
+        let mut core_isolate = restore_isolate(startup_data);
+
+        // Only freshly created snapshots are validated, restoring one implies
+        // that its script already passed these checks.
+        let snapshot = match snapshot {
+            Some(snapshot) => {
+                let manifest = Self::try_get_manifest(&mut core_isolate)
+                    .map_err(|error| vec![error])
+                    .and_then(|declarations| {
+                        ActorManifest::build(|name| Self::inspect_export(&mut core_isolate, name), declarations)
+                    })
+                    .map_err(InvalidManifest)?;
+                Some(snapshot.with_manifest(manifest))
+            }
+            None => None,
+        };
 
         let main_handle = Self::try_get_function_handle(&mut core_isolate, "main");
         let main_handle = match main_handle {
@@ -256,7 +551,19 @@ impl GolemIsolate {
         }?;
 
 
+        let serve_handle = Self::try_get_function_handle(&mut core_isolate, "fetch")
+            .and_then(|_| Self::try_get_function_handle(&mut core_isolate, "__golemServe"));
         let cache_handle = Self::try_get_function_handle(&mut core_isolate, "cache");
+        let migrate_handle = Self::try_get_function_handle(&mut core_isolate, "migrate");
+        let context_handle = Self::try_get_function_handle(&mut core_isolate, "__golemContext");
+        let codec = Self::try_get_codec(&mut core_isolate);
+
+        let mut lifecycle_handles = HashMap::new();
+        for hook in [LifecycleHook::Init, LifecycleHook::Activate, LifecycleHook::Passivate, LifecycleHook::Stop].iter() {
+            if let Some(handle) = Self::try_get_function_handle(&mut core_isolate, hook.export_name()) {
+                lifecycle_handles.insert(*hook, handle);
+            }
+        }
 
 
         let state: Global<Value> = {
@@ -273,10 +580,20 @@ impl GolemIsolate {
 
         let golem = Self {
             core_isolate: *core_isolate,
+            manifest: ActorManifest::default(),
             main_handle,
+            serve_handle,
             cache_handle,
+            migrate_handle,
+            context_handle,
+            codec,
+            lifecycle_handles,
+            lifecycle_limits: LifecycleLimits::default(),
+            response_cache: ResponseCache::new(),
             snapshot,
+            source_maps: SourceMaps::new(),
             state,
+            previous_state: Global::new(),
         };
 
         Ok(Box::from(golem))
@@ -309,6 +626,109 @@ impl GolemIsolate {
             .map(|function: Local<Function>| Global::new_from(scope, function))
     }
 
+    /// The prelude's `__golemCodec`, which actor code cannot replace. None
+    /// for isolates restored from a snapshot taken without it.
+    fn try_get_codec(core_isolate: &mut CoreIsolate) -> Option<Codec> {
+        let v8_isolate = core_isolate.v8_isolate.as_mut().unwrap();
+
+        let mut hs = HandleScope::new(v8_isolate);
+        let scope = hs.enter();
+        assert!(!core_isolate.global_context.is_empty());
+        let context = core_isolate.global_context.get(scope).unwrap();
+        let mut cs = ContextScope::new(scope, context);
+        let scope = cs.enter();
+        let global = context.global(scope);
+
+        let codec_name = v8::String::new(scope, "__golemCodec").unwrap().into();
+        let codec = global.get(scope, context, codec_name)?;
+        let codec = Local::<v8::Object>::try_from(codec).ok()?;
+        let mut function = |name: &str| {
+            let name = v8::String::new(scope, name).unwrap().into();
+            let function = codec.get(scope, context, name)?;
+            let function = Local::<Function>::try_from(function).ok()?;
+            Some(Global::new_from(scope, function))
+        };
+        Some(Codec {
+            encode: function("encode")?,
+            decode: function("decode")?,
+            clone: function("clone")?,
+            plain: function("plain")?,
+        })
+    }
+
+    fn inspect_export(core_isolate: &mut CoreIsolate, name: &str) -> Export {
+        let mut v8_isolate = core_isolate.v8_isolate.as_mut().unwrap();
+
+        let mut hs = HandleScope::new(v8_isolate);
+        let scope = hs.enter();
+        assert!(!core_isolate.global_context.is_empty());
+
+        let context = core_isolate.global_context.get(scope).unwrap();
+        let mut cs = ContextScope::new(scope, context);
+        let scope = cs.enter();
+        let global = context.global(scope);
+
+        let export_name = rusty_v8::String::new(scope, name).unwrap().into();
+        let export = match global.get(scope, context, export_name) {
+            Some(export) if !export.is_undefined() => export,
+            _ => return Export::Missing,
+        };
+        if !export.is_function() {
+            return Export::Other;
+        }
+
+        let function = Local::<Function>::try_from(export).unwrap();
+        let length_name = rusty_v8::String::new(scope, "length").unwrap().into();
+        let arity = function
+            .get(scope, context, length_name)
+            .and_then(|length| length.integer_value(scope))
+            .unwrap_or(0);
+
+        Export::Function { arity: arity as u32 }
+    }
+
+    /// The script's `manifest` as JSON, None if it does not declare one.
+    /// It is looked up by name rather than on the global object, so that a
+    /// classic script's top-level `const manifest` is found as well as
+    /// exports, which are copied onto the global object. A manifest that
+    /// throws when read or cannot be serialized is malformed.
+    fn try_get_manifest(core_isolate: &mut CoreIsolate) -> Result<Option<serde_json::Value>, ManifestError> {
+        let v8_isolate = core_isolate.v8_isolate.as_mut().unwrap();
+
+        let mut hs = HandleScope::new(v8_isolate);
+        let scope = hs.enter();
+        assert!(!core_isolate.global_context.is_empty());
+
+        let context = core_isolate.global_context.get(scope).unwrap();
+        let mut cs = ContextScope::new(scope, context);
+        let scope = cs.enter();
+
+        let mut try_catch = v8::TryCatch::new(scope);
+        let tc = try_catch.enter();
+
+        let source = v8::String::new(scope, "typeof manifest === 'undefined' ? undefined : manifest").unwrap();
+        let mut script = v8::Script::compile(scope, context, source, None).unwrap();
+        let value = match script.run(scope, context) {
+            Some(value) => value,
+            None => return Err(ManifestError::MalformedManifest(caught_error(scope, tc).message)),
+        };
+        if value.is_undefined() {
+            return Ok(None);
+        }
+
+        let json = match v8::json::stringify(context, value) {
+            Some(json) => json.to_rust_string_lossy(scope),
+            None => return Err(ManifestError::MalformedManifest(caught_error(scope, tc).message)),
+        };
+        serde_json::from_str(&json)
+            .map(Some)
+            .map_err(|_| ManifestError::MalformedManifest("it cannot be serialized as JSON".to_string()))
+    }
+
+    pub fn manifest(&self) -> &ActorManifest {
+        &self.manifest
+    }
+
     pub fn try_create_snapshot(script: Script) -> Result<GolemSnapshot, IsolateCreationError> {
         let mut golem = Self::try_new(StartupData::Script(script))?;
         let snapshot = golem.snapshot.take().unwrap();
@@ -316,11 +736,39 @@ impl GolemIsolate {
         Ok(snapshot)
     }
 
-    pub fn new(snapshot: GolemSnapshot) -> Box<Self> {
+    pub fn try_create_module_snapshot(bundle: &ModuleBundle) -> Result<GolemSnapshot, IsolateCreationError> {
+        let mut golem = Self::try_new(StartupData::Module(bundle))?;
+        let snapshot = golem.snapshot.take().unwrap();
+
+        Ok(snapshot)
+    }
+
+    /// Transpiles a TypeScript bundle before loading it. Stack traces of
+    /// exceptions thrown by isolates restored from the resulting snapshot
+    /// point at the original TypeScript lines.
+    pub fn try_create_typescript_snapshot(compiler: &mut TypeScriptCompiler, bundle: &ModuleBundle) -> Result<GolemSnapshot, IsolateCreationError> {
+        let (bundle, source_maps) = compiler.transpile_bundle(bundle).map_err(FailedToTranspile)?;
+        let snapshot = Self::try_create_module_snapshot(&bundle)?;
+
+        Ok(snapshot.with_source_maps(source_maps))
+    }
+
+    pub fn new(mut snapshot: GolemSnapshot) -> Box<Self> {
+        let source_maps = mem::take(&mut snapshot.source_maps);
+        let manifest = mem::take(&mut snapshot.manifest);
         let golem = Self::try_new(StartupData::Snapshot(&snapshot));
 
         match golem {
-            Ok(g) => {
+            Ok(mut g) => {
+                if !source_maps.is_empty() {
+                    let error_source_maps = source_maps.clone();
+                    g.core_isolate.set_js_error_create_fn(move |js_error| {
+                        ErrBox::from(apply_source_maps(js_error, &error_source_maps))
+                    });
+                }
+                g.source_maps = source_maps;
+                g.manifest = manifest;
+
                 // V8 takes ownership of the snapshot, so to prevent a doublefree, need to forget about the snapshot data
                 forget(snapshot);
                 g
@@ -329,14 +777,300 @@ impl GolemIsolate {
         }
     }
 
-    pub fn invoke_main(&mut self) {
-        &self.core_isolate.invoke_function(&self.main_handle);
+    /// Asks the actor's `cache` export whether a message is read-only and,
+    /// if it is, for the reply memoized for it, which is returned without
+    /// running `main`. A message that may change state clears the cache.
+    /// See the `cache` module for the semantics.
+    pub fn lookup_cache(&mut self, msg: &Message, ctx: &serde_json::Value) -> Result<CacheLookup, ErrBox> {
+        let directive = match self.invoke_cache(msg, ctx)? {
+            Some(directive) => directive,
+            None => {
+                self.response_cache.invalidate();
+                return Ok(CacheLookup::Bypass);
+            }
+        };
+
+        match self.response_cache.get(&directive, Instant::now()) {
+            Some(reply) => Ok(CacheLookup::Hit(reply)),
+            None => Ok(CacheLookup::Miss(directive)),
+        }
+    }
+
+    /// Memoizes the reply to a message `lookup_cache` missed: what `main`
+    /// passed to `ctx.reply` or, without such a call, what it returned.
+    pub fn memoize(&mut self, directive: &CacheDirective, reply: Message) {
+        self.response_cache.insert(directive, reply, Instant::now());
+    }
+
+    /// Runs `main(state, msg, ctx)`, storing what it returns as the new state.
+    /// If `main` exceeds its time limit it is terminated and reported as
+    /// `TimedOut`.
+    pub fn invoke_main(&mut self, msg: &Message, ctx: &serde_json::Value) -> Result<Message, ErrBox> {
+        let limit = self.lifecycle_limits.main;
+        let isolate_handle = self.core_isolate.v8_isolate.as_mut().unwrap().thread_safe_handle();
+        let deadline = Watchdog::arm(isolate_handle, limit);
+
+        let args = [msg.clone(), Message::Json(ctx.clone())];
+        let result = self.core_isolate
+            .invoke_function(&self.main_handle, StateBinding::Update(&mut self.state), &args, self.context_handle.as_ref(), self.codec.as_ref());
+
+        if deadline.disarm() {
+            return Err(ErrBox::from(TimedOut { export: "main", limit }));
+        }
+
+        let source_maps = &self.source_maps;
+        result.map_err(|e| ErrBox::from(apply_source_maps(e, source_maps)))
+    }
+
+    /// Whether the actor exports `fetch`, and so can be sent HTTP requests.
+    pub fn serves_http(&self) -> bool {
+        self.serve_handle.is_some()
+    }
+
+    /// Runs `fetch(state, request, ctx)` through the prelude, which turns
+    /// the JSON `request` into a `Request` and answers the caller under
+    /// `ctx.replyId` with the returned `Response`. Changes `fetch` makes
+    /// to the state in place are kept.
+    pub fn invoke_fetch(&mut self, request: &serde_json::Value, ctx: &serde_json::Value) -> Result<serde_json::Value, ErrBox> {
+        let serve_handle = self.serve_handle.as_ref().ok_or_else(|| ErrBox::from(NoFetchHandler))?;
+        self.response_cache.clear();
+        let args = [Message::Json(request.clone()), Message::Json(ctx.clone())];
+        let source_maps = &self.source_maps;
+        self.core_isolate
+            .invoke_function(serve_handle, StateBinding::Observe(&self.state), &args, self.context_handle.as_ref(), self.codec.as_ref())
+            .map(Message::into_json)
+            .map_err(|e| ErrBox::from(apply_source_maps(e, source_maps)))
+    }
+
+    fn invoke_cache(&mut self, msg: &Message, ctx: &serde_json::Value) -> Result<Option<CacheDirective>, ErrBox> {
+        let cache_handle = match &self.cache_handle {
+            Some(handle) => handle,
+            None => return Ok(None),
+        };
+
+        let args = [msg.clone(), Message::Json(ctx.clone())];
+        let source_maps = &self.source_maps;
+        let value = self.core_isolate
+            .invoke_function(cache_handle, StateBinding::None, &args, self.context_handle.as_ref(), self.codec.as_ref())
+            .map_err(|e| ErrBox::from(apply_source_maps(e, source_maps)))?;
+
+        let directive = CacheDirective::from_value(value.into_json())?;
+        Ok(directive)
+    }
+
+    pub fn cache_metrics(&self) -> CacheMetrics {
+        self.response_cache.metrics()
+    }
+
+    /// Runs `migrate(oldState, fromVersion, ctx)` if the script exports it,
+    /// replacing the state with what it returns.
+    pub fn invoke_migrate(&mut self, from_version: u32, ctx: &serde_json::Value) -> Result<(), ErrBox> {
+        let migrate_handle = match &self.migrate_handle {
+            Some(handle) => handle,
+            None => return Ok(()),
+        };
+        self.response_cache.clear();
+
+        let args = [Message::Json(json!(from_version)), Message::Json(ctx.clone())];
+        let source_maps = &self.source_maps;
+        self.core_isolate
+            .invoke_function(migrate_handle, StateBinding::Update(&mut self.state), &args, self.context_handle.as_ref(), self.codec.as_ref())
+            .map(|_| ())
+            .map_err(|e| ErrBox::from(apply_source_maps(e, source_maps)))
+    }
+
+    pub fn set_lifecycle_limits(&mut self, limits: LifecycleLimits) {
+        self.lifecycle_limits = limits;
+    }
+
+    /// Calls a lifecycle export if the actor defines it. `init` replaces the
+    /// state with its return value, the other hooks receive the state as
+    /// their first argument. Hooks that exceed their time limit are
+    /// terminated and reported as `TimedOut`.
+    pub fn invoke_hook(&mut self, hook: LifecycleHook, ctx: &serde_json::Value) -> Result<(), ErrBox> {
+        let handle = match self.lifecycle_handles.get(&hook) {
+            Some(handle) => handle,
+            None => return Ok(()),
+        };
+        self.response_cache.clear();
+
+        let limit = self.lifecycle_limits.limit(hook);
+        let isolate_handle = self.core_isolate.v8_isolate.as_mut().unwrap().thread_safe_handle();
+        let deadline = Watchdog::arm(isolate_handle, limit);
+
+        let binding = match hook {
+            LifecycleHook::Init => StateBinding::Initialize(&mut self.state),
+            _ => StateBinding::Observe(&self.state),
+        };
+        let args = [Message::Json(ctx.clone())];
+        let result = self.core_isolate.invoke_function(handle, binding, &args, self.context_handle.as_ref(), self.codec.as_ref());
+
+        if deadline.disarm() {
+            return Err(ErrBox::from(TimedOut { export: hook.export_name(), limit }));
+        }
+
+        let source_maps = &self.source_maps;
+        result
+            .map(|_| ())
+            .map_err(|e| ErrBox::from(apply_source_maps(e, source_maps)))
+    }
+
+    /// Replaces the state, e.g. with the persisted state of a rehydrated actor.
+    pub fn set_state(&mut self, state: &Message) -> Result<(), ErrBox> {
+        self.response_cache.clear();
+        let v8_isolate = self.core_isolate.v8_isolate.as_mut().unwrap();
+
+        let mut hs = HandleScope::new(v8_isolate);
+        let scope = hs.enter();
+        assert!(!self.core_isolate.global_context.is_empty());
+        let context = self.core_isolate.global_context.get(scope).unwrap();
+        let mut cs = ContextScope::new(scope, context);
+        let scope = cs.enter();
+
+        let mut try_catch = v8::TryCatch::new(scope);
+        let tc = try_catch.enter();
+
+        match state_to_v8(scope, context, state, self.codec.as_ref()) {
+            Some(value) => {
+                self.state.set(scope, value);
+                Ok(())
+            }
+            None => Err(ErrBox::from(caught_error(scope, tc))),
+        }
+    }
+
+    /// The state as clients are sent it, see `message_from_v8`.
+    pub fn get_state(&mut self) -> Result<Message, ErrBox> {
+        self.state_message(|codec| &codec.plain)
+    }
+
+    /// The state as the runtime keeps it, encoded so that `set_state`
+    /// restores what it held, e.g. persisted when the actor is passivated.
+    pub fn persisted_state(&mut self) -> Result<Message, ErrBox> {
+        self.state_message(|codec| &codec.encode)
+    }
+
+    fn state_message(&mut self, convert: fn(&Codec) -> &Global<Function>) -> Result<Message, ErrBox> {
+        let v8_isolate = self.core_isolate.v8_isolate.as_mut().unwrap();
+
+        let mut hs = HandleScope::new(v8_isolate);
+        let scope = hs.enter();
+        assert!(!self.core_isolate.global_context.is_empty());
+        let context = self.core_isolate.global_context.get(scope).unwrap();
+        let mut cs = ContextScope::new(scope, context);
+        let scope = cs.enter();
+
+        let mut try_catch = v8::TryCatch::new(scope);
+        let tc = try_catch.enter();
+
+        let state = match self.state.get(scope) {
+            Some(state) => state,
+            None => return Ok(Message::default()),
+        };
+        to_message(scope, context, state, self.codec.as_ref().map(convert))
+            .ok_or_else(|| ErrBox::from(caught_error(scope, tc)))
+    }
+
+    /// Starts a transaction over the state, which is kept by handle until
+    /// `commit_state` or `rollback_state`. With `copy`, functions invoked
+    /// from then on work on a copy of the state, so that changes made in
+    /// place can be undone. The copy is made within V8 by the prelude's
+    /// codec, which walks the whole state, so handlers that leave the state
+    /// alone should not ask for it.
+    pub fn checkpoint_state(&mut self, copy: bool) -> Result<(), ErrBox> {
+        let v8_isolate = self.core_isolate.v8_isolate.as_mut().unwrap();
+
+        let mut hs = HandleScope::new(v8_isolate);
+        let scope = hs.enter();
+        assert!(!self.core_isolate.global_context.is_empty());
+        let context = self.core_isolate.global_context.get(scope).unwrap();
+        let mut cs = ContextScope::new(scope, context);
+        let scope = cs.enter();
+
+        let mut try_catch = v8::TryCatch::new(scope);
+        let tc = try_catch.enter();
+
+        let state = self.state.get(scope).unwrap_or_else(|| v8::undefined(scope).into());
+        self.previous_state.set(scope, state);
+        if !copy {
+            return Ok(());
+        }
+        let clone = self.codec.as_ref().map(|codec| &codec.clone);
+        let copy = match call_codec(scope, context, clone, state) {
+            Some(copy) => copy,
+            None => {
+                self.previous_state.reset(scope);
+                return Err(ErrBox::from(caught_error(scope, tc)));
+            }
+        };
+        self.state.set(scope, copy);
+        Ok(())
+    }
+
+    /// Ends the transaction started by `checkpoint_state`, keeping the
+    /// state. With `diff`, returns the state if it changed, in which case
+    /// both states are serialized as clients are sent them, and only then.
+    /// A state that is still the value checkpointed did not change.
+    pub fn commit_state(&mut self, diff: bool) -> Result<Option<Message>, ErrBox> {
+        let v8_isolate = self.core_isolate.v8_isolate.as_mut().unwrap();
+
+        let mut hs = HandleScope::new(v8_isolate);
+        let scope = hs.enter();
+        assert!(!self.core_isolate.global_context.is_empty());
+        let context = self.core_isolate.global_context.get(scope).unwrap();
+        let mut cs = ContextScope::new(scope, context);
+        let scope = cs.enter();
+
+        let previous = match self.previous_state.get(scope) {
+            Some(previous) => previous,
+            None => return Ok(None),
+        };
+        self.previous_state.reset(scope);
+        if !diff {
+            return Ok(None);
+        }
+
+        let mut try_catch = v8::TryCatch::new(scope);
+        let tc = try_catch.enter();
+
+        let codec = self.codec.as_ref();
+        let state = self.state.get(scope).unwrap_or_else(|| v8::undefined(scope).into());
+        if state.strict_equals(previous) {
+            return Ok(None);
+        }
+        let previous = message_from_v8(scope, context, previous, codec);
+        let messages = previous.and_then(|previous| Some((previous, message_from_v8(scope, context, state, codec)?)));
+        match messages {
+            Some((previous, state)) if state != previous => Ok(Some(state)),
+            Some(_) => Ok(None),
+            None => Err(ErrBox::from(caught_error(scope, tc))),
+        }
+    }
+
+    /// Ends the transaction started by `checkpoint_state`, restoring the
+    /// state as it was then.
+    pub fn rollback_state(&mut self) {
+        let v8_isolate = self.core_isolate.v8_isolate.as_mut().unwrap();
+
+        let mut hs = HandleScope::new(v8_isolate);
+        let scope = hs.enter();
+
+        if let Some(previous) = self.previous_state.get(scope) {
+            self.state.set(scope, previous);
+            self.previous_state.reset(scope);
+        }
     }
 
     pub async fn get_future(self) -> Result<(), ErrBox> {
         self.core_isolate.await
     }
 
+    /// Drives the isolate's pending ops, e.g. the async work an actor
+    /// started before deferring its reply. Ready once no ops are pending.
+    pub fn poll(&mut self, cx: &mut Context) -> Poll<Result<(), ErrBox>> {
+        Pin::new(&mut self.core_isolate).poll(cx)
+    }
+
 
     pub fn register_op<F>(&mut self, name: &str, handler: F) -> OpId
         where
@@ -353,3 +1087,217 @@ impl GolemIsolate {
     }
 }
 
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::time::Duration;
+
+    #[test]
+    fn test_module_imports_across_modules() {
+        let bundle = ModuleBundle::new("main.js")
+            .with_module("main.js", "import { add } from './lib/math.js';\n\
+                                     export function main(state, msg) { return add(msg.a, msg.b); }")
+            .with_module("lib/math.js", "export function add(a, b) { return a + b; }");
+        let snapshot = GolemIsolate::try_create_module_snapshot(&bundle).unwrap();
+        let mut isolate = GolemIsolate::new(snapshot);
+
+        let response = isolate.invoke_main(&Message::Json(json!({"a": 2, "b": 3})), &json!({})).unwrap();
+        assert_eq!(response, Message::Json(json!(5)));
+    }
+
+    #[test]
+    fn test_cache_memoizes_reply() {
+        let script = Script {
+            source: "function cache(msg) { return msg.type === 'get' ? 'get' : null; }\n\
+                     function main(state, msg) { return msg.type === 'add' ? state + 1 : state; }",
+            filename: "counter.js",
+        };
+        let mut isolate = GolemIsolate::new(GolemIsolate::try_create_snapshot(script).unwrap());
+        isolate.set_state(&Message::Json(json!(1))).unwrap();
+        let get = Message::Json(json!({"type": "get"}));
+        let add = Message::Json(json!({"type": "add"}));
+
+        let directive = match isolate.lookup_cache(&get, &json!({})).unwrap() {
+            CacheLookup::Miss(directive) => directive,
+            lookup => panic!("expected a miss, got {:?}", lookup),
+        };
+        let response = isolate.invoke_main(&get, &json!({})).unwrap();
+        assert_eq!(response, Message::Json(json!(1)));
+        isolate.memoize(&directive, Message::Json(json!("one")));
+
+        let lookup = isolate.lookup_cache(&get, &json!({})).unwrap();
+        assert_eq!(lookup, CacheLookup::Hit(Message::Json(json!("one"))));
+        assert_eq!(isolate.cache_metrics().hits, 1);
+
+        assert_eq!(isolate.lookup_cache(&add, &json!({})).unwrap(), CacheLookup::Bypass);
+        isolate.invoke_main(&add, &json!({})).unwrap();
+        assert!(matches!(isolate.lookup_cache(&get, &json!({})).unwrap(), CacheLookup::Miss(_)));
+    }
+
+    #[test]
+    fn test_state_changes_clear_cache() {
+        let script = Script {
+            source: "function cache() { return 'get'; }\n\
+                     function main(state) { return state; }\n\
+                     function migrate(state) { return state + 1; }",
+            filename: "migrating.js",
+        };
+        let mut isolate = GolemIsolate::new(GolemIsolate::try_create_snapshot(script).unwrap());
+        isolate.set_state(&Message::Json(json!(1))).unwrap();
+        let get = Message::Json(json!({}));
+        let memoize = |isolate: &mut GolemIsolate| match isolate.lookup_cache(&get, &json!({})).unwrap() {
+            CacheLookup::Miss(directive) => {
+                let response = isolate.invoke_main(&get, &json!({})).unwrap();
+                isolate.memoize(&directive, response);
+            }
+            lookup => panic!("expected a miss, got {:?}", lookup),
+        };
+
+        memoize(&mut isolate);
+        isolate.invoke_migrate(1, &json!({})).unwrap();
+        memoize(&mut isolate);
+        isolate.set_state(&Message::Json(json!(5))).unwrap();
+        memoize(&mut isolate);
+        let lookup = isolate.lookup_cache(&get, &json!({})).unwrap();
+        assert_eq!(lookup, CacheLookup::Hit(Message::Json(json!(5))));
+    }
+
+    #[test]
+    fn test_script_manifest_is_found() {
+        let script = Script {
+            source: "const manifest = { permissions: ['fetch'] };\n\
+                     function main(state) { return state; }",
+            filename: "manifest.js",
+        };
+        let snapshot = GolemIsolate::try_create_snapshot(script).unwrap();
+        assert!(snapshot.manifest().has_permission("fetch"));
+    }
+
+    #[test]
+    fn test_unserializable_manifest_is_malformed() {
+        let script = Script {
+            source: "const manifest = { toJSON() { throw new Error('no manifest'); } };\n\
+                     function main(state) { return state; }",
+            filename: "manifest.js",
+        };
+        match GolemIsolate::try_create_snapshot(script) {
+            Err(InvalidManifest(errors)) => {
+                assert!(matches!(&errors[..], [ManifestError::MalformedManifest(message)] if message.contains("no manifest")));
+            }
+            other => panic!("expected a malformed manifest, got {:?}", other.map(|_| ())),
+        }
+    }
+
+    #[test]
+    fn test_persisted_state_keeps_map() {
+        let script = Script {
+            source: "function main(state, msg) {\n\
+                         const counts = state instanceof Map ? state : new Map();\n\
+                         counts.set(msg.key, BigInt(msg.count));\n\
+                         return counts;\n\
+                     }",
+            filename: "counts.js",
+        };
+        let snapshot = GolemIsolate::try_create_snapshot(script).unwrap();
+        let mut isolate = GolemIsolate::new(snapshot.clone());
+
+        let response = isolate.invoke_main(&Message::Json(json!({"key": "a", "count": 1})), &json!({})).unwrap();
+        assert_eq!(response, Message::Json(json!([["a", "1"]])));
+        assert_eq!(isolate.get_state().unwrap(), Message::Json(json!([["a", "1"]])));
+
+        let persisted = isolate.persisted_state().unwrap();
+        let mut isolate = GolemIsolate::new(snapshot);
+        isolate.set_state(&persisted).unwrap();
+        let response = isolate.invoke_main(&Message::Json(json!({"key": "b", "count": 2})), &json!({})).unwrap();
+        assert_eq!(response, Message::Json(json!([["a", "1"], ["b", "2"]])));
+    }
+
+    #[test]
+    fn test_client_message_is_not_decoded() {
+        let script = Script {
+            source: "function main(state, msg) { return [msg instanceof Map, msg.$type, new Set([1])]; }",
+            filename: "echo.js",
+        };
+        let mut isolate = GolemIsolate::new(GolemIsolate::try_create_snapshot(script).unwrap());
+
+        let msg = Message::Json(json!({"$type": "Map", "entries": [[1, 2]]}));
+        let response = isolate.invoke_main(&msg, &json!({})).unwrap();
+        assert_eq!(response, Message::Json(json!([false, "Map", [1]])));
+
+        let msg = Message::Encoded(json!({"$type": "Map", "entries": [[1, 2]]}));
+        let response = isolate.invoke_main(&msg, &json!({})).unwrap();
+        assert_eq!(response, Message::Json(json!([true, null, [1]])));
+    }
+
+    #[test]
+    fn test_codec_cannot_be_replaced() {
+        let script = Script {
+            source: "function main() {\n\
+                         'use strict';\n\
+                         let error = null;\n\
+                         try { globalThis.__golemCodec = null; } catch (e) { error = e.constructor.name; }\n\
+                         return [error, new Map([[1, 2]])];\n\
+                     }",
+            filename: "replace.js",
+        };
+        let mut isolate = GolemIsolate::new(GolemIsolate::try_create_snapshot(script).unwrap());
+
+        let response = isolate.invoke_main(&Message::Json(json!({})), &json!({})).unwrap();
+        assert_eq!(response, Message::Json(json!(["TypeError", [[1, 2]]])));
+    }
+
+    #[test]
+    fn test_codec_error_fails_invocation() {
+        let script = Script {
+            source: "function main() { return { toJSON() { throw new Error('not serializable'); } }; }",
+            filename: "throws.js",
+        };
+        let mut isolate = GolemIsolate::new(GolemIsolate::try_create_snapshot(script).unwrap());
+
+        let error = isolate.invoke_main(&Message::Json(json!({})), &json!({})).unwrap_err();
+        assert!(error.to_string().contains("not serializable"));
+    }
+
+    #[test]
+    fn test_main_times_out() {
+        let script = Script {
+            source: "function main(state, msg) { while (msg.spin) {} return msg; }",
+            filename: "spin.js",
+        };
+        let mut isolate = GolemIsolate::new(GolemIsolate::try_create_snapshot(script).unwrap());
+        isolate.set_lifecycle_limits(LifecycleLimits { main: Duration::from_millis(20), ..LifecycleLimits::default() });
+
+        let error = isolate.invoke_main(&Message::Json(json!({"spin": true})), &json!({})).unwrap_err();
+        assert!(error.downcast_ref::<TimedOut>().is_some());
+
+        let response = isolate.invoke_main(&Message::Json(json!({"spin": false})), &json!({})).unwrap();
+        assert_eq!(response, Message::Json(json!({"spin": false})));
+    }
+
+    #[test]
+    fn test_hook_times_out() {
+        let script = Script {
+            source: "function main() {}\n\
+                     function init() { for (;;) {} }",
+            filename: "init.js",
+        };
+        let mut isolate = GolemIsolate::new(GolemIsolate::try_create_snapshot(script).unwrap());
+        isolate.set_lifecycle_limits(LifecycleLimits { init: Duration::from_millis(20), ..LifecycleLimits::default() });
+
+        let error = isolate.invoke_hook(LifecycleHook::Init, &json!({})).unwrap_err();
+        assert_eq!(error.to_string(), "init did not complete within 20ms");
+    }
+
+    #[test]
+    fn test_missing_import_carries_error() {
+        let bundle = ModuleBundle::new("main.js")
+            .with_module("main.js", "import { add } from './lib/missing.js';\n\
+                                     export function main() {}");
+        match GolemIsolate::try_create_module_snapshot(&bundle) {
+            Err(FailedToLoadModule(error)) => assert!(error.to_string().contains("lib/missing.js")),
+            Err(error) => panic!("unexpected error: {}", error),
+            Ok(_) => panic!("expected the bundle to fail to load"),
+        }
+    }
+}
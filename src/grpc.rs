@@ -0,0 +1,315 @@
+//! A gRPC interface to actors, served next to the HTTP API by `serve`.
+//!
+//! The service is defined in `proto/golem.proto`. Messages, replies, states
+//! and events are JSON values, carried as `google.protobuf.Value`, or bytes
+//! where the protocol allows them, see `crate::message`. Replies an actor
+//! streams are read to their end and returned as bytes.
+
+use crate::controllers::DEFAULT_REPLY_TIMEOUT_MS;
+use crate::events::ActorEvent;
+use crate::message::Message;
+use crate::replies::Reply;
+use crate::runtime::Runtime;
+use crate::supervisor::{ActorExists, SendError, SupervisionPolicy};
+use futures::StreamExt;
+use prost_types::value::Kind;
+use serde_json::Value;
+use std::net::SocketAddr;
+use std::sync::Arc;
+use std::time::Duration;
+use tokio::sync::mpsc;
+use tonic::{Request, Response, Status, Streaming};
+
+pub mod proto {
+    tonic::include_proto!("golem");
+}
+
+use proto::actors_server::{Actors, ActorsServer};
+use proto::{
+    actor_event, ask_request, ask_response, get_state_response, AskRequest, AskResponse, CreateActorRequest, CreateActorResponse, GetStateRequest,
+    GetStateResponse, SendAllResponse, SendRequest, SendResponse, WatchRequest, send_request,
+};
+
+/// The responses buffered for a streaming call.
+const STREAM_CAPACITY: usize = 16;
+
+pub struct ActorService {
+    runtime: Arc<Runtime>,
+}
+
+impl ActorService {
+    pub fn new(runtime: Arc<Runtime>) -> Self {
+        Self { runtime }
+    }
+}
+
+/// Serves the actor service until the server fails.
+pub async fn serve(addr: SocketAddr, runtime: Arc<Runtime>) -> Result<(), tonic::transport::Error> {
+    tonic::transport::Server::builder()
+        .add_service(ActorsServer::new(ActorService::new(runtime)))
+        .serve(addr)
+        .await
+}
+
+#[tonic::async_trait]
+impl Actors for ActorService {
+    async fn create_actor(&self, request: Request<CreateActorRequest>) -> Result<Response<CreateActorResponse>, Status> {
+        let request = request.into_inner();
+        self.runtime
+            .spawn(&request.id, &request.actor_type, SupervisionPolicy::default(), None)
+            .await
+            .map_err(|error| match error.downcast_ref::<ActorExists>() {
+                Some(_) => Status::already_exists(error.to_string()),
+                None => Status::failed_precondition(error.to_string()),
+            })?;
+        Ok(Response::new(CreateActorResponse {}))
+    }
+
+    async fn send(&self, request: Request<SendRequest>) -> Result<Response<SendResponse>, Status> {
+        let request = request.into_inner();
+        self.runtime
+            .tell(&request.id, sent_message(request.message))
+            .map_err(|error| Status::unavailable(error.to_string()))?;
+        Ok(Response::new(SendResponse {}))
+    }
+
+    async fn ask(&self, request: Request<AskRequest>) -> Result<Response<AskResponse>, Status> {
+        let response = ask(&self.runtime, request.into_inner()).await?;
+        Ok(Response::new(response))
+    }
+
+    async fn get_state(&self, request: Request<GetStateRequest>) -> Result<Response<GetStateResponse>, Status> {
+        let state = match self.runtime.get_state(&request.into_inner().id).await.map_err(status)? {
+            Message::Json(state) | Message::Encoded(state) => get_state_response::State::Value(to_proto(state)),
+            Message::Binary(state) => get_state_response::State::Binary(state.to_vec()),
+        };
+        Ok(Response::new(GetStateResponse { state: Some(state) }))
+    }
+
+    async fn send_all(&self, request: Request<Streaming<SendRequest>>) -> Result<Response<SendAllResponse>, Status> {
+        let mut requests = request.into_inner();
+        let mut sent = 0;
+        while let Some(request) = requests.message().await? {
+            self.runtime
+                .tell(&request.id, sent_message(request.message))
+                .map_err(|error| Status::unavailable(error.to_string()))?;
+            sent += 1;
+        }
+        Ok(Response::new(SendAllResponse { sent }))
+    }
+
+    type AskAllStream = mpsc::Receiver<Result<AskResponse, Status>>;
+
+    /// A failed ask ends the call with its status.
+    async fn ask_all(&self, request: Request<Streaming<AskRequest>>) -> Result<Response<Self::AskAllStream>, Status> {
+        let mut requests = request.into_inner();
+        let runtime = self.runtime.clone();
+        let (mut sender, receiver) = mpsc::channel(STREAM_CAPACITY);
+        tokio::spawn(async move {
+            loop {
+                let result = match requests.message().await {
+                    Ok(Some(request)) => ask(&runtime, request).await,
+                    Ok(None) => return,
+                    Err(error) => Err(error),
+                };
+                let failed = result.is_err();
+                if sender.send(result).await.is_err() || failed {
+                    return;
+                }
+            }
+        });
+        Ok(Response::new(receiver))
+    }
+
+    type WatchStream = mpsc::Receiver<Result<proto::ActorEvent, Status>>;
+
+    async fn watch(&self, request: Request<WatchRequest>) -> Result<Response<Self::WatchStream>, Status> {
+        let mut events = self.runtime.listen(&request.into_inner().id).await.map_err(status)?;
+        let (mut sender, receiver) = mpsc::channel(STREAM_CAPACITY);
+        tokio::spawn(async move {
+            while let Some(event) = events.next().await {
+                let event = match event {
+                    ActorEvent::State(state) => actor_event::Event::State(to_proto(state)),
+                    ActorEvent::Emitted(event) => actor_event::Event::Emitted(to_proto(event)),
+                };
+                if sender.send(Ok(proto::ActorEvent { event: Some(event) })).await.is_err() {
+                    return;
+                }
+            }
+        });
+        Ok(Response::new(receiver))
+    }
+}
+
+fn sent_message(message: Option<send_request::Message>) -> Message {
+    match message {
+        Some(send_request::Message::Binary(bytes)) => Message::Binary(bytes.into()),
+        Some(send_request::Message::Value(value)) => Message::Json(from_proto(Some(value))),
+        None => Message::default(),
+    }
+}
+
+async fn ask(runtime: &Runtime, request: AskRequest) -> Result<AskResponse, Status> {
+    let timeout = match request.timeout_ms {
+        0 => DEFAULT_REPLY_TIMEOUT_MS,
+        timeout => timeout,
+    };
+    let idempotency_key = Some(request.idempotency_key).filter(|key| !key.is_empty());
+    let msg = match request.message {
+        Some(ask_request::Message::Binary(bytes)) => Message::Binary(bytes.into()),
+        Some(ask_request::Message::Value(value)) => Message::Json(from_proto(Some(value))),
+        None => Message::default(),
+    };
+    let reply = runtime
+        .ask(&request.id, msg, idempotency_key, Duration::from_millis(timeout))
+        .await
+        .map_err(status)?;
+
+    let reply = match reply {
+        Reply::Value(value) => ask_response::Reply::Value(to_proto(value)),
+        Reply::Binary(bytes) => ask_response::Reply::Body(bytes.to_vec()),
+        Reply::Stream(mut body) => {
+            let mut bytes = Vec::new();
+            while let Some(chunk) = body.next().await {
+                bytes.extend_from_slice(&chunk);
+            }
+            ask_response::Reply::Body(bytes)
+        }
+        Reply::Response(_) => return Err(Status::internal("the actor replied with an HTTP response")),
+    };
+    Ok(AskResponse { reply: Some(reply) })
+}
+
+fn status(error: SendError) -> Status {
+    match error {
+        SendError::NotFound => Status::not_found(error.to_string()),
+        SendError::Unavailable { .. } => Status::unavailable(error.to_string()),
+        SendError::TimedOut => Status::deadline_exceeded(error.to_string()),
+        SendError::Failed(error) => Status::internal(error.to_string()),
+    }
+}
+
+/// The largest integer a double represents exactly.
+const MAX_SAFE_INTEGER: f64 = 9_007_199_254_740_991.0;
+
+/// Converts a `google.protobuf.Value` to JSON. Whole numbers become
+/// integers again. A missing value is `null`, as are numbers JSON cannot
+/// represent.
+fn from_proto(value: Option<prost_types::Value>) -> Value {
+    let kind = match value.and_then(|value| value.kind) {
+        Some(kind) => kind,
+        None => return Value::Null,
+    };
+    match kind {
+        Kind::NullValue(_) => Value::Null,
+        Kind::NumberValue(number) if number.fract() == 0.0 && number.abs() <= MAX_SAFE_INTEGER => {
+            Value::from(number as i64)
+        }
+        Kind::NumberValue(number) => serde_json::Number::from_f64(number).map_or(Value::Null, Value::Number),
+        Kind::StringValue(string) => Value::String(string),
+        Kind::BoolValue(boolean) => Value::Bool(boolean),
+        Kind::StructValue(object) => Value::Object(
+            object
+                .fields
+                .into_iter()
+                .map(|(key, value)| (key, from_proto(Some(value))))
+                .collect(),
+        ),
+        Kind::ListValue(list) => Value::Array(list.values.into_iter().map(|value| from_proto(Some(value))).collect()),
+    }
+}
+
+/// Converts JSON to a `google.protobuf.Value`, in which all numbers are
+/// doubles.
+fn to_proto(value: Value) -> prost_types::Value {
+    let kind = match value {
+        Value::Null => Kind::NullValue(0),
+        Value::Bool(boolean) => Kind::BoolValue(boolean),
+        Value::Number(number) => Kind::NumberValue(number.as_f64().unwrap_or(0.0)),
+        Value::String(string) => Kind::StringValue(string),
+        Value::Array(values) => Kind::ListValue(prost_types::ListValue {
+            values: values.into_iter().map(to_proto).collect(),
+        }),
+        Value::Object(object) => Kind::StructValue(prost_types::Struct {
+            fields: object.into_iter().map(|(key, value)| (key, to_proto(value))).collect(),
+        }),
+    };
+    prost_types::Value { kind: Some(kind) }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::golem_isolate::GolemIsolate;
+    use crate::runtime::testing;
+    use deno_core::Script;
+    use proto::actors_client::ActorsClient;
+    use std::net::TcpListener;
+    use tonic::transport::Channel;
+
+    const COUNTER: &str = "function init() { return { count: 0 }; }\n\
+                           function main(state, msg) { state.count += msg.add; return state; }";
+
+    async fn start() -> SocketAddr {
+        let runtime = Arc::new(testing::start(1));
+        let snapshot = GolemIsolate::try_create_snapshot(Script { source: COUNTER, filename: "counter.js" }).unwrap();
+        runtime.deploy("counter", snapshot).await.unwrap();
+
+        let addr = TcpListener::bind("127.0.0.1:0").unwrap().local_addr().unwrap();
+        tokio::spawn(serve(addr, runtime));
+        addr
+    }
+
+    /// Connects once the server is listening.
+    async fn connect(addr: SocketAddr) -> ActorsClient<Channel> {
+        for _ in 0..50 {
+            if let Ok(client) = ActorsClient::connect(format!("http://{}", addr)).await {
+                return client;
+            }
+            tokio::time::delay_for(Duration::from_millis(20)).await;
+        }
+        panic!("the gRPC server did not start");
+    }
+
+    #[tokio::test]
+    async fn test_client_round_trip() {
+        let addr = start().await;
+        let mut client = connect(addr).await;
+
+        let create = CreateActorRequest { id: "a".to_string(), actor_type: "counter".to_string() };
+        client.create_actor(create.clone()).await.unwrap();
+        let ask = AskRequest {
+            id: "a".to_string(),
+            message: Some(ask_request::Message::Value(to_proto(json!({ "add": 2 })))),
+            idempotency_key: String::new(),
+            timeout_ms: 0,
+        };
+        let reply = client.ask(ask).await.unwrap().into_inner();
+        assert_eq!(reply.reply, Some(ask_response::Reply::Value(to_proto(json!({ "count": 2 })))));
+
+        let state = client.get_state(GetStateRequest { id: "a".to_string() }).await.unwrap().into_inner();
+        assert_eq!(state.state, Some(get_state_response::State::Value(to_proto(json!({ "count": 2 })))));
+
+        let missing = client.get_state(GetStateRequest { id: "b".to_string() }).await.unwrap_err();
+        assert_eq!(missing.code(), tonic::Code::NotFound);
+
+        let existing = client.create_actor(create).await.unwrap_err();
+        assert_eq!(existing.code(), tonic::Code::AlreadyExists);
+        let state = client.get_state(GetStateRequest { id: "a".to_string() }).await.unwrap().into_inner();
+        assert_eq!(state.state, Some(get_state_response::State::Value(to_proto(json!({ "count": 2 })))));
+    }
+
+    #[test]
+    fn test_values_round_trip() {
+        let value = json!({ "name": "room", "members": [1, 2.5, null], "open": true, "topic": {} });
+        assert_eq!(from_proto(Some(to_proto(value.clone()))), value);
+    }
+
+    #[test]
+    fn test_missing_values_are_null() {
+        assert_eq!(from_proto(None), Value::Null);
+        assert_eq!(from_proto(Some(prost_types::Value { kind: None })), Value::Null);
+        let nan = prost_types::Value { kind: Some(Kind::NumberValue(std::f64::NAN)) };
+        assert_eq!(from_proto(Some(nan)), Value::Null);
+    }
+}
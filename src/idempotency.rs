@@ -0,0 +1,188 @@
+//! Idempotency keys that clients attach to messages, so that retrying a
+//! message returns the original result instead of running `main` again.
+//!
+//! Only successful invocations are remembered. A failed invocation was
+//! rolled back (see `crate::transaction`), so it is safe to run it again.
+//! Keys are forgotten after a TTL, and each actor remembers a bounded
+//! number of them, the oldest being evicted first.
+
+use crate::actor::ActorId;
+use crate::message::Message;
+use crate::replies::ReplyId;
+use std::collections::{HashMap, VecDeque};
+use std::time::{Duration, Instant};
+
+pub const DEFAULT_TTL: Duration = Duration::from_secs(600);
+
+pub const DEFAULT_CAPACITY: usize = 1000;
+
+/// An idempotency key, scoped to the actor it was sent to.
+pub type IdempotencyKey = (ActorId, String);
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum Outcome {
+    /// The message is being processed, or its reply has been deferred.
+    InFlight(ReplyId),
+    Completed(Message),
+}
+
+struct Entry {
+    outcome: Outcome,
+    recorded_at: Instant,
+}
+
+#[derive(Default)]
+struct ActorKeys {
+    entries: HashMap<String, Entry>,
+    /// Keys in the order they were first seen.
+    order: VecDeque<String>,
+}
+
+pub struct ProcessedKeys {
+    actors: HashMap<ActorId, ActorKeys>,
+    ttl: Duration,
+    capacity: usize,
+}
+
+impl Default for ProcessedKeys {
+    fn default() -> Self {
+        Self::new(DEFAULT_TTL, DEFAULT_CAPACITY)
+    }
+}
+
+impl ProcessedKeys {
+    pub fn new(ttl: Duration, capacity: usize) -> Self {
+        Self {
+            actors: HashMap::new(),
+            ttl,
+            capacity,
+        }
+    }
+
+    pub fn get(&self, key: &IdempotencyKey, now: Instant) -> Option<&Outcome> {
+        let (actor_id, key) = key;
+        let entry = self.actors.get(actor_id)?.entries.get(key)?;
+        if now.duration_since(entry.recorded_at) >= self.ttl {
+            return None;
+        }
+        Some(&entry.outcome)
+    }
+
+    pub fn start(&mut self, key: IdempotencyKey, reply_id: ReplyId, now: Instant) {
+        let (actor_id, key) = key;
+        let actor = self.actors.entry(actor_id).or_default();
+        if actor.entries.contains_key(&key) {
+            actor.order.retain(|existing| *existing != key);
+        }
+        actor.order.push_back(key.clone());
+        actor.entries.insert(key, Entry {
+            outcome: Outcome::InFlight(reply_id),
+            recorded_at: now,
+        });
+        while actor.order.len() > self.capacity {
+            if let Some(oldest) = actor.order.pop_front() {
+                actor.entries.remove(&oldest);
+            }
+        }
+    }
+
+    pub fn complete(&mut self, key: &IdempotencyKey, response: Message) {
+        let (actor_id, key) = key;
+        let entry = self
+            .actors
+            .get_mut(actor_id)
+            .and_then(|actor| actor.entries.get_mut(key));
+        if let Some(entry) = entry {
+            entry.outcome = Outcome::Completed(response);
+        }
+    }
+
+    /// Forgets a key whose message failed, so that a retry runs it again.
+    pub fn forget(&mut self, key: &IdempotencyKey) {
+        let (actor_id, key) = key;
+        if let Some(actor) = self.actors.get_mut(actor_id) {
+            actor.entries.remove(key);
+            actor.order.retain(|existing| existing != key);
+        }
+    }
+
+    pub fn remove_actor(&mut self, actor_id: &str) {
+        self.actors.remove(actor_id);
+    }
+
+    pub fn expire(&mut self, now: Instant) {
+        let ttl = self.ttl;
+        for actor in self.actors.values_mut() {
+            while let Some(oldest) = actor.order.front() {
+                let expired = actor
+                    .entries
+                    .get(oldest)
+                    .map_or(true, |entry| now.duration_since(entry.recorded_at) >= ttl);
+                if !expired {
+                    break;
+                }
+                let oldest = actor.order.pop_front().unwrap();
+                actor.entries.remove(&oldest);
+            }
+        }
+        self.actors.retain(|_, actor| !actor.order.is_empty());
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn key(actor_id: &str, key: &str) -> IdempotencyKey {
+        (actor_id.to_string(), key.to_string())
+    }
+
+    #[test]
+    fn test_keys_are_scoped_to_actors() {
+        let mut keys = ProcessedKeys::default();
+        let now = Instant::now();
+        keys.start(key("a", "k"), 1, now);
+        keys.complete(&key("a", "k"), Message::Json(json!(1)));
+
+        assert_eq!(keys.get(&key("a", "k"), now), Some(&Outcome::Completed(Message::Json(json!(1)))));
+        assert_eq!(keys.get(&key("b", "k"), now), None);
+    }
+
+    #[test]
+    fn test_forget() {
+        let mut keys = ProcessedKeys::default();
+        let now = Instant::now();
+        keys.start(key("a", "k"), 1, now);
+        assert_eq!(keys.get(&key("a", "k"), now), Some(&Outcome::InFlight(1)));
+
+        keys.forget(&key("a", "k"));
+        assert_eq!(keys.get(&key("a", "k"), now), None);
+    }
+
+    #[test]
+    fn test_capacity_evicts_oldest() {
+        let mut keys = ProcessedKeys::new(DEFAULT_TTL, 2);
+        let now = Instant::now();
+        keys.start(key("a", "1"), 1, now);
+        keys.start(key("a", "2"), 2, now);
+        keys.start(key("a", "3"), 3, now);
+        keys.start(key("b", "1"), 4, now);
+
+        assert_eq!(keys.get(&key("a", "1"), now), None);
+        assert!(keys.get(&key("a", "3"), now).is_some());
+        assert!(keys.get(&key("b", "1"), now).is_some());
+    }
+
+    #[test]
+    fn test_expire() {
+        let mut keys = ProcessedKeys::new(Duration::from_secs(1), DEFAULT_CAPACITY);
+        let now = Instant::now();
+        keys.start(key("a", "1"), 1, now);
+        keys.start(key("a", "2"), 2, now + Duration::from_millis(500));
+
+        let later = now + Duration::from_millis(1200);
+        assert_eq!(keys.get(&key("a", "1"), later), None);
+        keys.expire(later);
+        assert!(keys.get(&key("a", "2"), later).is_some());
+    }
+}
@@ -0,0 +1,787 @@
+// The runtime prelude. It is evaluated before the actor's own code when a
+// snapshot is created, so everything defined here is restored with it.
+// Ops are looked up by name lazily, as they are registered on the isolate
+// after the snapshot has been restored.
+((window) => {
+  const core = Deno.core;
+
+  let opIds = {};
+  let nextPromiseId = 1;
+  const promiseTable = new Map();
+
+  // Ops dispatched as minimal ops, whose async responses are records rather
+  // than JSON.
+  const minimalOps = new Set(["op_read", "op_write"]);
+
+  function opId(name) {
+    if (!(name in opIds)) {
+      opIds = core.ops();
+      for (const [opName, id] of Object.entries(opIds)) {
+        core.setAsyncHandler(id, minimalOps.has(opName) ? handleAsyncMinimal : handleAsync);
+      }
+    }
+    const id = opIds[name];
+    if (id === undefined) {
+      throw new Error(`op ${name} is not available to this actor, check the permissions in its manifest`);
+    }
+    return id;
+  }
+
+  function unwrapResponse(response) {
+    if (response.err) {
+      const error = new Error(response.err.message);
+      error.kind = response.err.kind;
+      throw error;
+    }
+    return response.ok;
+  }
+
+  function handleAsync(buffer) {
+    const response = JSON.parse(core.decode(buffer));
+    const promise = promiseTable.get(response.promiseId);
+    promiseTable.delete(response.promiseId);
+    try {
+      promise.resolve(unwrapResponse(response));
+    } catch (error) {
+      promise.reject(error);
+    }
+  }
+
+  function sendSync(name, args = {}, zeroCopy) {
+    const control = core.encode(JSON.stringify(args));
+    const response = core.dispatch(opId(name), control, zeroCopy);
+    return unwrapResponse(JSON.parse(core.decode(response)));
+  }
+
+  function sendAsync(name, args = {}, zeroCopy) {
+    const promiseId = nextPromiseId++;
+    const control = core.encode(JSON.stringify({ ...args, promiseId }));
+    const promise = new Promise((resolve, reject) => {
+      promiseTable.set(promiseId, { resolve, reject });
+    });
+    const response = core.dispatch(opId(name), control, zeroCopy);
+    if (response) {
+      // The op failed synchronously.
+      handleAsync(response);
+    }
+    return promise;
+  }
+
+  // Parses the response of a minimal op, see dispatch_minimal.rs. It is
+  // [promiseId, arg, result], where a negative arg signals an error whose
+  // message follows the record.
+  function parseMinimal(response) {
+    const record = new Int32Array(response.buffer, response.byteOffset, 3);
+    if (record[1] < 0) {
+      const error = new Error(core.decode(response.subarray(12)).trimEnd());
+      error.kind = record[2];
+      return { promiseId: record[0], error };
+    }
+    return { promiseId: record[0], result: record[2] };
+  }
+
+  function handleAsyncMinimal(buffer) {
+    const { promiseId, error, result } = parseMinimal(buffer);
+    const promise = promiseTable.get(promiseId);
+    promiseTable.delete(promiseId);
+    if (error) {
+      promise.reject(error);
+    } else {
+      promise.resolve(result);
+    }
+  }
+
+  function sendSyncMinimal(name, rid, zeroCopy) {
+    const control = new Int32Array([0, rid, 0]);
+    const response = core.dispatch(opId(name), new Uint8Array(control.buffer), zeroCopy);
+    const { error, result } = parseMinimal(response);
+    if (error) {
+      throw error;
+    }
+    return result;
+  }
+
+  function sendAsyncMinimal(name, rid, zeroCopy) {
+    const promiseId = nextPromiseId++;
+    const control = new Int32Array([promiseId, rid, 0]);
+    const promise = new Promise((resolve, reject) => {
+      promiseTable.set(promiseId, { resolve, reject });
+    });
+    const response = core.dispatch(opId(name), new Uint8Array(control.buffer), zeroCopy);
+    if (response) {
+      // The op failed synchronously.
+      handleAsyncMinimal(response);
+    }
+    return promise;
+  }
+
+  function encodeBody(data) {
+    return typeof data === "string" ? core.encode(data) : data;
+  }
+
+  // A stream the actor writes a body to chunk by chunk. It can be passed as
+  // the body of fetch or to ctx.reply, and is closed once the whole body
+  // has been written.
+  class BodyStream {
+    constructor() {
+      this.rid = sendSync("op_stream_create");
+    }
+
+    // Resolves once the reader has room for the chunk.
+    write(data) {
+      return sendAsyncMinimal("op_write", this.rid, encodeBody(data));
+    }
+
+    close() {
+      sendSync("op_close", { rid: this.rid });
+    }
+  }
+
+  // A body the actor reads chunk by chunk, e.g. that of a fetch response.
+  // It is closed once read to the end with for await.
+  class BodyReader {
+    constructor(rid) {
+      this.rid = rid;
+      this.disturbed = false;
+      this.closed = false;
+    }
+
+    // Resolves to the next chunk, or null at the end of the body.
+    async read(size = 64 * 1024) {
+      this.disturbed = true;
+      const buffer = new Uint8Array(size);
+      const nread = await sendAsyncMinimal("op_read", this.rid, buffer);
+      return nread === 0 ? null : buffer.subarray(0, nread);
+    }
+
+    async *[Symbol.asyncIterator]() {
+      try {
+        for (let chunk = await this.read(); chunk !== null; chunk = await this.read()) {
+          yield chunk;
+        }
+      } finally {
+        this.close();
+      }
+    }
+
+    // Writes the rest of the body to a BodyStream and closes it.
+    async pipeTo(stream) {
+      for await (const chunk of this) {
+        await stream.write(chunk);
+      }
+      stream.close();
+    }
+
+    close() {
+      if (!this.closed) {
+        this.closed = true;
+        sendSync("op_close", { rid: this.rid });
+      }
+    }
+  }
+
+  // A minimal version of the WHATWG Headers. Names are case-insensitive
+  // and repeated headers are joined with ", ".
+  class Headers {
+    constructor(init = {}) {
+      this.map = new Map();
+      const entries = init instanceof Headers || Array.isArray(init) ? init : Object.entries(init);
+      for (const [name, value] of entries) {
+        this.append(name, value);
+      }
+    }
+
+    get(name) {
+      const value = this.map.get(name.toLowerCase());
+      return value === undefined ? null : value;
+    }
+
+    has(name) {
+      return this.map.has(name.toLowerCase());
+    }
+
+    set(name, value) {
+      this.map.set(name.toLowerCase(), String(value));
+    }
+
+    append(name, value) {
+      const current = this.get(name);
+      this.set(name, current === null ? value : `${current}, ${value}`);
+    }
+
+    delete(name) {
+      this.map.delete(name.toLowerCase());
+    }
+
+    forEach(callback) {
+      this.map.forEach((value, name) => callback(value, name, this));
+    }
+
+    entries() {
+      return this.map.entries();
+    }
+
+    [Symbol.iterator]() {
+      return this.map.entries();
+    }
+  }
+
+  // What Request and Response have in common. Unlike the WHATWG classes,
+  // body is a BodyReader when the body is received, or what was passed to
+  // the constructor otherwise.
+  class Body {
+    constructor(body) {
+      this.body = body === undefined ? null : body;
+      this.bodyUsed = false;
+    }
+
+    async bytes() {
+      if (this.bodyUsed) {
+        throw new TypeError("the body has already been read");
+      }
+      this.bodyUsed = true;
+      const body = this.body;
+      if (body === null) {
+        return new Uint8Array(0);
+      }
+      if (body instanceof BodyReader) {
+        const chunks = [];
+        for await (const chunk of body) {
+          chunks.push(chunk);
+        }
+        const bytes = new Uint8Array(chunks.reduce((length, chunk) => length + chunk.length, 0));
+        let offset = 0;
+        for (const chunk of chunks) {
+          bytes.set(chunk, offset);
+          offset += chunk.length;
+        }
+        return bytes;
+      }
+      if (body instanceof BodyStream) {
+        throw new TypeError("a BodyStream can be sent but not read");
+      }
+      return body instanceof ArrayBuffer ? new Uint8Array(body) : encodeBody(body);
+    }
+
+    async arrayBuffer() {
+      const bytes = await this.bytes();
+      return bytes.buffer.slice(bytes.byteOffset, bytes.byteOffset + bytes.byteLength);
+    }
+
+    async text() {
+      return core.decode(await this.bytes());
+    }
+
+    async json() {
+      return JSON.parse(await this.text());
+    }
+  }
+
+  class Request extends Body {
+    constructor(url, { method = "GET", headers = {}, body = null } = {}) {
+      super(body);
+      this.url = url;
+      this.method = method.toUpperCase();
+      this.headers = new Headers(headers);
+    }
+  }
+
+  class Response extends Body {
+    constructor(body = null, { status = 200, statusText = "", headers = {} } = {}) {
+      super(body);
+      this.status = status;
+      this.statusText = statusText;
+      this.headers = new Headers(headers);
+      if (typeof body === "string" && !this.headers.has("content-type")) {
+        this.headers.set("content-type", "text/plain;charset=UTF-8");
+      }
+    }
+
+    get ok() {
+      return this.status >= 200 && this.status < 300;
+    }
+
+    static json(data, init = {}) {
+      const response = new Response(JSON.stringify(data), init);
+      response.headers.set("content-type", "application/json");
+      return response;
+    }
+  }
+
+  // Needs the "fetch" permission. A BodyStream body is sent as it is
+  // written, which lets actors proxy bodies too large to be buffered.
+  async function fetch(url, { method = "GET", headers = {}, body } = {}) {
+    const args = { url, method, headers: [...new Headers(headers)] };
+    let zeroCopy;
+    if (body instanceof BodyStream) {
+      args.bodyRid = body.rid;
+    } else if (body !== undefined && body !== null) {
+      zeroCopy = body instanceof ArrayBuffer ? new Uint8Array(body) : encodeBody(body);
+    }
+    const response = await sendAsync("op_fetch", args, zeroCopy);
+    return new Response(new BodyReader(response.bodyRid), {
+      status: response.status,
+      statusText: response.statusText,
+      headers: response.headers,
+    });
+  }
+
+  class FsFile {
+    constructor(rid) {
+      this.rid = rid;
+    }
+
+    // Reads into the Uint8Array `buffer`, returning the number of bytes
+    // read, or null at the end of the file.
+    read(buffer) {
+      const nread = sendSyncMinimal("op_read", this.rid, buffer);
+      return nread === 0 && buffer.length > 0 ? null : nread;
+    }
+
+    write(data) {
+      return sendSyncMinimal("op_write", this.rid, encodeBody(data));
+    }
+
+    close() {
+      sendSync("op_close", { rid: this.rid });
+    }
+  }
+
+  // The actor's sandboxed filesystem, only usable with the "fs" permission.
+  // Paths are relative to its root. Writes, and every file and directory
+  // created, count against its quota.
+  // Files are not part of the invocation's transaction.
+  const fs = {
+    open: (path, options) => new FsFile(sendSync("op_vfs_open", { path, options })),
+    stat: (path) => sendSync("op_vfs_stat", { path }),
+    readDir: (path = "/") => sendSync("op_vfs_read_dir", { path }),
+    mkdir: (path, { recursive = false } = {}) => {
+      sendSync("op_vfs_mkdir", { path, recursive });
+    },
+    remove: (path, { recursive = false } = {}) => {
+      sendSync("op_vfs_remove", { path, recursive });
+    },
+  };
+
+  // Values cross into the runtime as JSON, see GolemIsolate. So that state
+  // and storage keep what a structured clone would, values JSON cannot
+  // represent are encoded as objects tagged with "$type": undefined, NaN,
+  // Infinity and -0, BigInts, Dates, RegExps, Maps, Sets, ArrayBuffers and
+  // their views, and objects seen before, which makes shared and cyclic
+  // references survive. Plain JSON encodes as itself, objects that have a
+  // "$type" key of their own are escaped. Functions and symbols are dropped
+  // as JSON.stringify drops them.
+  const VIEW_TYPES = [
+    "Int8Array", "Uint8Array", "Uint8ClampedArray", "Int16Array", "Uint16Array", "Int32Array",
+    "Uint32Array", "Float32Array", "Float64Array", "BigInt64Array", "BigUint64Array", "DataView",
+  ];
+
+  function encode(value) {
+    // Objects are numbered in the order they are first seen.
+    const ids = new Map();
+    const item = (value) => {
+      const encoded = walk(value);
+      return encoded === undefined ? null : encoded;
+    };
+    const walk = (value) => {
+      switch (typeof value) {
+        case "undefined":
+          return { $type: "undefined" };
+        case "number":
+          if (Number.isFinite(value) && !Object.is(value, -0)) {
+            return value;
+          }
+          return { $type: "Number", value: Object.is(value, -0) ? "-0" : String(value) };
+        case "bigint":
+          return { $type: "BigInt", value: value.toString() };
+        case "function":
+        case "symbol":
+          return undefined;
+        case "string":
+        case "boolean":
+          return value;
+      }
+      if (value === null) {
+        return null;
+      }
+      if (ids.has(value)) {
+        return { $type: "Ref", id: ids.get(value) };
+      }
+      ids.set(value, ids.size);
+
+      const type = Object.prototype.toString.call(value).slice(8, -1);
+      if (Array.isArray(value)) {
+        return Array.from(value, item);
+      }
+      if (type === "Date") {
+        const time = value.getTime();
+        return { $type: "Date", value: Number.isNaN(time) ? null : time };
+      }
+      if (type === "RegExp") {
+        return { $type: "RegExp", source: value.source, flags: value.flags };
+      }
+      if (type === "Map") {
+        return { $type: "Map", entries: Array.from(value, ([key, entry]) => [item(key), item(entry)]) };
+      }
+      if (type === "Set") {
+        return { $type: "Set", values: Array.from(value, item) };
+      }
+      if (type === "ArrayBuffer") {
+        return { $type: "ArrayBuffer", bytes: Array.from(new Uint8Array(value)) };
+      }
+      if (VIEW_TYPES.includes(type)) {
+        const length = type === "DataView" ? value.byteLength : value.length;
+        return { $type: type, buffer: walk(value.buffer), byteOffset: value.byteOffset, length };
+      }
+
+      const object = {};
+      for (const key of Object.keys(value)) {
+        const encoded = walk(value[key]);
+        if (encoded !== undefined) {
+          object[key] = encoded;
+        }
+      }
+      return Object.prototype.hasOwnProperty.call(value, "$type") ? { $type: "Object", value: object } : object;
+    };
+    return walk(value);
+  }
+
+  function decode(value) {
+    // Objects are numbered in the order encode saw them.
+    const objects = [];
+    const register = (object) => {
+      objects.push(object);
+      return object;
+    };
+    const walkObject = (value) => {
+      const object = register({});
+      for (const key of Object.keys(value)) {
+        object[key] = walk(value[key]);
+      }
+      return object;
+    };
+    const walk = (value) => {
+      if (value === null || typeof value !== "object") {
+        return value;
+      }
+      if (Array.isArray(value)) {
+        const array = register([]);
+        for (const item of value) {
+          array.push(walk(item));
+        }
+        return array;
+      }
+      switch (value.$type) {
+        case "undefined":
+          return undefined;
+        case "Number":
+          return Number(value.value);
+        case "BigInt":
+          return BigInt(value.value);
+        case "Ref":
+          return objects[value.id];
+        case "Date":
+          return register(new Date(value.value === null ? NaN : value.value));
+        case "RegExp":
+          return register(new RegExp(value.source, value.flags));
+        case "Map": {
+          const map = register(new Map());
+          for (const [key, entry] of value.entries) {
+            map.set(walk(key), walk(entry));
+          }
+          return map;
+        }
+        case "Set": {
+          const set = register(new Set());
+          for (const item of value.values) {
+            set.add(walk(item));
+          }
+          return set;
+        }
+        case "ArrayBuffer":
+          return register(new Uint8Array(value.bytes).buffer);
+        case "Object":
+          return walkObject(value.value);
+      }
+      if (VIEW_TYPES.includes(value.$type)) {
+        // The view is numbered before its buffer.
+        const id = objects.push(null) - 1;
+        const buffer = walk(value.buffer);
+        objects[id] = new window[value.$type](buffer, value.byteOffset, value.length);
+        return objects[id];
+      }
+      return walkObject(value);
+    };
+    return walk(value);
+  }
+
+  // A copy of the state for a transaction to work on, keeping what a
+  // structured clone keeps, like encode and decode do.
+  function clone(value) {
+    return decode(encode(value));
+  }
+
+  // What clients are sent, as JSON without "$type" tags: replies, events,
+  // published messages and the state they read. Maps become arrays of
+  // entries, Sets arrays, buffers and their views arrays of numbers,
+  // BigInts strings and Dates ISO strings, or null if invalid. toJSON is
+  // honoured and references back to an enclosing object become null,
+  // anything else is left to JSON.stringify.
+  function plain(value) {
+    const ancestors = new Set();
+    const walk = (value) => {
+      if (typeof value === "bigint") {
+        return value.toString();
+      }
+      if (typeof value !== "object" || value === null) {
+        return value;
+      }
+      if (ancestors.has(value)) {
+        return null;
+      }
+      if (typeof value.toJSON === "function" && !(value instanceof Date)) {
+        return walk(value.toJSON());
+      }
+      ancestors.add(value);
+      const result = walkObject(value);
+      ancestors.delete(value);
+      return result;
+    };
+    const walkObject = (value) => {
+      if (value instanceof Date) {
+        return Number.isNaN(value.getTime()) ? null : value.toISOString();
+      }
+      if (value instanceof Map) {
+        return Array.from(value, ([key, entry]) => [walk(key), walk(entry)]);
+      }
+      if (value instanceof Set || Array.isArray(value)) {
+        return Array.from(value, walk);
+      }
+      if (value instanceof ArrayBuffer) {
+        return Array.from(new Uint8Array(value));
+      }
+      if (ArrayBuffer.isView(value)) {
+        return Array.from(new Uint8Array(value.buffer, value.byteOffset, value.byteLength));
+      }
+      const object = {};
+      for (const key of Object.keys(value)) {
+        object[key] = walk(value[key]);
+      }
+      return object;
+    };
+    return walk(value);
+  }
+
+  // The bytes of an ArrayBuffer or a view of one, which are passed to ops
+  // without being encoded, or null for any other value. Empty buffers are
+  // not passed at all.
+  function asBytes(value) {
+    if (value instanceof ArrayBuffer) {
+      return new Uint8Array(value);
+    }
+    if (ArrayBuffer.isView(value)) {
+      return new Uint8Array(value.buffer, value.byteOffset, value.byteLength);
+    }
+    return null;
+  }
+
+  function zeroCopy(bytes) {
+    return bytes.length > 0 ? bytes : undefined;
+  }
+
+  const sockets = {
+    list: () => sendSync("op_socket_list"),
+    send: (socketId, data) => {
+      const bytes = asBytes(data);
+      if (bytes !== null) {
+        sendSync("op_socket_send", { socketId, binary: true }, zeroCopy(bytes));
+        return;
+      }
+      data = typeof data === "string" ? data : JSON.stringify(data);
+      sendSync("op_socket_send", { socketId, data });
+    },
+    close: (socketId, code, reason) => {
+      sendSync("op_socket_close", { socketId, code, reason });
+    },
+  };
+
+  // Replies with a value, bytes, or the body written to a BodyStream.
+  function reply(replyId, value) {
+    const bytes = asBytes(value);
+    if (value instanceof BodyStream) {
+      sendSync("op_reply_stream", { replyId, rid: value.rid });
+    } else if (bytes !== null) {
+      sendSync("op_reply", { replyId, binary: true }, zeroCopy(bytes));
+    } else {
+      sendSync("op_reply", { replyId, value: plain(value) });
+    }
+  }
+
+  // Answers an HTTP request with a Response. Bodies that are not streams
+  // already are written to one.
+  function respond(replyId, response) {
+    if (!(response instanceof Response)) {
+      throw new TypeError("fetch must return a Response");
+    }
+    const args = { replyId, status: response.status, headers: [...response.headers] };
+    const body = response.body;
+    if (body === null) {
+      sendSync("op_reply_response", args);
+      return;
+    }
+
+    const stream = body instanceof BodyStream ? body : new BodyStream();
+    sendSync("op_reply_response", { ...args, bodyRid: stream.rid });
+    if (body instanceof BodyReader) {
+      body.pipeTo(stream);
+    } else if (body !== stream) {
+      const bytes = body instanceof ArrayBuffer ? new Uint8Array(body) : encodeBody(body);
+      stream.write(bytes).then(() => stream.close());
+    }
+  }
+
+  // Runs the actor's fetch export for an HTTP request, see
+  // GolemIsolate::invoke_fetch. The Response it returns, or resolves to,
+  // answers the caller. A request body the actor did not start reading is
+  // closed once it responded.
+  function serve(state, raw, ctx) {
+    const body = new BodyReader(raw.bodyRid);
+    const request = new Request(raw.url, { method: raw.method, headers: raw.headers, body });
+    const finish = (response) => {
+      if (!body.disturbed) {
+        body.close();
+      }
+      respond(ctx.replyId, response);
+    };
+
+    let result;
+    try {
+      result = globalThis.fetch(state, request, ctx);
+    } catch (error) {
+      if (!body.disturbed) {
+        body.close();
+      }
+      throw error;
+    }
+    if (result && typeof result.then === "function") {
+      ctx.defer();
+      result.then(finish, (error) => {
+        finish(new Response(String((error && error.stack) || error), { status: 500 }));
+      });
+    } else {
+      finish(result);
+    }
+  }
+
+  function requireReplyId(raw) {
+    if (raw.replyId === undefined) {
+      throw new Error("this message does not expect a reply");
+    }
+    return raw.replyId;
+  }
+
+  // Turns the plain context passed by the runtime into the `ctx` argument
+  // of the actor's handlers.
+  function makeContext(raw) {
+    const ctx = Object.assign({}, raw);
+
+    // Answers the current message, while main runs or, once deferred, from
+    // an async callback. Without a call to reply or defer, the caller
+    // receives whatever main returns. An ArrayBuffer or a view of one is
+    // sent as bytes. Replying with a BodyStream streams its body to the
+    // caller while it is written.
+    ctx.reply = (value) => {
+      reply(requireReplyId(raw), value);
+    };
+
+    // Keeps the caller waiting after main returns. The returned handle can
+    // be kept, e.g. in the state or in a message to another actor, and
+    // answered later with ctx.replyTo.
+    ctx.defer = () => {
+      const replyId = requireReplyId(raw);
+      sendSync("op_defer_reply", { replyId });
+      return replyId;
+    };
+
+    ctx.replyTo = (handle, value) => {
+      reply(handle, value);
+    };
+
+    // Subscriptions outlive the invocation and the isolate: messages
+    // published to the topic are passed to main with ctx.topic set, until
+    // the actor unsubscribes or is stopped. A message is delivered again if
+    // main throws, ctx.deliveryId tells such redeliveries apart.
+    ctx.subscribe = (topic) => sendSync("op_subscribe", { topic });
+
+    ctx.unsubscribe = (topic) => sendSync("op_unsubscribe", { topic });
+
+    // Messages published while main runs are sent once it returns, and not
+    // at all if it throws. ArrayBuffers and their views are published as
+    // bytes, anything else is encoded like the state, so that subscribers
+    // are passed Maps, BigInts or cycles as they were published. Returns
+    // the number of subscribers of the topic.
+    ctx.publish = (topic, message) => {
+      const bytes = asBytes(message);
+      if (bytes !== null) {
+        return sendSync("op_publish", { topic, binary: true }, zeroCopy(bytes));
+      }
+      return sendSync("op_publish", { topic, message: encode(message), encoded: true });
+    };
+
+    // Sends an event to the clients of GET /actor/{id}/events. Like
+    // published messages, events are only sent if main returns.
+    ctx.emit = (event) => {
+      sendSync("op_emit", { event: plain(event) });
+    };
+
+    // Storage writes made while main runs are only committed if it
+    // returns without throwing.
+    ctx.storage = {
+      get: (key) => decode(sendSync("op_storage_get", { key })),
+      put: (key, value) => {
+        sendSync("op_storage_put", { key, value: encode(value) });
+      },
+      delete: (key) => sendSync("op_storage_delete", { key }),
+      list: (prefix = "") => {
+        const entries = sendSync("op_storage_list", { prefix });
+        return new Map(entries.map(([key, value]) => [key, decode(value)]));
+      },
+    };
+
+    ctx.fs = fs;
+
+    // WebSockets connected to the actor, see crate::sockets. Frames sent
+    // while main runs are sent once it returns, and not at all if it
+    // throws. ArrayBuffers and their views are sent as binary messages,
+    // values other than strings as JSON.
+    ctx.sockets = sockets;
+
+    // Set when main is passed an event of a WebSocket: its opening, with
+    // the upgrade request as the message, a message the client sent, a
+    // string or a Uint8Array, or its closing. Throwing on open refuses the
+    // connection.
+    if (raw.socket !== undefined) {
+      const id = raw.socket.id;
+      ctx.socket = {
+        id,
+        event: raw.socket.event,
+        send: (data) => sockets.send(id, data),
+        close: (code, reason) => sockets.close(id, code, reason),
+      };
+    }
+
+    return ctx;
+  }
+
+  window.Golem = { sendSync, sendAsync, fetch, BodyStream };
+  window.Headers = Headers;
+  window.Request = Request;
+  window.Response = Response;
+  window.__golemServe = serve;
+  window.__golemContext = makeContext;
+  // Looked up once by GolemIsolate when it is created, and locked so that
+  // actor code cannot replace how the runtime converts its values.
+  Object.defineProperty(window, "__golemCodec", {
+    value: Object.freeze({ encode, decode, clone, plain }),
+  });
+})(globalThis);
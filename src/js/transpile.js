@@ -0,0 +1,27 @@
+// Runs inside the compiler isolate after typescript.js has been evaluated.
+// The result is handed back to Rust through the op_transpile_result op.
+function golemTranspile(fileName, source) {
+  const output = ts.transpileModule(source, {
+    fileName,
+    reportDiagnostics: true,
+    compilerOptions: {
+      module: ts.ModuleKind.ESNext,
+      target: ts.ScriptTarget.ES2019,
+      sourceMap: true,
+      inlineSources: true,
+    },
+  });
+
+  const diagnostics = (output.diagnostics || []).map((diagnostic) =>
+    ts.flattenDiagnosticMessageText(diagnostic.messageText, "\n")
+  );
+
+  const result = JSON.stringify({
+    code: output.outputText,
+    sourceMap: output.sourceMapText || null,
+    diagnostics,
+  });
+
+  const opId = Deno.core.ops()["op_transpile_result"];
+  Deno.core.dispatch(opId, Deno.core.encode(result));
+}
@@ -21,15 +21,53 @@ use futures::{SinkExt, TryFutureExt};
 use crate::golem_isolate::GolemIsolate;
 use deno_core::Script;
 use std::time::Instant;
+use futures::future::join_all;
+use crate::dead_letter::DeadLetterStore;
+use crate::message::Message;
+use crate::runtime::Runtime;
+use crate::supervisor::SupervisionPolicy;
+use crate::topics::TopicStore;
+use crate::controllers::AppState;
+use crate::routes::RouteTable;
+use actix_web::{App, HttpServer};
+use deno_core::ErrBox;
+use futures::future;
+use std::net::SocketAddr;
+use std::path::PathBuf;
 
+mod actor;
+mod cache;
+mod dead_letter;
 mod dispatch_json;
 mod dispatch_minimal;
+mod events;
 mod op_error;
 mod golem_isolate;
+mod manifest;
+mod message;
+mod module_loader;
+mod source_maps;
+mod typescript;
+mod vfs;
 mod global_timer;
+mod idempotency;
+mod lifecycle;
+mod registry;
+mod replies;
+mod resources;
+mod routes;
+mod runtime;
+mod sockets;
 mod state;
+mod storage;
+mod streams;
+mod supervisor;
+mod topics;
+mod transaction;
 mod ops;
 
+const WORKER_COUNT: usize = 4;
+
 const SOURCE_CODE: &str = "
     function main(state, msg, ctx) {
         // console.log('hello world');
@@ -38,6 +76,55 @@ const SOURCE_CODE: &str = "
     }
 ";
 
+/// Where the server listens and what it routes, see `serve`.
+pub struct ServerConfig {
+    pub http_addr: String,
+    /// Where the gRPC interface is served, see `crate::grpc`. Not served if
+    /// absent.
+    pub grpc_addr: Option<SocketAddr>,
+    /// A JSON file with the routing table, see `crate::routes`.
+    pub routes: Option<PathBuf>,
+    pub worker_count: usize,
+}
+
+impl Default for ServerConfig {
+    fn default() -> Self {
+        Self {
+            http_addr: "127.0.0.1:8080".to_string(),
+            grpc_addr: Some(SocketAddr::from(([127, 0, 0, 1], 50051))),
+            routes: None,
+            worker_count: WORKER_COUNT,
+        }
+    }
+}
+
+/// Starts the runtime and serves the HTTP API, and the gRPC interface next
+/// to it, until one of the servers stops.
+pub async fn serve(config: ServerConfig) -> Result<(), ErrBox> {
+    let routes = match config.routes.as_ref() {
+        Some(path) => RouteTable::load(path)?,
+        None => RouteTable::default(),
+    };
+    let state = AppState::start(config.worker_count, routes)?;
+    let runtime = state.runtime();
+
+    info!("serving HTTP on {}", config.http_addr);
+    let http = HttpServer::new(move || state.mount(App::new()))
+        .bind(&config.http_addr)?
+        .run()
+        .map_err(ErrBox::from);
+
+    match config.grpc_addr {
+        Some(grpc_addr) => {
+            info!("serving gRPC on {}", grpc_addr);
+            let grpc = grpc::serve(grpc_addr, runtime).map_err(ErrBox::from);
+            future::try_join(http, grpc).await?;
+        }
+        None => http.await?,
+    }
+    Ok(())
+}
+
 pub async fn run_v8() -> Result<(), golem_isolate::IsolateCreationError> {
     let script = Script {
         source: SOURCE_CODE,
@@ -46,14 +133,19 @@ pub async fn run_v8() -> Result<(), golem_isolate::IsolateCreationError> {
 
     let snapshot = GolemIsolate::try_create_snapshot(script)?;
 
+    let dead_letters = DeadLetterStore::shared(dead_letter::DEFAULT_CAPACITY);
+    let topics = TopicStore::shared(topics::DEFAULT_BACKLOG, dead_letters.clone());
+    let runtime = Runtime::start(WORKER_COUNT, dead_letters, topics).unwrap();
+    runtime.deploy("test", snapshot).await.unwrap();
+
+    let ids: Vec<String> = (0..1000).map(|i| format!("test-{}", i)).collect();
+
     let global_start_time = Instant::now();
-    for _ in 0..1000 {
-        let cloned = snapshot.clone();
-        let mut isolate = GolemIsolate::new(cloned);
-        isolate.invoke_main();
-        isolate.get_future().await;
-    }
+    join_all(ids.iter().map(|id| runtime.spawn(id, "test", SupervisionPolicy::default(), None))).await;
+    join_all(ids.iter().map(|id| runtime.send(id, Message::Json(json!(1))))).await;
     let global_end_time = Instant::now();
+    runtime.shutdown();
+
     let delta_time = global_end_time - global_start_time;
     println!("Total Run Time: {}ms", delta_time.as_millis());
     println!("Average Run Time: {} microsecs", delta_time.as_micros() / 1000);
@@ -62,3 +154,4 @@ pub async fn run_v8() -> Result<(), golem_isolate::IsolateCreationError> {
 }
 
 pub mod controllers;
+pub mod grpc;
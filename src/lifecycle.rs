@@ -0,0 +1,218 @@
+//! Optional lifecycle exports that the runtime calls around `main`:
+//!
+//! - `init(ctx)` returns the initial state of a newly spawned actor.
+//! - `onActivate(state, ctx)` runs after an actor has been rehydrated.
+//! - `onPassivate(state, ctx)` runs before an actor's state is persisted and
+//!   its isolate released, e.g. to flush buffered data into the state.
+//! - `onStop(state, ctx)` runs once before an actor is deleted.
+//!
+//! Every hook, like `main`, runs under a time limit, after which its
+//! execution is terminated and the call reported as `TimedOut`.
+
+use std::collections::BTreeMap;
+use std::error::Error;
+use std::fmt;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, Condvar, Mutex};
+use std::thread;
+use std::time::{Duration, Instant};
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum LifecycleHook {
+    Init,
+    Activate,
+    Passivate,
+    Stop,
+}
+
+impl LifecycleHook {
+    pub fn export_name(self) -> &'static str {
+        match self {
+            LifecycleHook::Init => "init",
+            LifecycleHook::Activate => "onActivate",
+            LifecycleHook::Passivate => "onPassivate",
+            LifecycleHook::Stop => "onStop",
+        }
+    }
+}
+
+/// Time limits of `main` and of the lifecycle hooks.
+#[derive(Debug, Clone, Copy)]
+pub struct LifecycleLimits {
+    pub main: Duration,
+    pub init: Duration,
+    pub activate: Duration,
+    pub passivate: Duration,
+    pub stop: Duration,
+}
+
+impl Default for LifecycleLimits {
+    fn default() -> Self {
+        Self {
+            main: Duration::from_secs(5),
+            init: Duration::from_secs(1),
+            activate: Duration::from_millis(500),
+            passivate: Duration::from_millis(500),
+            stop: Duration::from_secs(1),
+        }
+    }
+}
+
+impl LifecycleLimits {
+    pub fn limit(&self, hook: LifecycleHook) -> Duration {
+        match hook {
+            LifecycleHook::Init => self.init,
+            LifecycleHook::Activate => self.activate,
+            LifecycleHook::Passivate => self.passivate,
+            LifecycleHook::Stop => self.stop,
+        }
+    }
+}
+
+#[derive(Debug)]
+pub struct TimedOut {
+    pub export: &'static str,
+    pub limit: Duration,
+}
+
+impl Error for TimedOut {}
+
+impl fmt::Display for TimedOut {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{} did not complete within {}ms", self.export, self.limit.as_millis())
+    }
+}
+
+struct Armed {
+    isolate_handle: rusty_v8::IsolateHandle,
+    fired: Arc<AtomicBool>,
+}
+
+#[derive(Default)]
+struct Deadlines {
+    next_id: u64,
+    armed: BTreeMap<(Instant, u64), Armed>,
+}
+
+/// Terminates the execution of isolates whose deadline passes before they
+/// are disarmed. A single thread watches the deadlines of all isolates.
+pub struct Watchdog {
+    deadlines: Mutex<Deadlines>,
+    changed: Condvar,
+}
+
+lazy_static! {
+    static ref WATCHDOG: Arc<Watchdog> = Watchdog::start();
+}
+
+impl Watchdog {
+    fn start() -> Arc<Self> {
+        let watchdog = Arc::new(Self {
+            deadlines: Mutex::new(Deadlines::default()),
+            changed: Condvar::new(),
+        });
+        let watcher = watchdog.clone();
+        thread::Builder::new()
+            .name("golem-watchdog".to_string())
+            .spawn(move || watcher.watch())
+            .expect("failed to start the watchdog thread");
+        watchdog
+    }
+
+    fn watch(&self) {
+        let mut deadlines = self.deadlines.lock().unwrap();
+        loop {
+            let now = Instant::now();
+            while let Some(&key) = deadlines.armed.keys().next() {
+                if key.0 > now {
+                    break;
+                }
+                let armed = deadlines.armed.remove(&key).unwrap();
+                armed.fired.store(true, Ordering::SeqCst);
+                armed.isolate_handle.terminate_execution();
+            }
+
+            deadlines = match deadlines.armed.keys().next() {
+                Some(&(deadline, _)) => self.changed.wait_timeout(deadlines, deadline - now).unwrap().0,
+                None => self.changed.wait(deadlines).unwrap(),
+            };
+        }
+    }
+
+    /// Terminates the execution of the isolate unless the returned deadline
+    /// is disarmed within `limit`.
+    pub fn arm(isolate_handle: rusty_v8::IsolateHandle, limit: Duration) -> Deadline {
+        let watchdog = WATCHDOG.clone();
+        let fired = Arc::new(AtomicBool::new(false));
+        let key = {
+            let mut deadlines = watchdog.deadlines.lock().unwrap();
+            deadlines.next_id += 1;
+            let key = (Instant::now() + limit, deadlines.next_id);
+            let armed = Armed {
+                isolate_handle: isolate_handle.clone(),
+                fired: fired.clone(),
+            };
+            deadlines.armed.insert(key, armed);
+            key
+        };
+        watchdog.changed.notify_one();
+
+        Deadline { watchdog, key, isolate_handle, fired }
+    }
+}
+
+pub struct Deadline {
+    watchdog: Arc<Watchdog>,
+    key: (Instant, u64),
+    isolate_handle: rusty_v8::IsolateHandle,
+    fired: Arc<AtomicBool>,
+}
+
+impl Deadline {
+    /// Returns whether the watchdog had already terminated execution, in
+    /// which case the termination is cancelled so that the isolate can run
+    /// scripts again.
+    pub fn disarm(self) -> bool {
+        self.watchdog.deadlines.lock().unwrap().armed.remove(&self.key);
+        let fired = self.fired.load(Ordering::SeqCst);
+        if fired {
+            self.isolate_handle.cancel_terminate_execution();
+        }
+        fired
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use deno_core::{CoreIsolate, StartupData};
+
+    fn isolate() -> (Box<CoreIsolate>, rusty_v8::IsolateHandle) {
+        let mut isolate = CoreIsolate::new(StartupData::None, false);
+        let handle = isolate.v8_isolate.as_mut().unwrap().thread_safe_handle();
+        (isolate, handle)
+    }
+
+    #[test]
+    fn test_terminates_after_limit() {
+        let (mut isolate, handle) = isolate();
+        let deadline = Watchdog::arm(handle, Duration::from_millis(20));
+
+        assert!(isolate.execute("loop.js", "for (;;) {}").is_err());
+        assert!(deadline.disarm());
+        assert!(isolate.execute("after.js", "1 + 1").is_ok());
+    }
+
+    #[test]
+    fn test_disarmed_deadline_does_not_fire() {
+        let (mut isolate, handle) = isolate();
+        let short = Watchdog::arm(handle.clone(), Duration::from_millis(10));
+        let long = Watchdog::arm(handle, Duration::from_secs(60));
+
+        assert!(isolate.execute("quick.js", "1 + 1").is_ok());
+        assert!(!short.disarm());
+        thread::sleep(Duration::from_millis(30));
+        assert!(isolate.execute("later.js", "1 + 1").is_ok());
+        assert!(!long.disarm());
+    }
+}
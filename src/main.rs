@@ -1,7 +1,31 @@
-use golem;
-use std::time::Instant;
+use golem::ServerConfig;
+use std::env;
+use std::process;
 
-#[tokio::main]
+/// Serves the API on `GOLEM_HTTP_ADDR` and the gRPC interface on
+/// `GOLEM_GRPC_ADDR`, with the routes in the file named by `GOLEM_ROUTES`
+/// if it is set. Setting `GOLEM_GRPC_ADDR` to `off` disables gRPC.
+#[actix_rt::main]
 async fn main() {
-    golem::run_v8().await;
+    let mut config = ServerConfig::default();
+    if let Ok(addr) = env::var("GOLEM_HTTP_ADDR") {
+        config.http_addr = addr;
+    }
+    match env::var("GOLEM_GRPC_ADDR").as_ref().map(String::as_str) {
+        Ok("off") => config.grpc_addr = None,
+        Ok(addr) => match addr.parse() {
+            Ok(addr) => config.grpc_addr = Some(addr),
+            Err(error) => {
+                eprintln!("invalid GOLEM_GRPC_ADDR '{}': {}", addr, error);
+                process::exit(1);
+            }
+        },
+        Err(_) => {}
+    }
+    config.routes = env::var_os("GOLEM_ROUTES").map(Into::into);
+
+    if let Err(error) = golem::serve(config).await {
+        eprintln!("{}", error);
+        process::exit(1);
+    }
 }
@@ -0,0 +1,228 @@
+//! Describes what an actor script exports, so that deploys are rejected
+//! when the script is turned into a snapshot rather than when the first
+//! message arrives.
+//!
+//! Besides its handlers, a script may export a `manifest` object declaring
+//! the messages it accepts and the capabilities it needs:
+//!
+//! ```js
+//! export const manifest = {
+//!     messages: {
+//!         increment: { type: "object", properties: { by: { type: "number" } } },
+//!     },
+//!     permissions: ["fetch", "fs"],
+//! };
+//! ```
+//!
+//! A classic script declares it as a top-level `const manifest` or
+//! `var manifest`. A manifest that throws when it is read or that cannot
+//! be serialized as JSON is malformed.
+//!
+//! Message schemas are informational: each must be an object, but the
+//! runtime does not check the messages an actor is sent against them.
+
+use serde_json::Value;
+use std::collections::BTreeMap;
+use std::fmt;
+
+/// Exports that are called by the runtime, together with the maximum
+/// number of arguments each of them is invoked with.
+pub const HANDLERS: &[(&str, u32)] = &[
+    ("main", 3),
+    ("fetch", 3),
+    ("cache", 2),
+    ("migrate", 3),
+    ("init", 1),
+    ("onActivate", 2),
+    ("onPassivate", 2),
+    ("onStop", 2),
+];
+
+pub const PERMISSIONS: &[&str] = &["fetch", "fs"];
+
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct HandlerInfo {
+    pub name: String,
+    pub arity: u32,
+}
+
+#[derive(Debug, Clone, Default, PartialEq, Serialize, Deserialize)]
+pub struct Declarations {
+    #[serde(default)]
+    pub messages: BTreeMap<String, Value>,
+    #[serde(default)]
+    pub permissions: Vec<String>,
+}
+
+#[derive(Debug, Clone, Default, PartialEq, Serialize)]
+pub struct ActorManifest {
+    pub handlers: Vec<HandlerInfo>,
+    /// The declared message schemas, which are not enforced.
+    pub messages: BTreeMap<String, Value>,
+    pub permissions: Vec<String>,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum ManifestError {
+    MissingMain,
+    NotAFunction(String),
+    TooManyParameters { handler: String, arity: u32, max: u32 },
+    MalformedManifest(String),
+    InvalidMessageSchema(String),
+    UnknownPermission(String),
+}
+
+impl fmt::Display for ManifestError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ManifestError::MissingMain => write!(f, "the script does not export a main function"),
+            ManifestError::NotAFunction(name) => write!(f, "'{}' is exported but is not a function", name),
+            ManifestError::TooManyParameters { handler, arity, max } => write!(
+                f,
+                "'{}' declares {} parameters but is only ever called with {}",
+                handler, arity, max
+            ),
+            ManifestError::MalformedManifest(msg) => write!(f, "the exported manifest is malformed: {}", msg),
+            ManifestError::InvalidMessageSchema(name) => {
+                write!(f, "the schema for message '{}' must be an object", name)
+            }
+            ManifestError::UnknownPermission(name) => write!(f, "unknown permission '{}'", name),
+        }
+    }
+}
+
+/// What was found on the global object for a handler name.
+pub enum Export {
+    Missing,
+    Function { arity: u32 },
+    Other,
+}
+
+impl ActorManifest {
+    /// Builds the manifest from the exports found on the global object,
+    /// collecting every problem rather than stopping at the first one.
+    pub fn build<F>(mut lookup: F, declarations: Option<Value>) -> Result<Self, Vec<ManifestError>>
+        where
+            F: FnMut(&str) -> Export,
+    {
+        let mut errors = Vec::new();
+        let mut handlers = Vec::new();
+
+        for (name, max) in HANDLERS.iter() {
+            match lookup(name) {
+                Export::Function { arity } => {
+                    if arity > *max {
+                        errors.push(ManifestError::TooManyParameters {
+                            handler: name.to_string(),
+                            arity,
+                            max: *max,
+                        });
+                    }
+                    handlers.push(HandlerInfo { name: name.to_string(), arity });
+                }
+                Export::Other => errors.push(ManifestError::NotAFunction(name.to_string())),
+                Export::Missing if *name == "main" => errors.push(ManifestError::MissingMain),
+                Export::Missing => {}
+            }
+        }
+
+        let declarations = match declarations {
+            None | Some(Value::Null) => Declarations::default(),
+            Some(value) => serde_json::from_value(value).unwrap_or_else(|e| {
+                errors.push(ManifestError::MalformedManifest(e.to_string()));
+                Declarations::default()
+            }),
+        };
+
+        for (name, schema) in declarations.messages.iter() {
+            if !schema.is_object() {
+                errors.push(ManifestError::InvalidMessageSchema(name.clone()));
+            }
+        }
+
+        for permission in declarations.permissions.iter() {
+            if !PERMISSIONS.contains(&permission.as_str()) {
+                errors.push(ManifestError::UnknownPermission(permission.clone()));
+            }
+        }
+
+        if !errors.is_empty() {
+            return Err(errors);
+        }
+
+        Ok(Self {
+            handlers,
+            messages: declarations.messages,
+            permissions: declarations.permissions,
+        })
+    }
+
+    pub fn has_handler(&self, name: &str) -> bool {
+        self.handlers.iter().any(|handler| handler.name == name)
+    }
+
+    pub fn has_permission(&self, name: &str) -> bool {
+        self.permissions.iter().any(|permission| permission == name)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn lookup(name: &str) -> Export {
+        match name {
+            "main" => Export::Function { arity: 3 },
+            "cache" => Export::Function { arity: 2 },
+            _ => Export::Missing,
+        }
+    }
+
+    #[test]
+    fn test_build_manifest() {
+        let declarations = json!({
+            "messages": { "increment": { "type": "object" } },
+            "permissions": ["fetch"],
+        });
+        let manifest = ActorManifest::build(lookup, Some(declarations)).unwrap();
+        assert!(manifest.has_handler("main"));
+        assert!(manifest.has_handler("cache"));
+        assert!(!manifest.has_handler("init"));
+        assert!(manifest.has_permission("fetch"));
+        assert_eq!(manifest.messages.len(), 1);
+    }
+
+    #[test]
+    fn test_collects_all_errors() {
+        let lookup = |name: &str| match name {
+            "cache" => Export::Function { arity: 4 },
+            "init" => Export::Other,
+            _ => Export::Missing,
+        };
+        let declarations = json!({
+            "messages": { "increment": true },
+            "permissions": ["stdin"],
+        });
+        let errors = ActorManifest::build(lookup, Some(declarations)).unwrap_err();
+        assert_eq!(
+            errors,
+            vec![
+                ManifestError::MissingMain,
+                ManifestError::TooManyParameters {
+                    handler: "cache".to_string(),
+                    arity: 4,
+                    max: 2,
+                },
+                ManifestError::NotAFunction("init".to_string()),
+                ManifestError::InvalidMessageSchema("increment".to_string()),
+                ManifestError::UnknownPermission("stdin".to_string()),
+            ]
+        );
+    }
+
+    #[test]
+    fn test_malformed_manifest() {
+        let errors = ActorManifest::build(lookup, Some(json!({ "permissions": "fetch" }))).unwrap_err();
+        assert!(matches!(errors[0], ManifestError::MalformedManifest(_)));
+    }
+}
@@ -0,0 +1,144 @@
+//! What crosses the boundary of an isolate: messages passed to `main`,
+//! replies and states.
+//!
+//! They are JSON, or raw bytes, which reach JavaScript as a `Uint8Array`
+//! without being encoded. Any `ArrayBuffer` or view of one an actor
+//! returns, replies with or keeps as its state is taken back as bytes.
+//! Where only JSON fits, e.g. in a state event, bytes are represented as
+//! an array of numbers.
+//!
+//! Clients are sent plain JSON: a `Map` in a reply becomes an array of
+//! entries, a `BigInt` a string, see `plain` in `js/golem.js`, and what
+//! they send is parsed as it is. Only the state the runtime keeps for
+//! passivation and hot swaps, storage, and messages actors publish to
+//! each other are encoded by the prelude with objects tagged with
+//! `"$type"`, so that values JSON cannot represent survive.
+
+use bytes::Bytes;
+use serde::{Serialize, Serializer};
+use serde_json::Value;
+
+pub const JSON_CONTENT_TYPE: &str = "application/json";
+
+pub const BINARY_CONTENT_TYPE: &str = "application/octet-stream";
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum Message {
+    Json(Value),
+    Binary(Bytes),
+    /// JSON encoded by the prelude's codec, which is decoded before it is
+    /// passed to an actor. Where it is shown as it is, e.g. in a listed
+    /// dead letter, values JSON cannot represent keep their tags.
+    Encoded(Value),
+}
+
+impl Message {
+    pub fn content_type(&self) -> &'static str {
+        match self {
+            Message::Json(_) | Message::Encoded(_) => JSON_CONTENT_TYPE,
+            Message::Binary(_) => BINARY_CONTENT_TYPE,
+        }
+    }
+
+    pub fn into_json(self) -> Value {
+        match self {
+            Message::Json(value) | Message::Encoded(value) => value,
+            Message::Binary(bytes) => Value::Array(bytes.iter().map(|byte| Value::from(*byte)).collect()),
+        }
+    }
+
+    /// The message as a body in its content type.
+    pub fn into_bytes(self) -> Bytes {
+        match self {
+            Message::Json(value) | Message::Encoded(value) => Bytes::from(value.to_string()),
+            Message::Binary(bytes) => bytes,
+        }
+    }
+}
+
+impl From<Value> for Message {
+    fn from(value: Value) -> Self {
+        Message::Json(value)
+    }
+}
+
+/// Serialized where only JSON fits, e.g. in a listed dead letter, with
+/// bytes as an array of numbers like `into_json`.
+impl Serialize for Message {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        match self {
+            Message::Json(value) | Message::Encoded(value) => value.serialize(serializer),
+            Message::Binary(bytes) => serializer.collect_seq(bytes.iter()),
+        }
+    }
+}
+
+impl Default for Message {
+    fn default() -> Self {
+        Message::Json(Value::Null)
+    }
+}
+
+/// Whether a `Content-Type` is JSON, i.e. `application/json` or a
+/// `+json` suffix type, parameters aside.
+pub fn is_json(content_type: &str) -> bool {
+    let essence = content_type.split(';').next().unwrap_or("").trim().to_ascii_lowercase();
+    essence == JSON_CONTENT_TYPE || (essence.starts_with("application/") && essence.ends_with("+json"))
+}
+
+/// Whether an `Accept` header allows a response of `content_type`. Media
+/// ranges are matched without regard to their weights, except that a
+/// weight of zero excludes a range.
+pub fn accepts(accept: &str, content_type: &str) -> bool {
+    let (kind, _) = split_media_type(content_type);
+    accept.split(',').any(|range| {
+        let mut parts = range.split(';');
+        let media_range = parts.next().unwrap_or("").trim().to_ascii_lowercase();
+        let refused = parts.any(|parameter| {
+            let parameter: String = parameter.chars().filter(|c| !c.is_whitespace()).collect();
+            parameter.starts_with("q=") && parameter[2..].parse::<f32>().map_or(false, |weight| weight == 0.0)
+        });
+        if refused {
+            return false;
+        }
+        match split_media_type(&media_range) {
+            ("*", "*") => true,
+            (range_kind, "*") => range_kind == kind,
+            _ => media_range == content_type,
+        }
+    })
+}
+
+fn split_media_type(media_type: &str) -> (&str, &str) {
+    let mut parts = media_type.splitn(2, '/');
+    (parts.next().unwrap_or(""), parts.next().unwrap_or(""))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_binary_as_json() {
+        let message = Message::Binary(Bytes::from_static(&[0, 127, 255]));
+        assert_eq!(message.into_json(), json!([0, 127, 255]));
+    }
+
+    #[test]
+    fn test_is_json() {
+        assert!(is_json("application/json"));
+        assert!(is_json("Application/JSON; charset=utf-8"));
+        assert!(is_json("application/merge-patch+json"));
+        assert!(!is_json("application/octet-stream"));
+        assert!(!is_json("application/x-protobuf"));
+    }
+
+    #[test]
+    fn test_accepts() {
+        assert!(accepts("*/*", BINARY_CONTENT_TYPE));
+        assert!(accepts("application/*", JSON_CONTENT_TYPE));
+        assert!(accepts("text/html, application/json;q=0.9", JSON_CONTENT_TYPE));
+        assert!(!accepts("application/json", BINARY_CONTENT_TYPE));
+        assert!(!accepts("*/*;q=0", JSON_CONTENT_TYPE));
+    }
+}
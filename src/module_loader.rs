@@ -0,0 +1,144 @@
+use deno_core::{ErrBox, ModuleLoader, ModuleSource, ModuleSourceFuture, ModuleSpecifier};
+use futures::FutureExt;
+use std::collections::HashMap;
+use std::pin::Pin;
+
+/// All modules live under this root so that relative imports between them
+/// can be resolved without ever touching the host filesystem.
+pub const BUNDLE_ROOT: &str = "golem:///";
+
+/// The synthetic module that imports the actor's main module and copies its
+/// exports onto the global object, where `GolemIsolate` expects to find
+/// `main`, `cache` and friends once the snapshot is restored.
+pub const ENTRY_MODULE: &str = "golem:///$golem_entry.js";
+
+/// An actor written as a graph of ES modules, keyed by their path relative
+/// to the bundle root, e.g. `main.js` or `lib/util.js`.
+#[derive(Debug, Clone)]
+pub struct ModuleBundle {
+    pub main: String,
+    pub modules: HashMap<String, String>,
+}
+
+impl ModuleBundle {
+    pub fn new(main: &str) -> Self {
+        Self {
+            main: main.to_string(),
+            modules: HashMap::new(),
+        }
+    }
+
+    pub fn with_module(mut self, path: &str, source: &str) -> Self {
+        self.modules.insert(path.to_string(), source.to_string());
+        self
+    }
+
+    pub fn main_specifier(&self) -> Result<ModuleSpecifier, ErrBox> {
+        let specifier = ModuleSpecifier::resolve_import(&format!("./{}", self.main), BUNDLE_ROOT)?;
+        Ok(specifier)
+    }
+
+    pub fn entry_source(&self) -> Result<String, ErrBox> {
+        let main = self.main_specifier()?;
+        Ok(format!(
+            "import * as actor from \"{}\";\n\
+             for (const name of Object.keys(actor)) {{ globalThis[name] = actor[name]; }}\n",
+            main
+        ))
+    }
+
+    fn get(&self, specifier: &ModuleSpecifier) -> Option<&String> {
+        let url = specifier.as_str();
+        if !url.starts_with(BUNDLE_ROOT) {
+            return None;
+        }
+        self.modules.get(&url[BUNDLE_ROOT.len()..])
+    }
+}
+
+/// Resolves imports against a `ModuleBundle`. Bare and remote specifiers
+/// are rejected, only paths that exist inside the bundle can be loaded.
+pub struct BundleModuleLoader {
+    bundle: ModuleBundle,
+}
+
+impl BundleModuleLoader {
+    pub fn new(bundle: ModuleBundle) -> Self {
+        Self { bundle }
+    }
+}
+
+impl ModuleLoader for BundleModuleLoader {
+    fn resolve(
+        &self,
+        specifier: &str,
+        referrer: &str,
+        _is_main: bool,
+        _is_dyn_import: bool,
+    ) -> Result<ModuleSpecifier, ErrBox> {
+        let resolved = ModuleSpecifier::resolve_import(specifier, referrer)?;
+        if resolved.as_str().starts_with(BUNDLE_ROOT) {
+            Ok(resolved)
+        } else {
+            Err(ErrBox::from(std::io::Error::new(
+                std::io::ErrorKind::PermissionDenied,
+                format!("import of '{}' from '{}' is outside of the actor bundle", specifier, referrer),
+            )))
+        }
+    }
+
+    fn load(
+        &self,
+        module_specifier: &ModuleSpecifier,
+        _maybe_referrer: Option<ModuleSpecifier>,
+        _is_dyn_import: bool,
+    ) -> Pin<Box<ModuleSourceFuture>> {
+        let url = module_specifier.to_string();
+        let result = match self.bundle.get(module_specifier) {
+            Some(code) => Ok(ModuleSource {
+                code: code.clone(),
+                module_url_specified: url.clone(),
+                module_url_found: url,
+            }),
+            None => Err(ErrBox::from(std::io::Error::new(
+                std::io::ErrorKind::NotFound,
+                format!("module '{}' is not part of the actor bundle", url),
+            ))),
+        };
+
+        futures::future::ready(result).boxed_local()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn bundle() -> ModuleBundle {
+        ModuleBundle::new("main.js")
+            .with_module("main.js", "export function main() {}")
+            .with_module("lib/util.js", "export const x = 1;")
+    }
+
+    #[test]
+    fn test_resolve_relative_import() {
+        let loader = BundleModuleLoader::new(bundle());
+        let specifier = loader
+            .resolve("./util.js", "golem:///lib/other.js", false, false)
+            .unwrap();
+        assert_eq!(specifier.as_str(), "golem:///lib/util.js");
+    }
+
+    #[test]
+    fn test_resolve_rejects_remote_import() {
+        let loader = BundleModuleLoader::new(bundle());
+        let result = loader.resolve("https://example.com/mod.js", "golem:///main.js", false, false);
+        assert!(result.is_err());
+    }
+
+    #[test]
+    fn test_entry_imports_main() {
+        let source = bundle().entry_source().unwrap();
+        assert!(source.contains("from \"golem:///main.js\""));
+    }
+}
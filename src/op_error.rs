@@ -90,6 +90,14 @@ impl OpError {
         Self::new(ErrorKind::PermissionDenied, msg)
     }
 
+    pub fn broken_pipe(msg: String) -> OpError {
+        Self::new(ErrorKind::BrokenPipe, msg)
+    }
+
+    pub fn busy(msg: String) -> OpError {
+        Self::new(ErrorKind::Busy, msg)
+    }
+
     pub fn bad_resource(msg: String) -> OpError {
         Self::new(ErrorKind::BadResource, msg)
     }
@@ -0,0 +1,41 @@
+use crate::actor::ActorId;
+use crate::dispatch_json::{Deserialize, JsonOp, Value};
+use crate::events::ActorEvent;
+use crate::golem_isolate::GolemIsolate;
+use crate::op_error::OpError;
+use crate::state::State;
+use crate::transaction::Effect;
+use deno_core::ZeroCopyBuf;
+
+/// Events are emitted to the listeners of the actor the isolate belongs to,
+/// see `crate::events`.
+pub fn init(i: &mut GolemIsolate, s: &State, actor_id: &str) {
+    let actor = actor_id.to_string();
+    i.register_op(
+        "op_emit",
+        s.stateful_json_op(move |state, args, zero_copy| op_emit(state, &actor, args, zero_copy)),
+    );
+}
+
+#[derive(Deserialize)]
+struct EmitArgs {
+    event: Option<Value>,
+}
+
+/// Events emitted while `main` runs are only sent once it returns, see
+/// `crate::transaction`.
+fn op_emit(
+    state: &State,
+    actor_id: &ActorId,
+    args: Value,
+    _zero_copy: Option<ZeroCopyBuf>,
+) -> Result<JsonOp, OpError> {
+    let args: EmitArgs = serde_json::from_value(args)?;
+    let event = args.event.unwrap_or(Value::Null);
+    let mut state = state.borrow_mut();
+    match state.transaction.as_mut() {
+        Some(transaction) => transaction.push(Effect::Emit { event }),
+        None => state.events.send(actor_id, ActorEvent::Emitted(event)),
+    }
+    Ok(JsonOp::Sync(json!({})))
+}
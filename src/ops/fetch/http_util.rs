@@ -1,4 +1,6 @@
 // Copyright 2018-2020 the Deno authors. All rights reserved. MIT license.
+use crate::op_error::OpError;
+use crate::resources::{ActorResource, Capability};
 use bytes::Bytes;
 use deno_core::ErrBox;
 use futures::future::FutureExt;
@@ -231,6 +233,16 @@ impl AsyncRead for HttpBody {
     }
 }
 
+impl ActorResource for HttpBody {
+    fn capability(&self) -> Capability {
+        Capability::HttpBody
+    }
+
+    fn poll_read(&mut self, cx: &mut Context, buf: &mut [u8]) -> Poll<Result<usize, OpError>> {
+        AsyncRead::poll_read(Pin::new(self), cx, buf).map_err(OpError::from)
+    }
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
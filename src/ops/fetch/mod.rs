@@ -1,34 +1,43 @@
 use crate::dispatch_json::{Deserialize, JsonOp, Value};
-use super::io::{StreamResource, StreamResourceHolder};
-
+use crate::golem_isolate::GolemIsolate;
 use crate::op_error::OpError;
+use crate::resources::{ResourceId, Resources};
 use crate::state::State;
-use deno_core::CoreIsolate;
 use deno_core::ZeroCopyBuf;
 use http::header::HeaderName;
 use http::header::HeaderValue;
 use http::Method;
 use std::convert::From;
 use std::borrow::Borrow;
-use futures::FutureExt;
+use futures::{FutureExt, StreamExt};
+use reqwest::Body;
+use std::io;
 use crate::ops::fetch::http_util::{create_http_client, HttpBody};
 
 pub mod http_util;
 
-pub fn init(i: &mut CoreIsolate, s: &State) {
-    i.register_op("op_fetch", s.stateful_json_op2(op_fetch));
+pub fn init(i: &mut GolemIsolate, s: &State, resources: &Resources) {
+    let resources = resources.clone();
+    i.register_op(
+        "op_fetch",
+        s.stateful_json_op(move |state, args, data| op_fetch(state, &resources, args, data)),
+    );
 }
 
 #[derive(Deserialize)]
+#[serde(rename_all = "camelCase")]
 struct FetchArgs {
     method: Option<String>,
     url: String,
     headers: Vec<(String, String)>,
+    /// A stream the request body is read from, instead of the zero-copy
+    /// buffer.
+    body_rid: Option<ResourceId>,
 }
 
 pub fn op_fetch(
-    isolate: &mut CoreIsolate,
-    state: &State,
+    _state: &State,
+    resources: &Resources,
     args: Value,
     data: Option<ZeroCopyBuf>,
 ) -> Result<JsonOp, OpError> {
@@ -58,7 +67,10 @@ pub fn op_fetch(
 
     let mut request = client.request(method, url_);
 
-    if let Some(buf) = data {
+    if let Some(rid) = args.body_rid {
+        let body = resources.borrow_mut().take_body(rid)?;
+        request = request.body(Body::wrap_stream(body.map(Ok::<_, io::Error>)));
+    } else if let Some(buf) = data {
         request = request.body(Vec::from(&*buf));
     }
 
@@ -69,7 +81,7 @@ pub fn op_fetch(
     }
     debug!("Before fetch {}", url);
 
-    let resource_table = isolate.resource_table.clone();
+    let resources = resources.clone();
     let future = async move {
         let res = request.send().await?;
         debug!("Fetch response {}", url);
@@ -80,13 +92,7 @@ pub fn op_fetch(
         }
 
         let body = HttpBody::from(res);
-        let mut resource_table = resource_table.borrow_mut();
-        let rid = resource_table.add(
-            "httpBody",
-            Box::new(StreamResourceHolder::new(StreamResource::HttpBody(
-                Box::new(body),
-            ))),
-        );
+        let rid = resources.borrow_mut().add(Box::new(body))?;
 
         let json_res = json!({
       "bodyRid": rid,
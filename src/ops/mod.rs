@@ -1,5 +1,37 @@
 use crate::golem_isolate::GolemIsolate;
+use crate::resources::{Capability, ResourceRegistry, Resources};
+use crate::state::State;
+use std::cell::RefCell;
+use std::rc::Rc;
 
 pub mod logging;
+pub mod events;
 pub mod fetch;
 pub mod io;
+pub mod reply;
+pub mod sockets;
+pub mod storage;
+pub mod topics;
+pub mod vfs;
+
+/// Registers the ops every actor isolate has access to, plus those of the
+/// capabilities its manifest grants. Ops that are not registered are
+/// unknown to the actor's code. Returns the isolate's resource registry.
+pub fn init(isolate: &mut GolemIsolate, state: &State, actor_id: &str) -> Resources {
+    let resources = Rc::new(RefCell::new(ResourceRegistry::for_manifest(isolate.manifest())));
+    reply::init(isolate, state, &resources);
+    topics::init(isolate, state, actor_id);
+    sockets::init(isolate, state, actor_id);
+    events::init(isolate, state, actor_id);
+    storage::init(isolate, state, actor_id);
+    io::init(isolate, state, &resources);
+
+    let granted = |capability| resources.borrow().is_granted(capability);
+    if granted(Capability::HttpBody) {
+        fetch::init(isolate, state, &resources);
+    }
+    if granted(Capability::VirtualFile) {
+        vfs::init(isolate, state, actor_id, &resources);
+    }
+    resources
+}
@@ -0,0 +1,127 @@
+use crate::dispatch_json::{Deserialize, JsonOp, Value};
+use crate::golem_isolate::GolemIsolate;
+use crate::op_error::OpError;
+use crate::replies::{ActorResponse, Reply, ReplyId};
+use crate::resources::{ResourceId, Resources};
+use crate::state::State;
+use bytes::Bytes;
+use deno_core::ZeroCopyBuf;
+
+pub fn init(i: &mut GolemIsolate, s: &State, resources: &Resources) {
+    i.register_op("op_reply", s.stateful_json_op(op_reply));
+    i.register_op("op_defer_reply", s.stateful_json_op(op_defer_reply));
+    let r = resources.clone();
+    i.register_op(
+        "op_reply_stream",
+        s.stateful_json_op(move |state, args, _zero_copy| op_reply_stream(state, &r, args)),
+    );
+    let r = resources.clone();
+    i.register_op(
+        "op_reply_response",
+        s.stateful_json_op(move |state, args, _zero_copy| op_reply_response(state, &r, args)),
+    );
+}
+
+fn already_sent(reply_id: ReplyId) -> OpError {
+    OpError::not_found(format!("reply {} was already sent or its caller stopped waiting", reply_id))
+}
+
+/// Sends a reply, or holds it back until the open transaction commits, see
+/// `crate::transaction`.
+fn send_reply(state: &State, reply_id: ReplyId, reply: Reply) -> Result<JsonOp, OpError> {
+    let mut state = state.borrow_mut();
+    let state = &mut *state;
+    let mut replies = state.replies.lock().unwrap();
+    let sent = match state.transaction.as_mut() {
+        Some(transaction) => replies.is_pending(reply_id) && transaction.hold_reply(reply_id, reply),
+        None => replies.send(reply_id, reply),
+    };
+    if !sent {
+        return Err(already_sent(reply_id));
+    }
+    Ok(JsonOp::Sync(json!({})))
+}
+
+#[derive(Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct ReplyArgs {
+    reply_id: ReplyId,
+    value: Option<Value>,
+    /// The reply is the bytes passed as the zero-copy buffer, which is
+    /// left out when there are none.
+    #[serde(default)]
+    binary: bool,
+}
+
+fn op_reply(
+    state: &State,
+    args: Value,
+    zero_copy: Option<ZeroCopyBuf>,
+) -> Result<JsonOp, OpError> {
+    let args: ReplyArgs = serde_json::from_value(args)?;
+    let reply = if args.binary {
+        Reply::Binary(zero_copy.map_or_else(Bytes::new, |buf| Bytes::copy_from_slice(&buf)))
+    } else {
+        Reply::Value(args.value.unwrap_or(Value::Null))
+    };
+    send_reply(state, args.reply_id, reply)
+}
+
+#[derive(Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct DeferReplyArgs {
+    reply_id: ReplyId,
+}
+
+fn op_defer_reply(
+    state: &State,
+    args: Value,
+    _zero_copy: Option<ZeroCopyBuf>,
+) -> Result<JsonOp, OpError> {
+    let args: DeferReplyArgs = serde_json::from_value(args)?;
+    if !state.borrow().replies.lock().unwrap().defer(args.reply_id) {
+        return Err(already_sent(args.reply_id));
+    }
+    Ok(JsonOp::Sync(json!({})))
+}
+
+#[derive(Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct ReplyStreamArgs {
+    reply_id: ReplyId,
+    rid: ResourceId,
+}
+
+/// Replies with the body written to the stream `rid`. The actor keeps
+/// writing to the stream after replying and closes it when done.
+fn op_reply_stream(state: &State, resources: &Resources, args: Value) -> Result<JsonOp, OpError> {
+    let args: ReplyStreamArgs = serde_json::from_value(args)?;
+    let body = resources.borrow_mut().take_body(args.rid)?;
+    send_reply(state, args.reply_id, Reply::Stream(body))
+}
+
+#[derive(Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct ReplyResponseArgs {
+    reply_id: ReplyId,
+    status: u16,
+    #[serde(default)]
+    headers: Vec<(String, String)>,
+    body_rid: Option<ResourceId>,
+}
+
+/// Answers an HTTP request with the `Response` returned by `fetch`. The
+/// body, if any, is streamed like that of `op_reply_stream`.
+fn op_reply_response(state: &State, resources: &Resources, args: Value) -> Result<JsonOp, OpError> {
+    let args: ReplyResponseArgs = serde_json::from_value(args)?;
+    let body = match args.body_rid {
+        Some(rid) => Some(resources.borrow_mut().take_body(rid)?),
+        None => None,
+    };
+    let response = ActorResponse {
+        status: args.status,
+        headers: args.headers,
+        body,
+    };
+    send_reply(state, args.reply_id, Reply::Response(response))
+}
@@ -0,0 +1,101 @@
+use crate::actor::ActorId;
+use crate::dispatch_json::{Deserialize, JsonOp, Value};
+use crate::golem_isolate::GolemIsolate;
+use crate::op_error::OpError;
+use crate::sockets::{SocketFrame, SocketId, CLOSE_NORMAL};
+use crate::state::State;
+use crate::transaction::Effect;
+use bytes::Bytes;
+use deno_core::ZeroCopyBuf;
+
+/// The socket ops only reach the connections of the actor the isolate
+/// belongs to, see `crate::sockets`.
+pub fn init(i: &mut GolemIsolate, s: &State, actor_id: &str) {
+    let actor = actor_id.to_string();
+    i.register_op(
+        "op_socket_send",
+        s.stateful_json_op(move |state, args, zero_copy| op_socket_send(state, &actor, args, zero_copy)),
+    );
+    let actor = actor_id.to_string();
+    i.register_op(
+        "op_socket_close",
+        s.stateful_json_op(move |state, args, zero_copy| op_socket_close(state, &actor, args, zero_copy)),
+    );
+    let actor = actor_id.to_string();
+    i.register_op(
+        "op_socket_list",
+        s.stateful_json_op(move |state, _args, _zero_copy| {
+            Ok(JsonOp::Sync(json!(state.borrow().sockets.list(&actor))))
+        }),
+    );
+}
+
+/// Queues a frame, or holds it back until `main` returns if it is running.
+fn send_frame(state: &State, actor_id: &ActorId, socket_id: SocketId, frame: SocketFrame) -> Result<(), OpError> {
+    let mut state = state.borrow_mut();
+    state.sockets.check(actor_id, socket_id)?;
+    match state.transaction.as_mut() {
+        Some(transaction) => transaction.push(Effect::Socket { socket_id, frame }),
+        None => state.sockets.send(socket_id, frame),
+    }
+    Ok(())
+}
+
+#[derive(Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct SendArgs {
+    socket_id: SocketId,
+    #[serde(default)]
+    data: String,
+    /// The message is the bytes passed as the zero-copy buffer, which is
+    /// left out when there are none.
+    #[serde(default)]
+    binary: bool,
+}
+
+fn op_socket_send(
+    state: &State,
+    actor_id: &ActorId,
+    args: Value,
+    zero_copy: Option<ZeroCopyBuf>,
+) -> Result<JsonOp, OpError> {
+    let args: SendArgs = serde_json::from_value(args)?;
+    let frame = if args.binary {
+        SocketFrame::Binary(zero_copy.map_or_else(Bytes::new, |buf| Bytes::copy_from_slice(&buf)))
+    } else {
+        SocketFrame::Text(args.data)
+    };
+    send_frame(state, actor_id, args.socket_id, frame)?;
+    Ok(JsonOp::Sync(json!({})))
+}
+
+#[derive(Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct CloseArgs {
+    socket_id: SocketId,
+    code: Option<u16>,
+    #[serde(default)]
+    reason: String,
+}
+
+fn op_socket_close(
+    state: &State,
+    actor_id: &ActorId,
+    args: Value,
+    _zero_copy: Option<ZeroCopyBuf>,
+) -> Result<JsonOp, OpError> {
+    let args: CloseArgs = serde_json::from_value(args)?;
+    let code = args.code.unwrap_or(CLOSE_NORMAL);
+    if code != CLOSE_NORMAL && !(3000..5000).contains(&code) {
+        return Err(OpError::type_error(format!(
+            "close code {} is reserved, use 1000 or a code between 3000 and 4999",
+            code
+        )));
+    }
+    if args.reason.len() > 123 {
+        return Err(OpError::type_error("close reasons are limited to 123 bytes".to_string()));
+    }
+    let frame = SocketFrame::Close { code, reason: args.reason };
+    send_frame(state, actor_id, args.socket_id, frame)?;
+    Ok(JsonOp::Sync(json!({})))
+}
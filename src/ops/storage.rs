@@ -0,0 +1,71 @@
+use crate::actor::ActorId;
+use crate::dispatch_json::{Deserialize, JsonOp, Value};
+use crate::golem_isolate::GolemIsolate;
+use crate::op_error::OpError;
+use crate::state::State;
+use deno_core::ZeroCopyBuf;
+use std::rc::Rc;
+
+type StorageOp = fn(&State, &ActorId, Value) -> Result<JsonOp, OpError>;
+
+/// Like the subscription ops, the storage ops are bound to the ID of the
+/// actor the isolate belongs to.
+pub fn init(i: &mut GolemIsolate, s: &State, actor_id: &str) {
+    let ops: &[(&str, StorageOp)] = &[
+        ("op_storage_get", op_storage_get),
+        ("op_storage_put", op_storage_put),
+        ("op_storage_delete", op_storage_delete),
+        ("op_storage_list", op_storage_list),
+    ];
+    let actor: Rc<ActorId> = Rc::new(actor_id.to_string());
+    for (name, op) in ops.iter().cloned() {
+        let actor = actor.clone();
+        i.register_op(
+            name,
+            s.stateful_json_op(move |state, args, _zero_copy: Option<ZeroCopyBuf>| op(state, &actor, args)),
+        );
+    }
+}
+
+#[derive(Deserialize)]
+struct KeyArgs {
+    key: String,
+}
+
+fn op_storage_get(state: &State, actor_id: &ActorId, args: Value) -> Result<JsonOp, OpError> {
+    let args: KeyArgs = serde_json::from_value(args)?;
+    let mut state = state.borrow_mut();
+    let value = state.storage.actor(actor_id).get(&args.key).cloned();
+    Ok(JsonOp::Sync(value.unwrap_or(Value::Null)))
+}
+
+#[derive(Deserialize)]
+struct PutArgs {
+    key: String,
+    #[serde(default)]
+    value: Value,
+}
+
+fn op_storage_put(state: &State, actor_id: &ActorId, args: Value) -> Result<JsonOp, OpError> {
+    let args: PutArgs = serde_json::from_value(args)?;
+    state.borrow_mut().storage.actor(actor_id).put(args.key, args.value);
+    Ok(JsonOp::Sync(json!({})))
+}
+
+fn op_storage_delete(state: &State, actor_id: &ActorId, args: Value) -> Result<JsonOp, OpError> {
+    let args: KeyArgs = serde_json::from_value(args)?;
+    let existed = state.borrow_mut().storage.actor(actor_id).delete(&args.key);
+    Ok(JsonOp::Sync(json!(existed)))
+}
+
+#[derive(Deserialize)]
+struct ListArgs {
+    prefix: Option<String>,
+}
+
+fn op_storage_list(state: &State, actor_id: &ActorId, args: Value) -> Result<JsonOp, OpError> {
+    let args: ListArgs = serde_json::from_value(args)?;
+    let prefix = args.prefix.unwrap_or_default();
+    let entries = state.borrow_mut().storage.actor(actor_id).list(&prefix);
+    Ok(JsonOp::Sync(json!(entries)))
+}
@@ -0,0 +1,97 @@
+use crate::actor::ActorId;
+use crate::dispatch_json::{Deserialize, JsonOp, Value};
+use crate::golem_isolate::GolemIsolate;
+use crate::message::Message;
+use crate::op_error::OpError;
+use crate::state::State;
+use crate::transaction::Effect;
+use bytes::Bytes;
+use deno_core::ZeroCopyBuf;
+
+/// The subscription ops act on behalf of the actor the isolate belongs to,
+/// so they are bound to its ID rather than taking it as an argument.
+pub fn init(i: &mut GolemIsolate, s: &State, actor_id: &str) {
+    let actor = actor_id.to_string();
+    i.register_op(
+        "op_subscribe",
+        s.stateful_json_op(move |state, args, zero_copy| op_subscribe(state, &actor, args, zero_copy)),
+    );
+    let actor = actor_id.to_string();
+    i.register_op(
+        "op_unsubscribe",
+        s.stateful_json_op(move |state, args, zero_copy| op_unsubscribe(state, &actor, args, zero_copy)),
+    );
+    i.register_op("op_publish", s.stateful_json_op(op_publish));
+}
+
+#[derive(Deserialize)]
+struct TopicArgs {
+    topic: String,
+}
+
+fn op_subscribe(
+    state: &State,
+    actor_id: &ActorId,
+    args: Value,
+    _zero_copy: Option<ZeroCopyBuf>,
+) -> Result<JsonOp, OpError> {
+    let args: TopicArgs = serde_json::from_value(args)?;
+    let topics = state.borrow().topics.clone();
+    let subscribed = topics.lock().unwrap().subscribe(&args.topic, actor_id);
+    Ok(JsonOp::Sync(json!(subscribed)))
+}
+
+fn op_unsubscribe(
+    state: &State,
+    actor_id: &ActorId,
+    args: Value,
+    _zero_copy: Option<ZeroCopyBuf>,
+) -> Result<JsonOp, OpError> {
+    let args: TopicArgs = serde_json::from_value(args)?;
+    let topics = state.borrow().topics.clone();
+    let unsubscribed = topics.lock().unwrap().unsubscribe(&args.topic, actor_id);
+    Ok(JsonOp::Sync(json!(unsubscribed)))
+}
+
+#[derive(Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct PublishArgs {
+    topic: String,
+    message: Option<Value>,
+    /// The message is the bytes passed as the zero-copy buffer, which is
+    /// left out when there are none.
+    #[serde(default)]
+    binary: bool,
+    /// The message was encoded by the prelude's codec, and is decoded
+    /// again before it is passed to subscribers.
+    #[serde(default)]
+    encoded: bool,
+}
+
+/// Messages published while `main` runs are only sent once it returns, see
+/// `crate::transaction`.
+fn op_publish(
+    state: &State,
+    args: Value,
+    zero_copy: Option<ZeroCopyBuf>,
+) -> Result<JsonOp, OpError> {
+    let args: PublishArgs = serde_json::from_value(args)?;
+    let message = if args.binary {
+        Message::Binary(zero_copy.map_or_else(Bytes::new, |buf| Bytes::copy_from_slice(&buf)))
+    } else if args.encoded {
+        Message::Encoded(args.message.unwrap_or(Value::Null))
+    } else {
+        Message::Json(args.message.unwrap_or(Value::Null))
+    };
+    let mut state = state.borrow_mut();
+    let topics = state.topics.clone();
+    let subscribers = match state.transaction.as_mut() {
+        Some(transaction) => {
+            let subscribers = topics.lock().unwrap().subscribers(&args.topic).len();
+            transaction.push(Effect::Publish { topic: args.topic, message });
+            subscribers
+        }
+        None => topics.lock().unwrap().publish(&args.topic, message),
+    };
+    Ok(JsonOp::Sync(json!(subscribers)))
+}
@@ -0,0 +1,169 @@
+use crate::actor::ActorId;
+use crate::dispatch_json::{Deserialize, JsonOp, Value};
+use crate::golem_isolate::GolemIsolate;
+use crate::op_error::OpError;
+use crate::resources::Resources;
+use crate::state::State;
+use crate::vfs::{disk_usage, VirtualFile, ENTRY_SIZE};
+use deno_core::ZeroCopyBuf;
+use std::fs;
+use std::io::{Seek, SeekFrom};
+use std::rc::Rc;
+use std::time::UNIX_EPOCH;
+
+type VfsOp = fn(&State, &ActorId, &Resources, Value) -> Result<JsonOp, OpError>;
+
+/// The filesystem ops work on the sandbox of the actor the isolate belongs
+/// to. Open files are `VirtualFile` resources, read and written with
+/// `op_read` and `op_write` and closed with `op_close`.
+pub fn init(i: &mut GolemIsolate, s: &State, actor_id: &str, resources: &Resources) {
+    let ops: &[(&str, VfsOp)] = &[
+        ("op_vfs_open", op_vfs_open),
+        ("op_vfs_stat", op_vfs_stat),
+        ("op_vfs_read_dir", op_vfs_read_dir),
+        ("op_vfs_mkdir", op_vfs_mkdir),
+        ("op_vfs_remove", op_vfs_remove),
+    ];
+    let actor: Rc<ActorId> = Rc::new(actor_id.to_string());
+    for (name, op) in ops.iter().cloned() {
+        let actor = actor.clone();
+        let resources = resources.clone();
+        i.register_op(
+            name,
+            s.stateful_json_op(move |state, args, _zero_copy: Option<ZeroCopyBuf>| {
+                op(state, &actor, &resources, args)
+            }),
+        );
+    }
+}
+
+#[derive(Deserialize, Default)]
+#[serde(rename_all = "camelCase", default)]
+struct OpenOptions {
+    read: bool,
+    write: bool,
+    append: bool,
+    truncate: bool,
+    create: bool,
+    create_new: bool,
+}
+
+#[derive(Deserialize)]
+struct OpenArgs {
+    path: String,
+    #[serde(default)]
+    options: Option<OpenOptions>,
+}
+
+fn op_vfs_open(state: &State, actor_id: &ActorId, resources: &Resources, args: Value) -> Result<JsonOp, OpError> {
+    let args: OpenArgs = serde_json::from_value(args)?;
+    let options = args.options.unwrap_or(OpenOptions {
+        read: true,
+        ..OpenOptions::default()
+    });
+
+    let mut state = state.borrow_mut();
+    let sandbox = state.vfs.sandbox(actor_id)?;
+    let path = sandbox.resolve(&args.path)?;
+    let quota = sandbox.quota().clone();
+
+    let previous = fs::metadata(&path).ok();
+    let previous_length = previous.as_ref().map_or(0, |metadata| metadata.len());
+    let creates = previous.is_none() && (options.create || options.create_new);
+    if creates {
+        quota.check(ENTRY_SIZE)?;
+    }
+    let mut file = fs::OpenOptions::new()
+        .read(options.read)
+        .write(options.write)
+        .append(options.append)
+        .truncate(options.truncate)
+        .create(options.create)
+        .create_new(options.create_new)
+        .open(&path)?;
+    if creates {
+        quota.charge(ENTRY_SIZE);
+    }
+    if options.truncate {
+        quota.release(previous_length);
+    }
+    if options.append {
+        // So that the growth of the first write is known, see `VirtualFile`.
+        file.seek(SeekFrom::End(0))?;
+    }
+
+    let rid = resources.borrow_mut().add(Box::new(VirtualFile::new(file, quota)))?;
+    Ok(JsonOp::Sync(json!(rid)))
+}
+
+#[derive(Deserialize)]
+struct PathArgs {
+    path: String,
+    #[serde(default)]
+    recursive: bool,
+}
+
+fn op_vfs_stat(state: &State, actor_id: &ActorId, _resources: &Resources, args: Value) -> Result<JsonOp, OpError> {
+    let args: PathArgs = serde_json::from_value(args)?;
+    let path = state.borrow_mut().vfs.sandbox(actor_id)?.resolve(&args.path)?;
+    let metadata = fs::metadata(&path)?;
+    let modified = metadata
+        .modified()
+        .ok()
+        .and_then(|time| time.duration_since(UNIX_EPOCH).ok())
+        .map(|duration| duration.as_millis() as u64);
+    Ok(JsonOp::Sync(json!({
+        "isFile": metadata.is_file(),
+        "isDirectory": metadata.is_dir(),
+        "size": metadata.len(),
+        "modified": modified,
+    })))
+}
+
+fn op_vfs_read_dir(state: &State, actor_id: &ActorId, _resources: &Resources, args: Value) -> Result<JsonOp, OpError> {
+    let args: PathArgs = serde_json::from_value(args)?;
+    let path = state.borrow_mut().vfs.sandbox(actor_id)?.resolve(&args.path)?;
+    let mut entries = Vec::new();
+    for entry in fs::read_dir(&path)? {
+        let entry = entry?;
+        let file_type = entry.file_type()?;
+        entries.push(json!({
+            "name": entry.file_name().to_string_lossy(),
+            "isFile": file_type.is_file(),
+            "isDirectory": file_type.is_dir(),
+        }));
+    }
+    Ok(JsonOp::Sync(json!(entries)))
+}
+
+fn op_vfs_mkdir(state: &State, actor_id: &ActorId, _resources: &Resources, args: Value) -> Result<JsonOp, OpError> {
+    let args: PathArgs = serde_json::from_value(args)?;
+    let mut state = state.borrow_mut();
+    let sandbox = state.vfs.sandbox(actor_id)?;
+    let path = sandbox.resolve(&args.path)?;
+    sandbox.create_dir(&path, args.recursive)?;
+    Ok(JsonOp::Sync(json!({})))
+}
+
+fn op_vfs_remove(state: &State, actor_id: &ActorId, _resources: &Resources, args: Value) -> Result<JsonOp, OpError> {
+    let args: PathArgs = serde_json::from_value(args)?;
+    let mut state = state.borrow_mut();
+    let sandbox = state.vfs.sandbox(actor_id)?;
+    let path = sandbox.resolve(&args.path)?;
+    if path == sandbox.root() {
+        return Err(OpError::permission_denied("the filesystem root cannot be removed".to_string()));
+    }
+
+    let size = disk_usage(&path)?;
+    if fs::symlink_metadata(&path)?.is_dir() {
+        if args.recursive {
+            fs::remove_dir_all(&path)?;
+        } else {
+            fs::remove_dir(&path)?;
+        }
+    } else {
+        fs::remove_file(&path)?;
+    }
+    sandbox.quota().release(size);
+    Ok(JsonOp::Sync(json!({})))
+}
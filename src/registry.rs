@@ -0,0 +1,292 @@
+//! Deployed actor types and their script versions.
+//!
+//! Every script uploaded for a type is kept as a numbered version. Which
+//! version an actor runs is decided, in order of precedence, by:
+//!
+//! 1. a pin of that actor to a specific version,
+//! 2. an ongoing rollout that selects the actor, either by listing it
+//!    explicitly or through a percentage of actors,
+//! 3. the type's stable version.
+//!
+//! Running actors are hot-swapped onto the version they resolve to the next
+//! time they receive a message, so promoting or rolling back a rollout
+//! takes effect without redeploying actors.
+
+use crate::golem_isolate::GolemSnapshot;
+use std::collections::{BTreeMap, HashMap, HashSet};
+use std::error::Error;
+use std::fmt;
+
+pub type Version = u32;
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum RolloutTarget {
+    /// The share of actors, between 0 and 100, that run the new version.
+    Percentage(u8),
+    Actors(HashSet<String>),
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct Rollout {
+    pub version: Version,
+    pub target: RolloutTarget,
+}
+
+#[derive(Debug, PartialEq)]
+pub enum RegistryError {
+    UnknownType(String),
+    UnknownVersion(Version),
+    NoRollout,
+    NothingToRollBack,
+}
+
+impl Error for RegistryError {}
+
+impl fmt::Display for RegistryError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            RegistryError::UnknownType(actor_type) => write!(f, "actor type '{}' has not been deployed", actor_type),
+            RegistryError::UnknownVersion(version) => write!(f, "version {} does not exist", version),
+            RegistryError::NoRollout => write!(f, "no rollout is in progress"),
+            RegistryError::NothingToRollBack => write!(f, "there is no previous stable version"),
+        }
+    }
+}
+
+#[derive(Default)]
+struct ActorType {
+    versions: BTreeMap<Version, GolemSnapshot>,
+    stable: Version,
+    /// Previously stable versions, most recent last.
+    history: Vec<Version>,
+    rollout: Option<Rollout>,
+    pins: HashMap<String, Version>,
+}
+
+impl ActorType {
+    fn resolve(&self, actor_id: &str) -> Version {
+        if let Some(version) = self.pins.get(actor_id) {
+            return *version;
+        }
+        if let Some(rollout) = &self.rollout {
+            let selected = match &rollout.target {
+                RolloutTarget::Percentage(percentage) => stable_hash(actor_id) % 100 < *percentage as u32,
+                RolloutTarget::Actors(actors) => actors.contains(actor_id),
+            };
+            if selected {
+                return rollout.version;
+            }
+        }
+        self.stable
+    }
+
+    fn check_version(&self, version: Version) -> Result<(), RegistryError> {
+        if self.versions.contains_key(&version) {
+            Ok(())
+        } else {
+            Err(RegistryError::UnknownVersion(version))
+        }
+    }
+}
+
+/// FNV-1a hash of an actor ID. Used rather than the std hasher so that an
+/// actor lands in the same rollout bucket and on the same worker across
+/// restarts.
+pub fn stable_hash(actor_id: &str) -> u32 {
+    let mut hash: u32 = 0x811c_9dc5;
+    for byte in actor_id.bytes() {
+        hash ^= byte as u32;
+        hash = hash.wrapping_mul(0x0100_0193);
+    }
+    hash
+}
+
+#[derive(Default)]
+pub struct Registry {
+    types: HashMap<String, ActorType>,
+}
+
+impl Registry {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Stores `snapshot` as a new version of `actor_type` without routing
+    /// any actors to it, unless it is the type's first version. Versions
+    /// start at 1.
+    pub fn upload(&mut self, actor_type: &str, snapshot: GolemSnapshot) -> Version {
+        let entry = self.types.entry(actor_type.to_string()).or_default();
+        let version = entry.versions.keys().next_back().map_or(1, |v| v + 1);
+        entry.versions.insert(version, snapshot);
+        if entry.stable == 0 {
+            entry.stable = version;
+        }
+        info!("uploaded {} version {}", actor_type, version);
+        version
+    }
+
+    /// Uploads `snapshot` and makes it the stable version straight away.
+    pub fn deploy(&mut self, actor_type: &str, snapshot: GolemSnapshot) -> Version {
+        let version = self.upload(actor_type, snapshot);
+        let entry = self.types.get_mut(actor_type).unwrap();
+        if entry.stable != version {
+            entry.history.push(entry.stable);
+            entry.stable = version;
+        }
+        entry.rollout = None;
+        info!("deployed {} version {}", actor_type, version);
+        version
+    }
+
+    /// Routes the actors selected by `target` to `version`, replacing any
+    /// rollout that is already in progress.
+    pub fn start_rollout(&mut self, actor_type: &str, version: Version, target: RolloutTarget) -> Result<(), RegistryError> {
+        let entry = self.get_mut(actor_type)?;
+        entry.check_version(version)?;
+        entry.rollout = Some(Rollout { version, target });
+        Ok(())
+    }
+
+    /// Makes the version being rolled out the stable version.
+    pub fn promote(&mut self, actor_type: &str) -> Result<Version, RegistryError> {
+        let entry = self.get_mut(actor_type)?;
+        let rollout = entry.rollout.take().ok_or(RegistryError::NoRollout)?;
+        entry.history.push(entry.stable);
+        entry.stable = rollout.version;
+        Ok(rollout.version)
+    }
+
+    /// Cancels the rollout in progress or, if there is none, reverts to the
+    /// previous stable version. Returns the version that is now stable.
+    pub fn rollback(&mut self, actor_type: &str) -> Result<Version, RegistryError> {
+        let entry = self.get_mut(actor_type)?;
+        if entry.rollout.take().is_some() {
+            return Ok(entry.stable);
+        }
+        let previous = entry.history.pop().ok_or(RegistryError::NothingToRollBack)?;
+        entry.stable = previous;
+        Ok(previous)
+    }
+
+    pub fn pin(&mut self, actor_type: &str, actor_id: &str, version: Version) -> Result<(), RegistryError> {
+        let entry = self.get_mut(actor_type)?;
+        entry.check_version(version)?;
+        entry.pins.insert(actor_id.to_string(), version);
+        Ok(())
+    }
+
+    pub fn unpin(&mut self, actor_type: &str, actor_id: &str) -> Result<(), RegistryError> {
+        self.get_mut(actor_type)?.pins.remove(actor_id);
+        Ok(())
+    }
+
+    pub fn versions(&self, actor_type: &str) -> Vec<Version> {
+        self.types
+            .get(actor_type)
+            .map(|entry| entry.versions.keys().cloned().collect())
+            .unwrap_or_default()
+    }
+
+    pub fn rollout(&self, actor_type: &str) -> Option<&Rollout> {
+        self.types.get(actor_type).and_then(|entry| entry.rollout.as_ref())
+    }
+
+    /// The version, and its snapshot, that `actor_id` should be running.
+    pub fn resolve(&self, actor_type: &str, actor_id: &str) -> Option<(Version, &GolemSnapshot)> {
+        let entry = self.types.get(actor_type)?;
+        let version = entry.resolve(actor_id);
+        entry.versions.get(&version).map(|snapshot| (version, snapshot))
+    }
+
+    fn get_mut(&mut self, actor_type: &str) -> Result<&mut ActorType, RegistryError> {
+        self.types
+            .get_mut(actor_type)
+            .ok_or_else(|| RegistryError::UnknownType(actor_type.to_string()))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn snapshot() -> GolemSnapshot {
+        GolemSnapshot::new(vec![])
+    }
+
+    fn version_of(registry: &Registry, actor_id: &str) -> Version {
+        registry.resolve("counter", actor_id).unwrap().0
+    }
+
+    #[test]
+    fn test_upload_does_not_route() {
+        let mut registry = Registry::new();
+        assert_eq!(registry.upload("counter", snapshot()), 1);
+        assert_eq!(registry.upload("counter", snapshot()), 2);
+        assert_eq!(version_of(&registry, "a"), 1);
+        assert_eq!(registry.versions("counter"), vec![1, 2]);
+    }
+
+    #[test]
+    fn test_rollout_to_listed_actors() {
+        let mut registry = Registry::new();
+        registry.deploy("counter", snapshot());
+        let v2 = registry.upload("counter", snapshot());
+
+        let actors = vec!["a".to_string()].into_iter().collect();
+        registry.start_rollout("counter", v2, RolloutTarget::Actors(actors)).unwrap();
+        assert_eq!(version_of(&registry, "a"), 2);
+        assert_eq!(version_of(&registry, "b"), 1);
+
+        assert_eq!(registry.rollback("counter"), Ok(1));
+        assert_eq!(version_of(&registry, "a"), 1);
+    }
+
+    #[test]
+    fn test_percentage_rollout() {
+        let mut registry = Registry::new();
+        registry.deploy("counter", snapshot());
+        let v2 = registry.upload("counter", snapshot());
+
+        registry.start_rollout("counter", v2, RolloutTarget::Percentage(0)).unwrap();
+        assert_eq!(version_of(&registry, "a"), 1);
+        registry.start_rollout("counter", v2, RolloutTarget::Percentage(100)).unwrap();
+        assert_eq!(version_of(&registry, "a"), 2);
+
+        registry.start_rollout("counter", v2, RolloutTarget::Percentage(30)).unwrap();
+        let upgraded = (0..1000)
+            .filter(|i| version_of(&registry, &i.to_string()) == 2)
+            .count();
+        assert!(upgraded > 200 && upgraded < 400);
+    }
+
+    #[test]
+    fn test_promote_and_roll_back() {
+        let mut registry = Registry::new();
+        registry.deploy("counter", snapshot());
+        let v2 = registry.upload("counter", snapshot());
+
+        assert_eq!(registry.promote("counter"), Err(RegistryError::NoRollout));
+        registry.start_rollout("counter", v2, RolloutTarget::Percentage(10)).unwrap();
+        assert_eq!(registry.promote("counter"), Ok(2));
+        assert_eq!(version_of(&registry, "a"), 2);
+
+        assert_eq!(registry.rollback("counter"), Ok(1));
+        assert_eq!(version_of(&registry, "a"), 1);
+        assert_eq!(registry.rollback("counter"), Err(RegistryError::NothingToRollBack));
+    }
+
+    #[test]
+    fn test_pin_takes_precedence() {
+        let mut registry = Registry::new();
+        registry.deploy("counter", snapshot());
+        registry.deploy("counter", snapshot());
+
+        registry.pin("counter", "a", 1).unwrap();
+        assert_eq!(version_of(&registry, "a"), 1);
+        assert_eq!(version_of(&registry, "b"), 2);
+        assert_eq!(registry.pin("counter", "a", 7), Err(RegistryError::UnknownVersion(7)));
+
+        registry.unpin("counter", "a").unwrap();
+        assert_eq!(version_of(&registry, "a"), 2);
+    }
+}
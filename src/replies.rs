@@ -0,0 +1,423 @@
+//! Callers waiting for an actor's reply.
+//!
+//! Every message sent with `ask` is registered here under a reply ID that
+//! the actor sees as `ctx.replyId`. Unless the actor answers explicitly
+//! with `ctx.reply`, or defers its answer with `ctx.defer`, the caller
+//! receives what `main` returned. A deferred reply is answered later with
+//! `ctx.reply` from an async callback, or with `ctx.replyTo` and the handle
+//! returned by `ctx.defer`, until the caller's deadline passes.
+//!
+//! The table is shared by all workers, so that any actor holding a handle
+//! can answer it, wherever the asked actor lives.
+//!
+//! Callers may attach an idempotency key. A caller retrying with a key that
+//! was already answered receives the remembered reply, and one retrying
+//! while the message is still in flight waits for the same reply. See
+//! `crate::idempotency`.
+//!
+//! Replies are JSON or bytes, see `crate::message`. Instead, an actor may
+//! reply with a stream it writes the body to, see `crate::streams`, or with
+//! the `Response` of its `fetch` export. Such replies are not remembered.
+
+use crate::idempotency::{IdempotencyKey, Outcome, ProcessedKeys};
+use crate::message::Message;
+use crate::streams::BodyReceiver;
+use crate::supervisor::SendError;
+use deno_core::ErrBox;
+use bytes::Bytes;
+use futures::channel::oneshot;
+use serde_json::Value;
+use std::collections::HashMap;
+use std::io;
+use std::sync::{Arc, Mutex};
+use std::time::Instant;
+
+pub type ReplyId = u64;
+
+pub type SharedReplies = Arc<Mutex<Replies>>;
+
+pub type ReplySender = oneshot::Sender<Result<Reply, SendError>>;
+
+/// What the caller of `ask` receives.
+pub enum Reply {
+    Value(Value),
+    Binary(Bytes),
+    /// A body the actor streams chunk by chunk.
+    Stream(BodyReceiver),
+    /// The answer to an HTTP request passed to the actor's `fetch` export.
+    Response(ActorResponse),
+}
+
+impl From<Message> for Reply {
+    fn from(message: Message) -> Self {
+        match message {
+            Message::Json(value) | Message::Encoded(value) => Reply::Value(value),
+            Message::Binary(bytes) => Reply::Binary(bytes),
+        }
+    }
+}
+
+pub struct ActorResponse {
+    pub status: u16,
+    pub headers: Vec<(String, String)>,
+    pub body: Option<BodyReceiver>,
+}
+
+struct Waiter {
+    sender: ReplySender,
+    deadline: Instant,
+}
+
+struct PendingReply {
+    waiters: Vec<Waiter>,
+    deferred: bool,
+    key: Option<IdempotencyKey>,
+}
+
+/// What happened to a caller passed to `Replies::register`.
+#[derive(Debug, PartialEq)]
+pub enum Registration {
+    /// The message should be delivered, and its reply sent under this ID.
+    New(ReplyId),
+    /// The same message is already in flight, the caller will receive its
+    /// reply.
+    Joined,
+    /// The caller received the remembered reply.
+    Answered,
+}
+
+#[derive(Default)]
+pub struct Replies {
+    next_id: ReplyId,
+    pending: HashMap<ReplyId, PendingReply>,
+    keys: ProcessedKeys,
+}
+
+impl Replies {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn shared() -> SharedReplies {
+        Arc::new(Mutex::new(Self::new()))
+    }
+
+    pub fn register(&mut self, sender: ReplySender, deadline: Instant, key: Option<IdempotencyKey>) -> Registration {
+        let now = Instant::now();
+        if let Some(key) = key.as_ref() {
+            match self.keys.get(key, now) {
+                Some(Outcome::Completed(response)) => {
+                    sender.send(Ok(Reply::from(response.clone()))).ok();
+                    return Registration::Answered;
+                }
+                Some(Outcome::InFlight(id)) => {
+                    if let Some(pending) = self.pending.get_mut(id) {
+                        pending.waiters.push(Waiter { sender, deadline });
+                        return Registration::Joined;
+                    }
+                }
+                None => {}
+            }
+        }
+
+        self.next_id += 1;
+        let id = self.next_id;
+        if let Some(key) = key.clone() {
+            self.keys.start(key, id, now);
+        }
+        self.pending.insert(id, PendingReply {
+            waiters: vec![Waiter { sender, deadline }],
+            deferred: false,
+            key,
+        });
+        Registration::New(id)
+    }
+
+    pub fn defer(&mut self, id: ReplyId) -> bool {
+        match self.pending.get_mut(&id) {
+            Some(pending) => {
+                pending.deferred = true;
+                true
+            }
+            None => false,
+        }
+    }
+
+    /// Whether callers are waiting for a reply under `id`.
+    pub fn is_pending(&self, id: ReplyId) -> bool {
+        self.pending.contains_key(&id)
+    }
+
+    /// Sends a reply of any kind, see `reply`, `stream` and `respond`.
+    pub fn send(&mut self, id: ReplyId, reply: Reply) -> bool {
+        match reply {
+            Reply::Value(value) => self.reply(id, Message::Json(value)),
+            Reply::Binary(bytes) => self.reply(id, Message::Binary(bytes)),
+            Reply::Stream(body) => self.stream(id, body),
+            Reply::Response(response) => self.respond(id, response),
+        }
+    }
+
+    /// Answers the callers, returning false if they already received a
+    /// reply or gave up waiting. A reply to a message with an idempotency
+    /// key is remembered even if nobody is waiting for it any more.
+    pub fn reply(&mut self, id: ReplyId, response: Message) -> bool {
+        let pending = match self.pending.remove(&id) {
+            Some(pending) => pending,
+            None => return false,
+        };
+
+        let mut delivered = false;
+        for waiter in pending.waiters {
+            delivered |= waiter.sender.send(Ok(Reply::from(response.clone()))).is_ok();
+        }
+        if let Some(key) = pending.key {
+            self.keys.complete(&key, response);
+            delivered = true;
+        }
+        delivered
+    }
+
+    /// Streams the reply to one of the callers, see `send_once`.
+    pub fn stream(&mut self, id: ReplyId, body: BodyReceiver) -> bool {
+        self.send_once(id, Reply::Stream(body))
+    }
+
+    /// Answers an HTTP request, see `send_once`.
+    pub fn respond(&mut self, id: ReplyId, response: ActorResponse) -> bool {
+        self.send_once(id, Reply::Response(response))
+    }
+
+    /// Sends a reply that can only be read once to one of the callers.
+    /// Callers that joined with the same idempotency key fail.
+    fn send_once(&mut self, id: ReplyId, reply: Reply) -> bool {
+        let pending = match self.pending.remove(&id) {
+            Some(pending) => pending,
+            None => return false,
+        };
+        if let Some(key) = pending.key.as_ref() {
+            self.keys.forget(key);
+        }
+
+        let mut reply = Some(reply);
+        for waiter in pending.waiters {
+            let result = match reply.take() {
+                Some(reply) if !waiter.sender.is_canceled() => Ok(reply),
+                other => {
+                    reply = other;
+                    Err(SendError::Failed(ErrBox::from(io::Error::new(
+                        io::ErrorKind::Other,
+                        "the reply was streamed to another caller",
+                    ))))
+                }
+            };
+            waiter.sender.send(result).ok();
+        }
+        reply.is_none()
+    }
+
+    pub fn fail(&mut self, id: ReplyId, error: SendError) {
+        let pending = match self.pending.remove(&id) {
+            Some(pending) => pending,
+            None => return,
+        };
+        if let Some(key) = pending.key.as_ref() {
+            self.keys.forget(key);
+        }
+
+        let mut waiters = pending.waiters.into_iter();
+        if let Some(first) = waiters.next() {
+            for waiter in waiters {
+                waiter.sender.send(Err(duplicate(&error))).ok();
+            }
+            first.sender.send(Err(error)).ok();
+        }
+    }
+
+    /// Called once the invocation for `id` returned `response`.
+    pub fn complete(&mut self, id: ReplyId, response: Message) {
+        let deferred = self.pending.get(&id).map_or(true, |pending| pending.deferred);
+        if !deferred {
+            self.reply(id, response);
+        }
+    }
+
+    /// Forgets the idempotency keys of a stopped actor.
+    pub fn remove_actor(&mut self, actor_id: &str) {
+        self.keys.remove_actor(actor_id);
+    }
+
+    /// Drops the callers that are no longer waiting. Deferred replies with
+    /// an idempotency key are kept while the key is remembered, so that a
+    /// retry can still wait for them.
+    pub fn expire(&mut self, now: Instant) {
+        self.keys.expire(now);
+        let keys = &self.keys;
+        self.pending.retain(|_, pending| {
+            pending
+                .waiters
+                .retain(|waiter| waiter.deadline > now && !waiter.sender.is_canceled());
+            let remembered = pending.key.as_ref().map_or(false, |key| keys.get(key, now).is_some());
+            !pending.waiters.is_empty() || remembered
+        });
+    }
+}
+
+/// A copy of `error` for the other callers waiting on the same message.
+fn duplicate(error: &SendError) -> SendError {
+    match error {
+        SendError::NotFound => SendError::NotFound,
+        SendError::Unavailable { retry_after } => SendError::Unavailable { retry_after: *retry_after },
+        SendError::Failed(error) => SendError::Failed(ErrBox::from(io::Error::new(io::ErrorKind::Other, error.to_string()))),
+        SendError::TimedOut => SendError::TimedOut,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::time::Duration;
+
+    fn later() -> Instant {
+        Instant::now() + Duration::from_secs(60)
+    }
+
+    fn register(replies: &mut Replies, sender: ReplySender, deadline: Instant, key: Option<IdempotencyKey>) -> ReplyId {
+        match replies.register(sender, deadline, key) {
+            Registration::New(id) => id,
+            registration => panic!("unexpected {:?}", registration),
+        }
+    }
+
+    fn key() -> Option<IdempotencyKey> {
+        Some(("a".to_string(), "key".to_string()))
+    }
+
+    fn received(receiver: &mut oneshot::Receiver<Result<Reply, SendError>>) -> Value {
+        match receiver.try_recv().unwrap().unwrap() {
+            Ok(Reply::Value(value)) => value,
+            _ => panic!("expected a value"),
+        }
+    }
+
+    #[test]
+    fn test_complete_replies_with_response() {
+        let mut replies = Replies::new();
+        let (sender, mut receiver) = oneshot::channel();
+        let id = register(&mut replies, sender, later(), None);
+
+        replies.complete(id, Message::Json(json!(1)));
+        assert_eq!(received(&mut receiver), json!(1));
+    }
+
+    #[test]
+    fn test_explicit_reply_wins() {
+        let mut replies = Replies::new();
+        let (sender, mut receiver) = oneshot::channel();
+        let id = register(&mut replies, sender, later(), None);
+
+        assert!(replies.reply(id, Message::Json(json!("explicit"))));
+        replies.complete(id, Message::Json(json!(1)));
+        assert_eq!(received(&mut receiver), json!("explicit"));
+    }
+
+    #[test]
+    fn test_deferred_reply() {
+        let mut replies = Replies::new();
+        let (sender, mut receiver) = oneshot::channel();
+        let id = register(&mut replies, sender, later(), None);
+
+        assert!(replies.defer(id));
+        replies.complete(id, Message::Json(json!(1)));
+        assert!(receiver.try_recv().unwrap().is_none());
+
+        assert!(replies.reply(id, Message::Json(json!(2))));
+        assert_eq!(received(&mut receiver), json!(2));
+    }
+
+    #[test]
+    fn test_expire() {
+        let mut replies = Replies::new();
+        let (sender, _receiver) = oneshot::channel();
+        let id = register(&mut replies, sender, Instant::now(), None);
+
+        replies.expire(Instant::now() + Duration::from_millis(1));
+        assert!(!replies.defer(id));
+    }
+
+    #[test]
+    fn test_retry_receives_remembered_reply() {
+        let mut replies = Replies::new();
+        let (sender, _receiver) = oneshot::channel();
+        let id = register(&mut replies, sender, later(), key());
+        replies.complete(id, Message::Json(json!(1)));
+
+        let (sender, mut receiver) = oneshot::channel();
+        assert_eq!(replies.register(sender, later(), key()), Registration::Answered);
+        assert_eq!(received(&mut receiver), json!(1));
+    }
+
+    #[test]
+    fn test_binary_reply_is_remembered() {
+        let mut replies = Replies::new();
+        let (sender, mut receiver) = oneshot::channel();
+        let id = register(&mut replies, sender, later(), key());
+        replies.complete(id, Message::Binary(Bytes::from_static(b"\x89PNG")));
+        assert!(matches!(receiver.try_recv().unwrap().unwrap(), Ok(Reply::Binary(_))));
+
+        let (sender, mut receiver) = oneshot::channel();
+        assert_eq!(replies.register(sender, later(), key()), Registration::Answered);
+        match receiver.try_recv().unwrap().unwrap() {
+            Ok(Reply::Binary(bytes)) => assert_eq!(bytes, Bytes::from_static(b"\x89PNG")),
+            _ => panic!("expected bytes"),
+        }
+    }
+
+    #[test]
+    fn test_retry_joins_deferred_reply() {
+        let mut replies = Replies::new();
+        let (sender, first) = oneshot::channel();
+        let id = register(&mut replies, sender, later(), key());
+        replies.defer(id);
+        replies.complete(id, Message::Json(json!(1)));
+
+        // The first caller gave up before the actor answered.
+        drop(first);
+        replies.expire(Instant::now());
+
+        let (sender, mut receiver) = oneshot::channel();
+        assert_eq!(replies.register(sender, later(), key()), Registration::Joined);
+        assert!(replies.reply(id, Message::Json(json!(2))));
+        assert_eq!(received(&mut receiver), json!(2));
+    }
+
+    #[test]
+    fn test_failure_is_not_remembered() {
+        let mut replies = Replies::new();
+        let (sender, _receiver) = oneshot::channel();
+        let id = register(&mut replies, sender, later(), key());
+        replies.fail(id, SendError::TimedOut);
+
+        let (sender, _receiver) = oneshot::channel();
+        assert!(matches!(replies.register(sender, later(), key()), Registration::New(_)));
+    }
+
+    #[test]
+    fn test_stream_goes_to_one_caller() {
+        let mut replies = Replies::new();
+        let (sender, mut first) = oneshot::channel();
+        let id = register(&mut replies, sender, later(), key());
+        replies.defer(id);
+        let (sender, mut second) = oneshot::channel();
+        assert_eq!(replies.register(sender, later(), key()), Registration::Joined);
+
+        let (_body, receiver) = futures::channel::mpsc::channel(1);
+        assert!(replies.stream(id, receiver));
+        assert!(matches!(first.try_recv().unwrap().unwrap(), Ok(Reply::Stream(_))));
+        assert!(second.try_recv().unwrap().unwrap().is_err());
+
+        // Streams are not remembered.
+        let (sender, _receiver) = oneshot::channel();
+        assert!(matches!(replies.register(sender, later(), key()), Registration::New(_)));
+    }
+}
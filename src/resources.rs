@@ -0,0 +1,232 @@
+//! Resources that actors hold handles to, e.g. the body of a `fetch`
+//! response or a file opened from their sandbox.
+//!
+//! Every isolate has its own registry, so a resource ID is meaningless to
+//! any other actor, and everything in it is dropped together with the
+//! isolate. Resources are trait objects of a fixed set of kinds, and a
+//! registry only accepts the kinds the actor's manifest grants it: an actor
+//! can never get hold of host streams such as the process's stdio.
+
+use crate::manifest::ActorManifest;
+use crate::op_error::OpError;
+use crate::streams::BodyReceiver;
+use std::cell::RefCell;
+use std::collections::{HashMap, HashSet};
+use std::rc::Rc;
+use std::task::{Context, Poll, Waker};
+
+pub type ResourceId = u32;
+
+pub type Resources = Rc<RefCell<ResourceRegistry>>;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum Capability {
+    HttpBody,
+    VirtualFile,
+    /// Streams between the runtime and the actor, e.g. request bodies.
+    ActorStream,
+}
+
+impl Capability {
+    pub const ALL: &'static [Capability] = &[Capability::HttpBody, Capability::VirtualFile, Capability::ActorStream];
+
+    /// The manifest permission that grants the capability, `None` if every
+    /// actor has it.
+    pub fn permission(self) -> Option<&'static str> {
+        match self {
+            Capability::HttpBody => Some("fetch"),
+            Capability::VirtualFile => Some("fs"),
+            Capability::ActorStream => None,
+        }
+    }
+
+    fn name(self) -> &'static str {
+        match self {
+            Capability::HttpBody => "HTTP body",
+            Capability::VirtualFile => "file",
+            Capability::ActorStream => "stream",
+        }
+    }
+}
+
+/// A resource an actor can read from or write to. Operations a resource
+/// does not support fail by default.
+pub trait ActorResource {
+    fn capability(&self) -> Capability;
+
+    fn poll_read(&mut self, _cx: &mut Context, _buf: &mut [u8]) -> Poll<Result<usize, OpError>> {
+        Poll::Ready(Err(unsupported(self.capability(), "read from")))
+    }
+
+    fn poll_write(&mut self, _cx: &mut Context, _buf: &[u8]) -> Poll<Result<usize, OpError>> {
+        Poll::Ready(Err(unsupported(self.capability(), "written to")))
+    }
+
+    fn poll_flush(&mut self, _cx: &mut Context) -> Poll<Result<(), OpError>> {
+        Poll::Ready(Ok(()))
+    }
+
+    fn read_sync(&mut self, _buf: &mut [u8]) -> Result<usize, OpError> {
+        Err(unsupported(self.capability(), "read from synchronously"))
+    }
+
+    fn write_sync(&mut self, _buf: &[u8]) -> Result<usize, OpError> {
+        Err(unsupported(self.capability(), "written to synchronously"))
+    }
+
+    /// Hands the readable end of a stream to the runtime, e.g. to send it
+    /// as a request body. `None` if the resource is not a stream or its
+    /// body was already taken.
+    fn take_body(&mut self) -> Option<BodyReceiver> {
+        None
+    }
+}
+
+fn unsupported(capability: Capability, operation: &str) -> OpError {
+    OpError::bad_resource(format!("a {} cannot be {}", capability.name(), operation))
+}
+
+struct Entry {
+    resource: Box<dyn ActorResource>,
+    /// Tasks waiting for the resource, woken when it is closed.
+    waiting: Vec<Waker>,
+}
+
+pub struct ResourceRegistry {
+    granted: HashSet<Capability>,
+    entries: HashMap<ResourceId, Entry>,
+    next_rid: ResourceId,
+}
+
+impl ResourceRegistry {
+    pub fn new<I>(granted: I) -> Self
+        where
+            I: IntoIterator<Item=Capability>,
+    {
+        Self {
+            granted: granted.into_iter().collect(),
+            entries: HashMap::new(),
+            next_rid: 1,
+        }
+    }
+
+    /// A registry granting the capabilities the manifest has permissions
+    /// for.
+    pub fn for_manifest(manifest: &ActorManifest) -> Self {
+        Self::new(Capability::ALL.iter().cloned().filter(|capability| {
+            capability
+                .permission()
+                .map_or(true, |permission| manifest.has_permission(permission))
+        }))
+    }
+
+    pub fn is_granted(&self, capability: Capability) -> bool {
+        self.granted.contains(&capability)
+    }
+
+    pub fn add(&mut self, resource: Box<dyn ActorResource>) -> Result<ResourceId, OpError> {
+        let capability = resource.capability();
+        if !self.is_granted(capability) {
+            return Err(OpError::permission_denied(format!(
+                "the actor has not been granted access to {}s",
+                capability.name()
+            )));
+        }
+        let rid = self.next_rid;
+        self.next_rid += 1;
+        self.entries.insert(rid, Entry {
+            resource,
+            waiting: Vec::new(),
+        });
+        Ok(rid)
+    }
+
+    pub fn get_mut(&mut self, rid: ResourceId) -> Result<&mut dyn ActorResource, OpError> {
+        match self.entries.get_mut(&rid) {
+            Some(entry) => Ok(entry.resource.as_mut()),
+            None => Err(OpError::bad_resource_id()),
+        }
+    }
+
+    /// Wakes `waker` when the resource is closed.
+    pub fn track(&mut self, rid: ResourceId, waker: &Waker) {
+        if let Some(entry) = self.entries.get_mut(&rid) {
+            if !entry.waiting.iter().any(|waiting| waiting.will_wake(waker)) {
+                entry.waiting.push(waker.clone());
+            }
+        }
+    }
+
+    pub fn take_body(&mut self, rid: ResourceId) -> Result<BodyReceiver, OpError> {
+        self.get_mut(rid)?
+            .take_body()
+            .ok_or_else(|| OpError::bad_resource(format!("resource {} is not a stream that can be sent", rid)))
+    }
+
+    pub fn close(&mut self, rid: ResourceId) -> Result<(), OpError> {
+        let entry = self.entries.remove(&rid).ok_or_else(OpError::bad_resource_id)?;
+        for waker in entry.waiting {
+            waker.wake();
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    struct Stream;
+
+    impl ActorResource for Stream {
+        fn capability(&self) -> Capability {
+            Capability::ActorStream
+        }
+    }
+
+    struct File;
+
+    impl ActorResource for File {
+        fn capability(&self) -> Capability {
+            Capability::VirtualFile
+        }
+    }
+
+    #[test]
+    fn test_only_granted_resources_are_added() {
+        let mut registry = ResourceRegistry::new(vec![Capability::ActorStream]);
+        let rid = registry.add(Box::new(Stream)).unwrap();
+        assert!(registry.get_mut(rid).is_ok());
+        assert!(registry.add(Box::new(File)).is_err());
+        assert!(registry.get_mut(rid + 1).is_err());
+    }
+
+    #[test]
+    fn test_close() {
+        let mut registry = ResourceRegistry::new(vec![Capability::ActorStream]);
+        let rid = registry.add(Box::new(Stream)).unwrap();
+        registry.close(rid).unwrap();
+        assert!(registry.get_mut(rid).is_err());
+        assert!(registry.close(rid).is_err());
+    }
+
+    #[test]
+    fn test_grants_follow_manifest_permissions() {
+        let manifest = ActorManifest {
+            permissions: vec!["fs".to_string()],
+            ..ActorManifest::default()
+        };
+        let registry = ResourceRegistry::for_manifest(&manifest);
+        assert!(registry.is_granted(Capability::VirtualFile));
+        assert!(registry.is_granted(Capability::ActorStream));
+        assert!(!registry.is_granted(Capability::HttpBody));
+    }
+
+    #[test]
+    fn test_unsupported_operations_fail() {
+        let mut registry = ResourceRegistry::new(vec![Capability::ActorStream]);
+        let rid = registry.add(Box::new(Stream)).unwrap();
+        let mut buf = [0; 4];
+        assert!(registry.get_mut(rid).unwrap().read_sync(&mut buf).is_err());
+    }
+}
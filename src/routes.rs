@@ -0,0 +1,440 @@
+//! A routing table mapping HTTP requests to the `fetch` export of actors.
+//!
+//! Routes are matched in order, the first one that matches the request's
+//! host, method and path wins. The actor ID is taken from a path segment,
+//! a header or a query parameter, and the actor is spawned from the route's
+//! type if it does not exist yet. The table is loaded from a JSON file or
+//! replaced through `PUT /routes`:
+//!
+//! ```json
+//! [
+//!     {
+//!         "host": "*.chat.example.com",
+//!         "path": "/rooms/{room}/*",
+//!         "methods": ["GET", "POST"],
+//!         "actorType": "room",
+//!         "actorId": { "segment": "room" }
+//!     }
+//! ]
+//! ```
+//!
+//! In paths, `{name}` matches a single segment and a trailing `*` matches
+//! the rest of the path. A segment an actor ID is taken from is
+//! percent-decoded.
+//!
+//! Routes are served to anyone, so a route that spawns actors lets clients
+//! create as many as they have IDs for. Set `"spawn": false` for a route to
+//! only reach actors that already exist, or cap the actors it spawns with
+//! `"maxSpawned"`.
+
+use percent_encoding::percent_decode_str;
+use std::collections::HashMap;
+use std::fmt;
+use std::fs;
+use std::path::Path;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::{Arc, Mutex};
+
+pub type Routes = Arc<Mutex<RouteTable>>;
+
+/// Where the actor ID of a request is taken from.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub enum IdSource {
+    Segment(String),
+    Header(String),
+    Query(String),
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct RouteConfig {
+    /// An exact host name, or `*.` followed by a domain to match its
+    /// subdomains. Any host if absent.
+    #[serde(default)]
+    pub host: Option<String>,
+    pub path: String,
+    /// Any method if empty.
+    #[serde(default)]
+    pub methods: Vec<String>,
+    pub actor_type: String,
+    pub actor_id: IdSource,
+    /// How long to wait for the actor's response, in milliseconds.
+    #[serde(default)]
+    pub timeout: Option<u64>,
+    /// Whether requests for an actor that does not exist spawn it, rather
+    /// than getting a 404.
+    #[serde(default = "spawns_by_default")]
+    pub spawn: bool,
+    /// The most actors the route spawns, counted since the table was
+    /// loaded. Unlimited if absent.
+    #[serde(default)]
+    pub max_spawned: Option<usize>,
+}
+
+fn spawns_by_default() -> bool {
+    true
+}
+
+#[derive(Debug)]
+pub enum RouteError {
+    Io(String),
+    Malformed(String),
+    InvalidPath { path: String, reason: &'static str },
+    UnknownSegment { path: String, segment: String },
+}
+
+impl fmt::Display for RouteError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            RouteError::Io(msg) => write!(f, "the routes could not be read: {}", msg),
+            RouteError::Malformed(msg) => write!(f, "the routes are malformed: {}", msg),
+            RouteError::InvalidPath { path, reason } => write!(f, "invalid path '{}': {}", path, reason),
+            RouteError::UnknownSegment { path, segment } => {
+                write!(f, "the actor ID is taken from '{}', which '{}' does not contain", segment, path)
+            }
+        }
+    }
+}
+
+impl std::error::Error for RouteError {}
+
+#[derive(Debug, Clone, PartialEq)]
+enum Segment {
+    Literal(String),
+    Param(String),
+    Rest,
+}
+
+#[derive(Debug, Clone)]
+struct PathPattern {
+    segments: Vec<Segment>,
+}
+
+fn split_path(path: &str) -> impl Iterator<Item=&str> {
+    path.split('/').filter(|segment| !segment.is_empty())
+}
+
+impl PathPattern {
+    fn parse(path: &str) -> Result<Self, RouteError> {
+        let invalid = |reason| RouteError::InvalidPath { path: path.to_string(), reason };
+        if !path.starts_with('/') {
+            return Err(invalid("paths must start with '/'"));
+        }
+
+        let mut segments = Vec::new();
+        for segment in split_path(path) {
+            if segments.last() == Some(&Segment::Rest) {
+                return Err(invalid("'*' must be the last segment"));
+            }
+            let segment = if segment == "*" {
+                Segment::Rest
+            } else if segment.starts_with('{') && segment.ends_with('}') && segment.len() > 2 {
+                Segment::Param(segment[1..segment.len() - 1].to_string())
+            } else if segment.contains(|c| c == '{' || c == '}' || c == '*') {
+                return Err(invalid("parameters must span a whole segment"));
+            } else {
+                Segment::Literal(segment.to_string())
+            };
+            segments.push(segment);
+        }
+        Ok(Self { segments })
+    }
+
+    fn has_param(&self, name: &str) -> bool {
+        self.segments.iter().any(|segment| *segment == Segment::Param(name.to_string()))
+    }
+
+    /// The parameters of a matching path.
+    fn matches<'a>(&self, path: &'a str) -> Option<HashMap<&str, &'a str>> {
+        let mut params = HashMap::new();
+        let mut parts = split_path(path);
+        for segment in self.segments.iter() {
+            match segment {
+                Segment::Rest => return Some(params),
+                Segment::Literal(literal) => {
+                    if parts.next()? != literal.as_str() {
+                        return None;
+                    }
+                }
+                Segment::Param(name) => {
+                    params.insert(name.as_str(), parts.next()?);
+                }
+            }
+        }
+        match parts.next() {
+            Some(_) => None,
+            None => Some(params),
+        }
+    }
+}
+
+/// Strips the port from the value of a `Host` header.
+fn host_name(host: &str) -> &str {
+    match host.rfind(':') {
+        Some(i) if host[i + 1..].bytes().all(|b| b.is_ascii_digit()) => &host[..i],
+        _ => host,
+    }
+}
+
+fn host_matches(pattern: &str, host: &str) -> bool {
+    let host = host_name(host).to_ascii_lowercase();
+    let pattern = pattern.to_ascii_lowercase();
+    if pattern.starts_with("*.") {
+        host.ends_with(&pattern[1..])
+    } else {
+        host == pattern
+    }
+}
+
+struct Route {
+    config: RouteConfig,
+    path: PathPattern,
+    spawned: Arc<AtomicUsize>,
+}
+
+/// The parts of a request routes are matched against.
+pub struct RouteRequest<'a> {
+    pub method: &'a str,
+    pub host: &'a str,
+    pub path: &'a str,
+    pub query: &'a str,
+    pub headers: &'a [(String, String)],
+}
+
+/// The actor a request was routed to.
+#[derive(Debug, Clone)]
+pub struct RouteTarget {
+    pub actor_type: String,
+    pub actor_id: String,
+    pub timeout: Option<u64>,
+    /// The actors the route may still spawn, None if it spawns none.
+    pub spawn: Option<SpawnBudget>,
+}
+
+/// The actors a route may spawn, shared by all requests it routes.
+#[derive(Debug, Clone)]
+pub struct SpawnBudget {
+    limit: Option<usize>,
+    spawned: Arc<AtomicUsize>,
+}
+
+impl SpawnBudget {
+    /// Counts an actor about to be spawned, unless the limit was reached.
+    pub fn take(&self) -> bool {
+        let limit = match self.limit {
+            Some(limit) => limit,
+            None => return true,
+        };
+        if self.spawned.fetch_add(1, Ordering::SeqCst) < limit {
+            true
+        } else {
+            self.spawned.fetch_sub(1, Ordering::SeqCst);
+            false
+        }
+    }
+
+    /// Gives back what `take` counted, for an actor that failed to spawn.
+    pub fn give_back(&self) {
+        if self.limit.is_some() {
+            self.spawned.fetch_sub(1, Ordering::SeqCst);
+        }
+    }
+}
+
+#[derive(Default)]
+pub struct RouteTable {
+    routes: Vec<Route>,
+}
+
+impl RouteTable {
+    pub fn new(configs: Vec<RouteConfig>) -> Result<Self, RouteError> {
+        let mut routes = Vec::with_capacity(configs.len());
+        for config in configs {
+            let path = PathPattern::parse(&config.path)?;
+            if let IdSource::Segment(segment) = &config.actor_id {
+                if !path.has_param(segment) {
+                    return Err(RouteError::UnknownSegment {
+                        path: config.path.clone(),
+                        segment: segment.clone(),
+                    });
+                }
+            }
+            routes.push(Route { config, path, spawned: Arc::new(AtomicUsize::new(0)) });
+        }
+        Ok(Self { routes })
+    }
+
+    pub fn shared(self) -> Routes {
+        Arc::new(Mutex::new(self))
+    }
+
+    /// Reads a JSON array of routes.
+    pub fn load(path: &Path) -> Result<Self, RouteError> {
+        let json = fs::read_to_string(path).map_err(|error| RouteError::Io(error.to_string()))?;
+        let configs = serde_json::from_str(&json).map_err(|error| RouteError::Malformed(error.to_string()))?;
+        Self::new(configs)
+    }
+
+    pub fn configs(&self) -> Vec<RouteConfig> {
+        self.routes.iter().map(|route| route.config.clone()).collect()
+    }
+
+    /// The actor of the first route that matches the request and whose
+    /// actor ID is present in it.
+    pub fn resolve(&self, request: &RouteRequest) -> Option<RouteTarget> {
+        self.routes.iter().find_map(|route| {
+            let config = &route.config;
+            if let Some(host) = config.host.as_ref() {
+                if !host_matches(host, request.host) {
+                    return None;
+                }
+            }
+            if !config.methods.is_empty()
+                && !config.methods.iter().any(|method| method.eq_ignore_ascii_case(request.method))
+            {
+                return None;
+            }
+            let params = route.path.matches(request.path)?;
+
+            let actor_id = match &config.actor_id {
+                IdSource::Segment(name) => percent_decode_str(params.get(name.as_str())?).decode_utf8().ok()?.into_owned(),
+                IdSource::Header(name) => request
+                    .headers
+                    .iter()
+                    .find(|(header, _)| header.eq_ignore_ascii_case(name))?
+                    .1
+                    .clone(),
+                IdSource::Query(name) => url::form_urlencoded::parse(request.query.as_bytes())
+                    .find(|(parameter, _)| parameter == name)?
+                    .1
+                    .into_owned(),
+            };
+            if actor_id.is_empty() {
+                return None;
+            }
+            let spawn = if config.spawn {
+                Some(SpawnBudget {
+                    limit: config.max_spawned,
+                    spawned: route.spawned.clone(),
+                })
+            } else {
+                None
+            };
+            Some(RouteTarget {
+                actor_type: config.actor_type.clone(),
+                actor_id,
+                timeout: config.timeout,
+                spawn,
+            })
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::Value;
+
+    fn table(routes: Value) -> RouteTable {
+        RouteTable::new(serde_json::from_value(routes).unwrap()).unwrap()
+    }
+
+    fn request<'a>(method: &'a str, host: &'a str, path: &'a str, query: &'a str, headers: &'a [(String, String)]) -> RouteRequest<'a> {
+        RouteRequest { method, host, path, query, headers }
+    }
+
+    fn actor_id(table: &RouteTable, request: &RouteRequest) -> Option<String> {
+        table.resolve(request).map(|target| target.actor_id)
+    }
+
+    #[test]
+    fn test_id_from_segment() {
+        let table = table(json!([
+            { "path": "/rooms/{room}/*", "actorType": "room", "actorId": { "segment": "room" } },
+        ]));
+        let target = table.resolve(&request("GET", "localhost", "/rooms/lobby/messages", "", &[])).unwrap();
+        assert_eq!(target.actor_type, "room");
+        assert_eq!(target.actor_id, "lobby");
+        assert_eq!(actor_id(&table, &request("GET", "localhost", "/rooms/lobby", "", &[])), Some("lobby".to_string()));
+        assert_eq!(actor_id(&table, &request("GET", "localhost", "/users/lobby", "", &[])), None);
+    }
+
+    #[test]
+    fn test_segment_id_is_percent_decoded() {
+        let table = table(json!([
+            { "path": "/rooms/{room}", "actorType": "room", "actorId": { "segment": "room" } },
+        ]));
+        assert_eq!(actor_id(&table, &request("GET", "localhost", "/rooms/a%2Fb%20c", "", &[])), Some("a/b c".to_string()));
+        assert_eq!(actor_id(&table, &request("GET", "localhost", "/rooms/%ff", "", &[])), None);
+    }
+
+    #[test]
+    fn test_spawn_options() {
+        let table = table(json!([
+            { "path": "/fixed/{id}", "actorType": "a", "actorId": { "segment": "id" }, "spawn": false },
+            { "path": "/capped/{id}", "actorType": "a", "actorId": { "segment": "id" }, "maxSpawned": 1 },
+            { "path": "/open/{id}", "actorType": "a", "actorId": { "segment": "id" } },
+        ]));
+        let target = |path| table.resolve(&request("GET", "localhost", path, "", &[])).unwrap();
+        assert!(target("/fixed/1").spawn.is_none());
+
+        let budget = target("/capped/1").spawn.unwrap();
+        assert!(budget.take());
+        assert!(!target("/capped/2").spawn.unwrap().take());
+        budget.give_back();
+        assert!(target("/capped/3").spawn.unwrap().take());
+
+        let budget = target("/open/1").spawn.unwrap();
+        assert!(budget.take() && budget.take());
+    }
+
+    #[test]
+    fn test_id_from_header_and_query() {
+        let table = table(json!([
+            { "path": "/me", "actorType": "user", "actorId": { "header": "X-User" } },
+            { "path": "/me", "actorType": "user", "actorId": { "query": "user" } },
+        ]));
+        let headers = [("x-user".to_string(), "alice".to_string())];
+        assert_eq!(actor_id(&table, &request("GET", "localhost", "/me", "", &headers)), Some("alice".to_string()));
+        assert_eq!(actor_id(&table, &request("GET", "localhost", "/me", "a=1&user=bob", &[])), Some("bob".to_string()));
+        assert_eq!(actor_id(&table, &request("GET", "localhost", "/me", "", &[])), None);
+    }
+
+    #[test]
+    fn test_host_and_methods() {
+        let table = table(json!([
+            {
+                "host": "*.example.com",
+                "path": "/{id}",
+                "methods": ["POST"],
+                "actorType": "a",
+                "actorId": { "segment": "id" },
+            },
+        ]));
+        assert!(actor_id(&table, &request("post", "api.example.com:8080", "/x", "", &[])).is_some());
+        assert!(actor_id(&table, &request("GET", "api.example.com", "/x", "", &[])).is_none());
+        assert!(actor_id(&table, &request("POST", "example.org", "/x", "", &[])).is_none());
+    }
+
+    #[test]
+    fn test_first_match_wins() {
+        let table = table(json!([
+            { "path": "/a/{id}", "actorType": "first", "actorId": { "segment": "id" } },
+            { "path": "/*", "actorType": "second", "actorId": { "query": "id" } },
+        ]));
+        let target = table.resolve(&request("GET", "localhost", "/a/1", "id=2", &[])).unwrap();
+        assert_eq!(target.actor_type, "first");
+        let target = table.resolve(&request("GET", "localhost", "/b/1", "id=2", &[])).unwrap();
+        assert_eq!(target.actor_type, "second");
+    }
+
+    #[test]
+    fn test_invalid_routes_are_rejected() {
+        let invalid = |routes: Value| RouteTable::new(serde_json::from_value(routes).unwrap()).is_err();
+        assert!(invalid(json!([{ "path": "rooms/{id}", "actorType": "a", "actorId": { "segment": "id" } }])));
+        assert!(invalid(json!([{ "path": "/*/{id}", "actorType": "a", "actorId": { "segment": "id" } }])));
+        assert!(invalid(json!([{ "path": "/room-{id}", "actorType": "a", "actorId": { "segment": "id" } }])));
+        assert!(invalid(json!([{ "path": "/rooms/{room}", "actorType": "a", "actorId": { "segment": "id" } }])));
+    }
+}
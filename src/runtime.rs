@@ -0,0 +1,841 @@
+//! Runs actors on a fixed number of worker threads.
+//!
+//! Isolates are `!Send`, so every worker owns its actors for their whole
+//! life: it runs a single threaded executor and a `Supervisor`, and is only
+//! ever talked to through a channel. Actors are assigned to workers by a
+//! stable hash of their ID, so all messages for an actor land on the same
+//! thread. Deploys and registry changes are broadcast to every worker, each
+//! of which keeps its own copy of the registry. Topics are shared by all
+//! workers, and publishing wakes them up to deliver to their subscribers.
+//! The events of a WebSocket go to the worker of the actor it is connected
+//! to, in the order they happened.
+//!
+//! A child may live on another worker than its parent. Workers forward
+//! what their supervisor asks of other workers, e.g. stopping the children
+//! of a stopped parent, over a second channel, see `Remote`.
+
+use crate::actor::ActorId;
+use crate::dead_letter::DeadLetters;
+use crate::events::{event_channel, EventReceiver, EventSender};
+use crate::golem_isolate::GolemSnapshot;
+use crate::message::Message;
+use crate::registry::{stable_hash, Registry, RegistryError, Version};
+use crate::replies::{Registration, Replies, Reply, ReplySender, SharedReplies};
+use crate::routes::RouteTarget;
+use crate::sockets::{SocketEvent, SocketFrame, SocketId, SocketSender, CLOSE_GOING_AWAY, CLOSE_TRY_AGAIN_LATER};
+use crate::streams::BodyReceiver;
+use crate::supervisor::{Remote, SendError, SupervisionPolicy, Supervisor};
+use crate::topics::Topics;
+use deno_core::ErrBox;
+use futures::channel::oneshot;
+use futures::future::poll_fn;
+use serde_json::Value;
+use std::future::Future;
+use std::io;
+use std::pin::Pin;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Arc;
+use std::task::Poll;
+use std::thread;
+use std::time::{Duration, Instant};
+use tokio::sync::mpsc;
+use tokio::time::Delay;
+
+pub type RegistryUpdate = Arc<dyn Fn(&mut Registry) -> Result<(), RegistryError> + Send + Sync>;
+
+enum Command {
+    Deploy {
+        actor_type: String,
+        snapshot: GolemSnapshot,
+        reply: oneshot::Sender<Version>,
+    },
+    UpdateRegistry {
+        update: RegistryUpdate,
+        reply: oneshot::Sender<Result<(), RegistryError>>,
+    },
+    Spawn {
+        id: ActorId,
+        actor_type: String,
+        policy: SupervisionPolicy,
+        parent: Option<ActorId>,
+        reply: oneshot::Sender<Result<(), ErrBox>>,
+    },
+    /// Sent to the worker of `parent` when `child` was spawned on another.
+    Adopt {
+        parent: ActorId,
+        child: ActorId,
+    },
+    Send {
+        id: ActorId,
+        msg: Message,
+        reply: oneshot::Sender<Result<Message, SendError>>,
+    },
+    Ask {
+        id: ActorId,
+        msg: Message,
+        idempotency_key: Option<String>,
+        deadline: Instant,
+        reply: ReplySender,
+    },
+    Serve {
+        id: ActorId,
+        route: Option<RouteTarget>,
+        request: Value,
+        body: BodyReceiver,
+        deadline: Instant,
+        reply: ReplySender,
+    },
+    Tell {
+        id: ActorId,
+        msg: Message,
+    },
+    OpenSocket {
+        id: ActorId,
+        route: Option<RouteTarget>,
+        socket_id: SocketId,
+        request: Value,
+        sender: SocketSender,
+        reply: oneshot::Sender<Result<(), SendError>>,
+    },
+    SocketMessage {
+        id: ActorId,
+        socket_id: SocketId,
+        msg: Message,
+    },
+    SocketClosed {
+        id: ActorId,
+        socket_id: SocketId,
+    },
+    GetState {
+        id: ActorId,
+        reply: oneshot::Sender<Result<Message, SendError>>,
+    },
+    Listen {
+        id: ActorId,
+        listener: EventSender,
+        reply: oneshot::Sender<Result<(), SendError>>,
+    },
+    Stop {
+        id: ActorId,
+    },
+}
+
+/// Why a worker woke up.
+enum Wakeup {
+    Command(Command),
+    Remote(Remote),
+    Deliveries,
+    /// The backoff of a restarting actor has elapsed.
+    Restart,
+    Shutdown,
+}
+
+struct Worker {
+    commands: mpsc::UnboundedSender<Command>,
+    thread: thread::JoinHandle<()>,
+}
+
+fn worker_index(id: &str, worker_count: usize) -> usize {
+    stable_hash(id) as usize % worker_count
+}
+
+/// The channels workers forward `Remote`s to each other on. They are kept
+/// apart from the commands, so that a worker stops once the runtime drops
+/// its command channel.
+#[derive(Clone)]
+struct Peers {
+    remotes: Vec<mpsc::UnboundedSender<Remote>>,
+}
+
+impl Peers {
+    fn forward(&self, remote: Remote) {
+        let index = worker_index(remote.target(), self.remotes.len());
+        // A worker that has stopped has no actors left to act on.
+        self.remotes[index].send(remote).ok();
+    }
+}
+
+pub struct Runtime {
+    workers: Vec<Worker>,
+    next_socket_id: AtomicU64,
+}
+
+impl Runtime {
+    pub fn start(worker_count: usize, dead_letters: DeadLetters, topics: Topics) -> Result<Self, ErrBox> {
+        assert!(worker_count > 0);
+        let mut workers = Vec::with_capacity(worker_count);
+        let (remotes, remote_receivers): (Vec<_>, Vec<_>) = (0..worker_count).map(|_| mpsc::unbounded_channel()).unzip();
+        let peers = Peers { remotes };
+        let replies = Replies::shared();
+
+        for (index, remote_receiver) in remote_receivers.into_iter().enumerate() {
+            let (commands, receiver) = mpsc::unbounded_channel();
+            let dead_letters = dead_letters.clone();
+            let topics = topics.clone();
+            let replies = replies.clone();
+            let peers = peers.clone();
+            let thread = thread::Builder::new()
+                .name(format!("golem-worker-{}", index))
+                .spawn(move || run_worker(receiver, remote_receiver, peers, dead_letters, topics, replies))?;
+            workers.push(Worker { commands, thread });
+        }
+
+        Ok(Self {
+            workers,
+            next_socket_id: AtomicU64::new(1),
+        })
+    }
+
+    pub fn worker_count(&self) -> usize {
+        self.workers.len()
+    }
+
+    fn worker_for(&self, id: &str) -> &Worker {
+        &self.workers[worker_index(id, self.workers.len())]
+    }
+
+    fn dispatch(worker: &Worker, command: Command) -> Result<(), ErrBox> {
+        worker.commands.send(command).map_err(|_| worker_gone())
+    }
+
+    pub async fn deploy(&self, actor_type: &str, snapshot: GolemSnapshot) -> Result<Version, ErrBox> {
+        let mut version = 0;
+        for worker in self.workers.iter() {
+            let (reply, response) = oneshot::channel();
+            Self::dispatch(worker, Command::Deploy {
+                actor_type: actor_type.to_string(),
+                snapshot: snapshot.clone(),
+                reply,
+            })?;
+            version = response.await.map_err(|_| worker_gone())?;
+        }
+        Ok(version)
+    }
+
+    /// Applies the same change, e.g. a rollout or a pin, to the registry of
+    /// every worker.
+    pub async fn update_registry<F>(&self, update: F) -> Result<Result<(), RegistryError>, ErrBox>
+        where
+            F: Fn(&mut Registry) -> Result<(), RegistryError> + Send + Sync + 'static,
+    {
+        let update: RegistryUpdate = Arc::new(update);
+        let mut result = Ok(());
+        for worker in self.workers.iter() {
+            let (reply, response) = oneshot::channel();
+            Self::dispatch(worker, Command::UpdateRegistry { update: update.clone(), reply })?;
+            result = response.await.map_err(|_| worker_gone())?;
+        }
+        Ok(result)
+    }
+
+    pub async fn spawn(
+        &self,
+        id: &str,
+        actor_type: &str,
+        policy: SupervisionPolicy,
+        parent: Option<ActorId>,
+    ) -> Result<(), ErrBox> {
+        let (reply, response) = oneshot::channel();
+        let worker = self.worker_for(id);
+        Self::dispatch(worker, Command::Spawn {
+            id: id.to_string(),
+            actor_type: actor_type.to_string(),
+            policy,
+            parent: parent.clone(),
+            reply,
+        })?;
+        response.await.map_err(|_| worker_gone())??;
+
+        // Sent before returning, so that the parent's worker knows about the
+        // child before any later command for the parent.
+        if let Some(parent) = parent {
+            let parent_worker = self.worker_for(&parent);
+            if !std::ptr::eq(parent_worker, worker) {
+                Self::dispatch(parent_worker, Command::Adopt { parent, child: id.to_string() })?;
+            }
+        }
+        Ok(())
+    }
+
+    /// Delivers a message and waits for the actor's response.
+    pub async fn send(&self, id: &str, msg: Message) -> Result<Message, SendError> {
+        let (reply, response) = oneshot::channel();
+        let command = Command::Send { id: id.to_string(), msg, reply };
+        Self::dispatch(self.worker_for(id), command).map_err(SendError::Failed)?;
+        response.await.map_err(|_| SendError::Failed(worker_gone()))?
+    }
+
+    /// Delivers a message and waits up to `timeout` for the actor's reply,
+    /// which it may defer past the end of the invocation with `ctx.defer`
+    /// and send later with `ctx.reply`, or from any actor with `ctx.replyTo`.
+    /// A message sent again with the same `idempotency_key` is not
+    /// processed twice, see `crate::replies`. The timeout does not apply
+    /// to reading a streamed reply.
+    pub async fn ask(
+        &self,
+        id: &str,
+        msg: Message,
+        idempotency_key: Option<String>,
+        timeout: Duration,
+    ) -> Result<Reply, SendError> {
+        let (reply, response) = oneshot::channel();
+        let command = Command::Ask {
+            id: id.to_string(),
+            msg,
+            idempotency_key,
+            deadline: Instant::now() + timeout,
+            reply,
+        };
+        Self::dispatch(self.worker_for(id), command).map_err(SendError::Failed)?;
+        match tokio::time::timeout(timeout, response).await {
+            Ok(result) => result.map_err(|_| SendError::Failed(worker_gone()))?,
+            Err(_) => Err(SendError::TimedOut),
+        }
+    }
+
+    /// Passes an HTTP request to the actor's `fetch` export and waits up to
+    /// `timeout` for its response. `request` holds the method, URL and
+    /// headers, while the body is streamed through `body`. With the
+    /// `route` the request came through, an actor of another type than the
+    /// route's is not found, and one that does not exist is spawned if the
+    /// route allows it, see `crate::routes`.
+    pub async fn serve(
+        &self,
+        id: &str,
+        route: Option<&RouteTarget>,
+        request: Value,
+        body: BodyReceiver,
+        timeout: Duration,
+    ) -> Result<Reply, SendError> {
+        let (reply, response) = oneshot::channel();
+        let command = Command::Serve {
+            id: id.to_string(),
+            route: route.cloned(),
+            request,
+            body,
+            deadline: Instant::now() + timeout,
+            reply,
+        };
+        Self::dispatch(self.worker_for(id), command).map_err(SendError::Failed)?;
+        match tokio::time::timeout(timeout, response).await {
+            Ok(result) => result.map_err(|_| SendError::Failed(worker_gone()))?,
+            Err(_) => Err(SendError::TimedOut),
+        }
+    }
+
+    /// Queues a message in the actor's mailbox without waiting for it.
+    pub fn tell(&self, id: &str, msg: Message) -> Result<(), ErrBox> {
+        Self::dispatch(self.worker_for(id), Command::Tell { id: id.to_string(), msg })
+    }
+
+    /// Connects a WebSocket to an actor, which is told about it with an
+    /// `open` event whose message is `request`, the method, URL and headers
+    /// of the upgrade request. The actor refuses the connection by throwing.
+    /// Frames for the client are queued on `sender`. With a `route`, the
+    /// actor is found or spawned as in `serve`.
+    pub async fn open_socket(
+        &self,
+        id: &str,
+        route: Option<&RouteTarget>,
+        request: Value,
+        sender: SocketSender,
+    ) -> Result<SocketId, SendError> {
+        let socket_id = self.next_socket_id.fetch_add(1, Ordering::Relaxed);
+        let (reply, response) = oneshot::channel();
+        let command = Command::OpenSocket {
+            id: id.to_string(),
+            route: route.cloned(),
+            socket_id,
+            request,
+            sender,
+            reply,
+        };
+        Self::dispatch(self.worker_for(id), command).map_err(SendError::Failed)?;
+        response.await.map_err(|_| SendError::Failed(worker_gone()))??;
+        Ok(socket_id)
+    }
+
+    /// Passes a message the client sent to the actor, without waiting for
+    /// it to be processed.
+    pub fn socket_message(&self, id: &str, socket_id: SocketId, msg: Message) -> Result<(), ErrBox> {
+        let command = Command::SocketMessage { id: id.to_string(), socket_id, msg };
+        Self::dispatch(self.worker_for(id), command)
+    }
+
+    /// Tells the actor that the client has gone away.
+    pub fn socket_closed(&self, id: &str, socket_id: SocketId) -> Result<(), ErrBox> {
+        Self::dispatch(self.worker_for(id), Command::SocketClosed { id: id.to_string(), socket_id })
+    }
+
+    pub async fn get_state(&self, id: &str) -> Result<Message, SendError> {
+        let (reply, response) = oneshot::channel();
+        Self::dispatch(self.worker_for(id), Command::GetState { id: id.to_string(), reply }).map_err(SendError::Failed)?;
+        response.await.map_err(|_| SendError::Failed(worker_gone()))?
+    }
+
+    /// Streams the actor's state changes and emitted events, see
+    /// `crate::events`. The stream ends when the actor is stopped.
+    pub async fn listen(&self, id: &str) -> Result<EventReceiver, SendError> {
+        let (listener, events) = event_channel();
+        let (reply, response) = oneshot::channel();
+        let command = Command::Listen { id: id.to_string(), listener, reply };
+        Self::dispatch(self.worker_for(id), command).map_err(SendError::Failed)?;
+        response.await.map_err(|_| SendError::Failed(worker_gone()))??;
+        Ok(events)
+    }
+
+    pub fn stop(&self, id: &str) -> Result<(), ErrBox> {
+        Self::dispatch(self.worker_for(id), Command::Stop { id: id.to_string() })
+    }
+
+    /// Stops accepting commands and waits for the workers to finish the
+    /// ones already queued.
+    pub fn shutdown(self) {
+        for worker in self.workers {
+            drop(worker.commands);
+            worker.thread.join().ok();
+        }
+    }
+}
+
+fn worker_gone() -> ErrBox {
+    ErrBox::from(io::Error::new(io::ErrorKind::BrokenPipe, "the worker thread has stopped"))
+}
+
+fn run_worker(
+    commands: mpsc::UnboundedReceiver<Command>,
+    remotes: mpsc::UnboundedReceiver<Remote>,
+    peers: Peers,
+    dead_letters: DeadLetters,
+    topics: Topics,
+    replies: SharedReplies,
+) {
+    let mut runtime = tokio::runtime::Builder::new()
+        .basic_scheduler()
+        .enable_all()
+        .build()
+        .expect("failed to build the worker's executor");
+    let local = tokio::task::LocalSet::new();
+
+    local.block_on(&mut runtime, worker_loop(commands, remotes, peers, Supervisor::new(dead_letters, topics, replies)));
+}
+
+async fn worker_loop(
+    mut commands: mpsc::UnboundedReceiver<Command>,
+    mut remotes: mpsc::UnboundedReceiver<Remote>,
+    peers: Peers,
+    mut supervisor: Supervisor,
+) {
+    let mut restart_timer: Option<(Instant, Delay)> = None;
+    loop {
+        restart_timer = match (supervisor.next_restart(), restart_timer) {
+            (Some(restart_at), Some((armed_at, timer))) if armed_at == restart_at => Some((armed_at, timer)),
+            (Some(restart_at), _) => Some((restart_at, tokio::time::delay_until(restart_at.into()))),
+            (None, _) => None,
+        };
+
+        let wakeup = poll_fn(|cx| {
+            supervisor.poll_isolates(cx);
+            if let Some((_, timer)) = restart_timer.as_mut() {
+                if Pin::new(timer).poll(cx).is_ready() {
+                    return Poll::Ready(Wakeup::Restart);
+                }
+            }
+            match commands.poll_recv(cx) {
+                Poll::Ready(Some(command)) => return Poll::Ready(Wakeup::Command(command)),
+                Poll::Ready(None) => return Poll::Ready(Wakeup::Shutdown),
+                Poll::Pending => {}
+            }
+            if let Poll::Ready(Some(remote)) = remotes.poll_recv(cx) {
+                return Poll::Ready(Wakeup::Remote(remote));
+            }
+            supervisor.poll_deliveries(cx).map(|()| Wakeup::Deliveries)
+        }).await;
+
+        match wakeup {
+            Wakeup::Command(command) => handle_command(&mut supervisor, command),
+            Wakeup::Remote(remote) => supervisor.handle_remote(remote),
+            Wakeup::Deliveries | Wakeup::Restart => {}
+            Wakeup::Shutdown => break,
+        }
+        supervisor.process_mailboxes();
+        for remote in supervisor.take_remote() {
+            peers.forward(remote);
+        }
+        supervisor.state().borrow().replies.lock().unwrap().expire(Instant::now());
+    }
+}
+
+fn handle_command(supervisor: &mut Supervisor, command: Command) {
+    match command {
+        Command::Deploy { actor_type, snapshot, reply } => {
+            reply.send(supervisor.deploy(&actor_type, snapshot)).ok();
+        }
+        Command::UpdateRegistry { update, reply } => {
+            reply.send(supervisor.update_registry(|registry| update(registry))).ok();
+        }
+        Command::Spawn { id, actor_type, policy, parent, reply } => {
+            reply.send(supervisor.spawn(id, &actor_type, policy, parent)).ok();
+        }
+        Command::Adopt { parent, child } => supervisor.adopt(&parent, child),
+        Command::Send { id, msg, reply } => {
+            reply.send(supervisor.send(&id, &msg)).ok();
+        }
+        Command::Ask { id, msg, idempotency_key, deadline, reply } => {
+            let key = idempotency_key.map(|key| (id.clone(), key));
+            let replies = supervisor.state().borrow().replies.clone();
+            let registration = replies.lock().unwrap().register(reply, deadline, key);
+            let reply_id = match registration {
+                Registration::New(reply_id) => reply_id,
+                Registration::Joined | Registration::Answered => return,
+            };
+            let result = supervisor.ask(&id, &msg, reply_id);
+            let mut replies = replies.lock().unwrap();
+            match result {
+                Ok(response) => replies.complete(reply_id, response),
+                Err(error) => replies.fail(reply_id, error),
+            }
+        }
+        Command::Serve { id, route, request, body, deadline, reply } => {
+            let replies = supervisor.state().borrow().replies.clone();
+            let registration = replies.lock().unwrap().register(reply, deadline, None);
+            let reply_id = match registration {
+                Registration::New(reply_id) => reply_id,
+                Registration::Joined | Registration::Answered => return,
+            };
+            let result = match route {
+                Some(route) => spawn_routed(supervisor, &id, &route),
+                None => Ok(()),
+            };
+            let result = result.and_then(|()| supervisor.serve(&id, &request, body, reply_id));
+            let mut replies = replies.lock().unwrap();
+            match result {
+                Ok(response) => replies.complete(reply_id, Message::Json(response)),
+                Err(error) => replies.fail(reply_id, error),
+            }
+        }
+        Command::Tell { id, msg } => supervisor.tell(&id, msg),
+        Command::OpenSocket { id, route, socket_id, request, sender, reply } => {
+            let result = match route {
+                Some(route) => spawn_routed(supervisor, &id, &route),
+                None => Ok(()),
+            };
+            // Registered first, so that the actor can already send to it.
+            supervisor.state().borrow_mut().sockets.open(&id, socket_id, sender);
+            let result = result.and_then(|()| supervisor.socket_event(&id, socket_id, SocketEvent::Open, &Message::Json(request)));
+            if result.is_err() {
+                supervisor.state().borrow_mut().sockets.disconnect(socket_id);
+            }
+            reply.send(result.map(|_| ())).ok();
+        }
+        Command::SocketMessage { id, socket_id, msg } => {
+            if !supervisor.state().borrow().sockets.contains(socket_id) {
+                return;
+            }
+            let close = match supervisor.socket_event(&id, socket_id, SocketEvent::Message, &msg) {
+                Ok(_) | Err(SendError::Failed(_)) => return,
+                Err(SendError::Unavailable { .. }) => (CLOSE_TRY_AGAIN_LATER, "the actor is restarting"),
+                Err(_) => (CLOSE_GOING_AWAY, "the actor has gone away"),
+            };
+            let (code, reason) = close;
+            let frame = SocketFrame::Close { code, reason: reason.to_string() };
+            supervisor.state().borrow_mut().sockets.send(socket_id, frame);
+        }
+        Command::SocketClosed { id, socket_id } => {
+            if supervisor.state().borrow_mut().sockets.disconnect(socket_id) {
+                supervisor.socket_event(&id, socket_id, SocketEvent::Close, &Message::default()).ok();
+            }
+        }
+        Command::GetState { id, reply } => {
+            reply.send(supervisor.actor_state(&id)).ok();
+        }
+        Command::Listen { id, listener, reply } => {
+            reply.send(supervisor.listen(&id, listener)).ok();
+        }
+        Command::Stop { id } => supervisor.stop(&id),
+    }
+}
+
+/// Makes sure a request routed to an actor of the route's type reaches one,
+/// spawning it with the default policy if it does not exist yet and the
+/// route's spawn budget allows it.
+fn spawn_routed(supervisor: &mut Supervisor, id: &str, route: &RouteTarget) -> Result<(), SendError> {
+    match supervisor.actor_type(id) {
+        Some(existing) if existing == route.actor_type => return Ok(()),
+        Some(_) => return Err(SendError::NotFound),
+        None => {}
+    }
+    let budget = match route.spawn.as_ref() {
+        Some(budget) => budget,
+        None => return Err(SendError::NotFound),
+    };
+    if !budget.take() {
+        warn!("a route for actors of type {} has spawned as many as it may", route.actor_type);
+        return Err(SendError::NotFound);
+    }
+    supervisor
+        .spawn(id.to_string(), &route.actor_type, SupervisionPolicy::default(), None)
+        .map_err(|error| {
+            budget.give_back();
+            SendError::Failed(error)
+        })
+}
+
+/// The stores, actor state and runtimes that tests across the crate are
+/// built on.
+#[cfg(test)]
+pub mod testing {
+    use super::*;
+    use crate::dead_letter::DeadLetterStore;
+    use crate::state::State;
+    use crate::topics::{self, TopicStore};
+
+    pub fn dead_letters() -> DeadLetters {
+        DeadLetterStore::shared(10)
+    }
+
+    /// A topic store that dead-letters into `dead_letters`.
+    pub fn topic_store(dead_letters: &DeadLetters) -> Topics {
+        TopicStore::shared(topics::DEFAULT_BACKLOG, dead_letters.clone())
+    }
+
+    /// The state of an actor that is not part of a runtime.
+    pub fn state() -> State {
+        State::new(topic_store(&dead_letters()), Replies::shared()).unwrap()
+    }
+
+    pub fn start(worker_count: usize) -> Runtime {
+        start_with(worker_count, dead_letters())
+    }
+
+    pub fn start_with(worker_count: usize, dead_letters: DeadLetters) -> Runtime {
+        let topics = topic_store(&dead_letters);
+        Runtime::start(worker_count, dead_letters, topics).unwrap()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::testing::{self, start, start_with};
+    use super::*;
+    use crate::golem_isolate::GolemIsolate;
+    use crate::supervisor::{Backoff, Directive};
+    use deno_core::Script;
+    use bytes::Bytes;
+    use futures::{SinkExt, StreamExt};
+
+    const COUNTER: &str = "function init() { return 0; }\n\
+                           function main(state, msg) { if (msg.fail) { throw new Error('boom'); } return msg.set; }";
+
+    /// An ID of an actor that lives on another worker than `id`.
+    fn elsewhere(runtime: &Runtime, id: &str) -> String {
+        let worker = worker_index(id, runtime.worker_count());
+        (0..)
+            .map(|i| format!("{}-{}", id, i))
+            .find(|other| worker_index(other, runtime.worker_count()) != worker)
+            .unwrap()
+    }
+
+    /// Waits for the actor's state to satisfy `done`, giving up after a
+    /// few seconds.
+    async fn wait_for_state<F>(runtime: &Runtime, id: &str, done: F) -> Result<Message, SendError>
+        where
+            F: Fn(&Result<Message, SendError>) -> bool,
+    {
+        for _ in 0..100 {
+            let state = runtime.get_state(id).await;
+            if done(&state) {
+                return state;
+            }
+            tokio::time::delay_for(Duration::from_millis(20)).await;
+        }
+        runtime.get_state(id).await
+    }
+
+    fn snapshot(source: &str) -> GolemSnapshot {
+        GolemIsolate::try_create_snapshot(Script { source, filename: "actor.js" }).unwrap()
+    }
+
+    #[tokio::test]
+    async fn test_queued_message_delivered_after_backoff() {
+        let runtime = start(1);
+        runtime.deploy("counter", snapshot(COUNTER)).await.unwrap();
+        let policy = SupervisionPolicy {
+            directive: Directive::Restart,
+            backoff: Backoff { initial: Duration::from_millis(50), ..Backoff::default() },
+            max_attempts: 1,
+            ..SupervisionPolicy::default()
+        };
+        runtime.spawn("a", "counter", policy, None).await.unwrap();
+
+        runtime.tell("a", Message::Json(json!({ "fail": true }))).unwrap();
+        runtime.tell("a", Message::Json(json!({ "set": 5 }))).unwrap();
+        tokio::time::delay_for(Duration::from_millis(300)).await;
+
+        // Reading the state restarts the actor too, but would not deliver
+        // the queued message before answering.
+        assert_eq!(runtime.get_state("a").await.unwrap(), Message::Json(json!(5)));
+        runtime.shutdown();
+    }
+
+    #[tokio::test]
+    async fn test_redrive_reaches_actor_on_another_worker() {
+        let dead_letters = testing::dead_letters();
+        let runtime = start_with(2, dead_letters.clone());
+        let source = "function init() { return { ready: false, count: 0 }; }\n\
+                      function main(state, msg) {\n\
+                          if (msg.ready) { state.ready = true; }\n\
+                          if (msg.add) { if (!state.ready) { throw new Error('not ready'); } state.count += msg.add; }\n\
+                          return state;\n\
+                      }";
+        runtime.deploy("counter", snapshot(source)).await.unwrap();
+        let policy = SupervisionPolicy { directive: Directive::Resume, max_attempts: 1, ..SupervisionPolicy::default() };
+        runtime.spawn("a", "counter", policy, None).await.unwrap();
+
+        runtime.tell("a", Message::Json(json!({ "add": 1 }))).unwrap();
+        runtime.tell("a", Message::Json(json!({ "ready": true }))).unwrap();
+        runtime.get_state("a").await.unwrap();
+        let letter = dead_letters.lock().unwrap().list(Some("a"))[0].id;
+        assert!(dead_letters.lock().unwrap().redrive(letter));
+
+        // Wakes the other worker, which takes the redrive.
+        runtime.tell(&elsewhere(&runtime, "a"), Message::Json(json!({}))).unwrap();
+        let state = wait_for_state(&runtime, "a", |state| match state {
+            Ok(Message::Json(state)) => state["count"] == json!(1),
+            _ => false,
+        }).await;
+        assert_eq!(state.unwrap(), Message::Json(json!({ "ready": true, "count": 1 })));
+        runtime.shutdown();
+    }
+
+    #[tokio::test]
+    async fn test_stopping_parent_stops_child_on_another_worker() {
+        let runtime = start(2);
+        runtime.deploy("counter", snapshot(COUNTER)).await.unwrap();
+        let child = elsewhere(&runtime, "parent");
+        runtime.spawn("parent", "counter", SupervisionPolicy::default(), None).await.unwrap();
+        runtime.spawn(&child, "counter", SupervisionPolicy::default(), Some("parent".to_string())).await.unwrap();
+
+        runtime.stop("parent").unwrap();
+        let state = wait_for_state(&runtime, &child, |state| matches!(state, Err(SendError::NotFound))).await;
+        assert!(matches!(state, Err(SendError::NotFound)));
+        runtime.shutdown();
+    }
+
+    #[tokio::test]
+    async fn test_reply_to_handle_from_another_worker() {
+        let runtime = start(2);
+        let source = "function init() { return {}; }\n\
+                      function main(state, msg, ctx) {\n\
+                          if (msg.subscribe) { ctx.subscribe('handles'); }\n\
+                          else if (ctx.topic) { ctx.replyTo(msg.handle, msg.value * 2); }\n\
+                          else { ctx.publish('handles', { handle: ctx.defer(), value: msg.value }); }\n\
+                          return state;\n\
+                      }";
+        runtime.deploy("relay", snapshot(source)).await.unwrap();
+        let answerer = elsewhere(&runtime, "asker");
+        runtime.spawn("asker", "relay", SupervisionPolicy::default(), None).await.unwrap();
+        runtime.spawn(&answerer, "relay", SupervisionPolicy::default(), None).await.unwrap();
+        runtime.send(&answerer, Message::Json(json!({ "subscribe": true }))).await.unwrap();
+
+        let reply = runtime.ask("asker", Message::Json(json!({ "value": 21 })), None, Duration::from_secs(5)).await;
+        assert!(matches!(reply, Ok(Reply::Value(value)) if value == json!(42)));
+        runtime.shutdown();
+    }
+
+    #[tokio::test]
+    async fn test_binary_messages_through_mailbox_and_topics() {
+        let runtime = start(1);
+        let source = "function init() { return {}; }\n\
+                      function main(state, msg, ctx) {\n\
+                          if (msg instanceof Uint8Array) { state[ctx.topic || 'mailbox'] = msg.length; }\n\
+                          else if (msg.subscribe) { ctx.subscribe('bytes'); }\n\
+                          else { ctx.publish('bytes', new Uint8Array(msg.publish)); }\n\
+                          return state;\n\
+                      }";
+        runtime.deploy("bytes", snapshot(source)).await.unwrap();
+        runtime.spawn("a", "bytes", SupervisionPolicy::default(), None).await.unwrap();
+        runtime.send("a", Message::Json(json!({ "subscribe": true }))).await.unwrap();
+
+        runtime.tell("a", Message::Binary(Bytes::from_static(b"abc"))).unwrap();
+        runtime.send("a", Message::Json(json!({ "publish": 2 }))).await.unwrap();
+        let state = wait_for_state(&runtime, "a", |state| match state {
+            Ok(Message::Json(state)) => state.as_object().map_or(false, |state| state.len() == 2),
+            _ => false,
+        }).await;
+        assert_eq!(state.unwrap(), Message::Json(json!({ "mailbox": 3, "bytes": 2 })));
+        runtime.shutdown();
+    }
+
+    #[tokio::test]
+    async fn test_published_map_reaches_subscriber() {
+        let runtime = start(1);
+        let source = "function init() { return {}; }\n\
+                      function main(state, msg, ctx) {\n\
+                          if (msg.subscribe) { ctx.subscribe('maps'); }\n\
+                          else if (ctx.topic) { state.received = msg instanceof Map && msg.get('n') === 2n; }\n\
+                          else { ctx.publish('maps', new Map([['n', 2n]])); }\n\
+                          return state;\n\
+                      }";
+        runtime.deploy("maps", snapshot(source)).await.unwrap();
+        runtime.spawn("a", "maps", SupervisionPolicy::default(), None).await.unwrap();
+        runtime.send("a", Message::Json(json!({ "subscribe": true }))).await.unwrap();
+
+        runtime.send("a", Message::Json(json!({}))).await.unwrap();
+        let state = wait_for_state(&runtime, "a", |state| match state {
+            Ok(Message::Json(state)) => state.get("received").is_some(),
+            _ => false,
+        }).await;
+        assert_eq!(state.unwrap(), Message::Json(json!({ "received": true })));
+        runtime.shutdown();
+    }
+
+    #[tokio::test]
+    async fn test_reply_discarded_when_main_throws() {
+        let runtime = start(1);
+        let source = "function init() { return 0; }\n\
+                      function main(state, msg, ctx) {\n\
+                          ctx.reply(msg.value);\n\
+                          if (msg.fail) { throw new Error('boom'); }\n\
+                          return state;\n\
+                      }";
+        runtime.deploy("replier", snapshot(source)).await.unwrap();
+        let policy = SupervisionPolicy { directive: Directive::Resume, ..SupervisionPolicy::default() };
+        runtime.spawn("a", "replier", policy, None).await.unwrap();
+        let key = Some("once".to_string());
+
+        let msg = Message::Json(json!({ "value": "first", "fail": true }));
+        let reply = runtime.ask("a", msg, key.clone(), Duration::from_secs(5)).await;
+        assert!(matches!(reply, Err(SendError::Failed(_))));
+
+        // The failed invocation was not remembered, so the retry runs.
+        let msg = Message::Json(json!({ "value": "second" }));
+        let reply = runtime.ask("a", msg, key, Duration::from_secs(5)).await;
+        assert!(matches!(reply, Ok(Reply::Value(value)) if value == json!("second")));
+        runtime.shutdown();
+    }
+
+    #[tokio::test]
+    async fn test_isolate_woken_by_async_op() {
+        let runtime = start(1);
+        let source = "function main(state) { return state; }\n\
+                      async function fetch(state, request) { return new Response((await request.text()).toUpperCase()); }";
+        runtime.deploy("echo", snapshot(source)).await.unwrap();
+        runtime.spawn("a", "echo", SupervisionPolicy::default(), None).await.unwrap();
+
+        // The body arrives after fetch has started waiting for it.
+        let (mut sender, body) = futures::channel::mpsc::channel(1);
+        tokio::spawn(async move {
+            tokio::time::delay_for(Duration::from_millis(50)).await;
+            sender.send(Bytes::from_static(b"hello")).await.unwrap();
+        });
+        let request = json!({ "method": "POST", "url": "http://localhost/", "headers": [] });
+        let response = match runtime.serve("a", None, request, body, Duration::from_secs(5)).await {
+            Ok(Reply::Response(response)) => response,
+            _ => panic!("expected a response"),
+        };
+        let chunks: Vec<Bytes> = response.body.unwrap().collect().await;
+        assert_eq!(chunks.concat(), b"HELLO".to_vec());
+        runtime.shutdown();
+    }
+}
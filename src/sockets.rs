@@ -0,0 +1,202 @@
+//! WebSocket connections attached to actors.
+//!
+//! The HTTP layer accepts the upgrade and keeps the connection, while the
+//! worker owning the actor keeps the sending end of a channel to it. Every
+//! message the client sends is passed to `main` with `ctx.socket` set, text
+//! as a string and binary as a `Uint8Array`, as are the opening and the
+//! closing of the connection. Frames the actor sends with `ctx.socket.send`
+//! are queued on the channel, which is bounded: a client that does not keep
+//! up is disconnected rather than buffered for.
+//!
+//! Like published messages, frames sent while `main` runs are held back
+//! until it returns, see `crate::transaction`.
+
+use crate::actor::ActorId;
+use crate::op_error::OpError;
+use bytes::Bytes;
+use futures::channel::mpsc;
+use std::collections::HashMap;
+
+pub type SocketId = u64;
+
+/// The frames queued for a connection.
+pub const SOCKET_CAPACITY: usize = 64;
+
+/// Close codes, see RFC 6455 section 7.4.1.
+pub const CLOSE_NORMAL: u16 = 1000;
+pub const CLOSE_GOING_AWAY: u16 = 1001;
+pub const CLOSE_INVALID_PAYLOAD: u16 = 1007;
+pub const CLOSE_TOO_BIG: u16 = 1009;
+pub const CLOSE_TRY_AGAIN_LATER: u16 = 1013;
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum SocketFrame {
+    Text(String),
+    Binary(Bytes),
+    Pong(Bytes),
+    Close { code: u16, reason: String },
+}
+
+pub type SocketSender = mpsc::Sender<SocketFrame>;
+
+pub type SocketReceiver = mpsc::Receiver<SocketFrame>;
+
+/// Creates the channel frames reach a connection through. The connection
+/// ends once the receiver ends.
+pub fn socket_channel() -> (SocketSender, SocketReceiver) {
+    mpsc::channel(SOCKET_CAPACITY)
+}
+
+/// Why a socket event was passed to `main`.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum SocketEvent {
+    Open,
+    Message,
+    Close,
+}
+
+impl SocketEvent {
+    pub fn name(self) -> &'static str {
+        match self {
+            SocketEvent::Open => "open",
+            SocketEvent::Message => "message",
+            SocketEvent::Close => "close",
+        }
+    }
+}
+
+struct Socket {
+    actor_id: ActorId,
+    sender: SocketSender,
+}
+
+/// The open connections of the actors owned by a worker.
+#[derive(Default)]
+pub struct Sockets {
+    sockets: HashMap<SocketId, Socket>,
+}
+
+fn not_connected(socket_id: SocketId) -> OpError {
+    OpError::not_found(format!("socket {} is not connected", socket_id))
+}
+
+impl Sockets {
+    pub fn open(&mut self, actor_id: &str, socket_id: SocketId, sender: SocketSender) {
+        let actor_id = actor_id.to_string();
+        self.sockets.insert(socket_id, Socket { actor_id, sender });
+    }
+
+    pub fn contains(&self, socket_id: SocketId) -> bool {
+        self.sockets.contains_key(&socket_id)
+    }
+
+    /// The connections of an actor, in no particular order.
+    pub fn list(&self, actor_id: &str) -> Vec<SocketId> {
+        self.sockets
+            .iter()
+            .filter(|(_, socket)| socket.actor_id == actor_id)
+            .map(|(socket_id, _)| *socket_id)
+            .collect()
+    }
+
+    /// Fails unless the socket is connected to the actor, which is how ops
+    /// check a socket ID passed by an actor.
+    pub fn check(&self, actor_id: &str, socket_id: SocketId) -> Result<(), OpError> {
+        match self.sockets.get(&socket_id) {
+            Some(socket) if socket.actor_id == actor_id => Ok(()),
+            _ => Err(not_connected(socket_id)),
+        }
+    }
+
+    /// Queues a frame for the client. Closing frames disconnect the socket.
+    /// A client whose queue is full is disconnected as well.
+    pub fn send(&mut self, socket_id: SocketId, frame: SocketFrame) {
+        let closing = match &frame {
+            SocketFrame::Close { .. } => true,
+            _ => false,
+        };
+        let socket = match self.sockets.get_mut(&socket_id) {
+            Some(socket) => socket,
+            None => return,
+        };
+        let sent = match socket.sender.try_send(frame) {
+            Ok(()) => true,
+            Err(error) => {
+                if error.is_full() {
+                    warn!("socket {} of actor {} is not keeping up, disconnecting it", socket_id, socket.actor_id);
+                }
+                false
+            }
+        };
+        if closing || !sent {
+            self.disconnect(socket_id);
+        }
+    }
+
+    /// Forgets a socket and ends its connection once the frames already
+    /// queued have been sent. Returns whether it was connected.
+    pub fn disconnect(&mut self, socket_id: SocketId) -> bool {
+        match self.sockets.remove(&socket_id) {
+            Some(mut socket) => {
+                socket.sender.close_channel();
+                true
+            }
+            None => false,
+        }
+    }
+
+    /// Closes the connections of a stopped actor.
+    pub fn remove_actor(&mut self, actor_id: &str) {
+        for socket_id in self.list(actor_id) {
+            let reason = "the actor was stopped".to_string();
+            self.send(socket_id, SocketFrame::Close { code: CLOSE_GOING_AWAY, reason });
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_frames_reach_the_connection() {
+        let mut sockets = Sockets::default();
+        let (sender, mut receiver) = socket_channel();
+        sockets.open("room", 1, sender);
+
+        assert!(sockets.check("room", 1).is_ok());
+        assert!(sockets.check("other", 1).is_err());
+        assert_eq!(sockets.list("room"), vec![1]);
+
+        sockets.send(1, SocketFrame::Text("hello".to_string()));
+        sockets.send(1, SocketFrame::Close { code: CLOSE_NORMAL, reason: String::new() });
+        assert!(!sockets.contains(1));
+
+        assert_eq!(receiver.try_next().unwrap(), Some(SocketFrame::Text("hello".to_string())));
+        assert!(matches!(receiver.try_next().unwrap(), Some(SocketFrame::Close { code: CLOSE_NORMAL, .. })));
+        assert_eq!(receiver.try_next().unwrap(), None);
+    }
+
+    #[test]
+    fn test_slow_clients_are_disconnected() {
+        let mut sockets = Sockets::default();
+        let (sender, _receiver) = socket_channel();
+        sockets.open("room", 1, sender);
+        for _ in 0..SOCKET_CAPACITY + 2 {
+            sockets.send(1, SocketFrame::Text("x".to_string()));
+        }
+        assert!(!sockets.contains(1));
+    }
+
+    #[test]
+    fn test_stopped_actors_lose_their_sockets() {
+        let mut sockets = Sockets::default();
+        let (first, _first) = socket_channel();
+        let (second, _second) = socket_channel();
+        sockets.open("a", 1, first);
+        sockets.open("b", 2, second);
+        sockets.remove_actor("a");
+        assert!(!sockets.contains(1));
+        assert!(sockets.contains(2));
+    }
+}
@@ -0,0 +1,199 @@
+//! Remaps the locations in a deno_core::JSError using the source maps
+//! produced when actor code was transpiled.
+use deno_core::JSError;
+use deno_core::JSStackFrame;
+use sourcemap::SourceMap;
+use std::collections::HashMap;
+
+/// Raw source maps keyed by the URL of the generated module.
+pub type SourceMaps = HashMap<String, Vec<u8>>;
+
+struct CachedMaps<'a> {
+    raw: &'a SourceMaps,
+    parsed: HashMap<String, Option<SourceMap>>,
+}
+
+impl<'a> CachedMaps<'a> {
+    fn new(raw: &'a SourceMaps) -> Self {
+        Self {
+            raw,
+            parsed: HashMap::new(),
+        }
+    }
+
+    fn get(&mut self, script_name: &str) -> Option<&SourceMap> {
+        let raw = self.raw;
+        self.parsed
+            .entry(script_name.to_string())
+            .or_insert_with(|| {
+                raw.get(script_name)
+                    .and_then(|data| SourceMap::from_slice(data).ok())
+            })
+            .as_ref()
+    }
+
+    /// V8 stack frames use 1-based lines and columns, whereas source maps
+    /// use 0-based positions for both.
+    fn original_position(&mut self, script_name: &str, line: i64, column: i64) -> Option<(String, i64, i64)> {
+        if line < 1 || column < 1 {
+            return None;
+        }
+        let source_map = self.get(script_name)?;
+        let token = source_map.lookup_token(line as u32 - 1, column as u32 - 1)?;
+        // The lookup falls back to the last token of an earlier line, which
+        // says nothing about this one.
+        if token.get_dst_line() != line as u32 - 1 {
+            return None;
+        }
+        let source = token.get_source().unwrap_or(script_name).to_string();
+        Some((source, token.get_src_line() as i64 + 1, token.get_src_col() as i64 + 1))
+    }
+
+    /// Maps the exclusive, 0-based end column of a range that starts at
+    /// `start` (the original position of its first character). Columns
+    /// inside a token are offset from the start of the token, as mappings
+    /// only mark where tokens begin. Ranges whose end falls in another
+    /// source or on another line than its start cannot be expressed in a
+    /// `JSError`, so they yield `None`.
+    fn original_end_column(&mut self, script_name: &str, line: i64, end_column: i64, start: &(String, i64, i64)) -> Option<i64> {
+        if line < 1 || end_column < 1 {
+            return None;
+        }
+        let source_map = self.get(script_name)?;
+        let last_column = end_column as u32 - 1;
+        let token = source_map.lookup_token(line as u32 - 1, last_column)?;
+        if token.get_dst_line() != line as u32 - 1 {
+            return None;
+        }
+        let source = token.get_source().unwrap_or(script_name);
+        let original_line = token.get_src_line() as i64 + 1;
+        if source != start.0 || original_line != start.1 {
+            return None;
+        }
+        Some(token.get_src_col() as i64 + (last_column - token.get_dst_col()) as i64 + 1)
+    }
+}
+
+/// Rewrites the locations of a `JSError` raised from transpiled code so
+/// that they point at the original TypeScript source.
+pub fn apply_source_maps(js_error: JSError, source_maps: &SourceMaps) -> JSError {
+    if source_maps.is_empty() {
+        return js_error;
+    }
+    let mut maps = CachedMaps::new(source_maps);
+
+    let frames: Vec<JSStackFrame> = js_error
+        .frames
+        .into_iter()
+        .map(|frame| {
+            match maps.original_position(&frame.script_name, frame.line_number, frame.column) {
+                Some((script_name, line_number, column)) => JSStackFrame {
+                    script_name,
+                    line_number,
+                    column,
+                    ..frame
+                },
+                None => frame,
+            }
+        })
+        .collect();
+
+    let (script_resource_name, line_number, start_column, end_column) =
+        match (&js_error.script_resource_name, js_error.line_number, js_error.start_column) {
+            (Some(name), Some(line), Some(column)) => {
+                match maps.original_position(name, line, column + 1) {
+                    Some(start) => {
+                        let end_column = js_error
+                            .end_column
+                            .and_then(|end| maps.original_end_column(name, line, end, &start));
+                        let (name, line, column) = start;
+                        (Some(name), Some(line), Some(column - 1), end_column)
+                    }
+                    None => (js_error.script_resource_name.clone(), Some(line), Some(column), js_error.end_column),
+                }
+            }
+            _ => (
+                js_error.script_resource_name.clone(),
+                js_error.line_number,
+                js_error.start_column,
+                js_error.end_column,
+            ),
+        };
+
+    JSError {
+        message: js_error.message,
+        source_line: js_error.source_line,
+        script_resource_name,
+        line_number,
+        start_column,
+        end_column,
+        frames,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use sourcemap::SourceMapBuilder;
+
+    const GENERATED: &str = "golem:///main.ts";
+
+    /// `function add(a: number, b: number)` on line 3 of the original is
+    /// emitted as `function add(a, b)` on line 1.
+    fn source_maps() -> SourceMaps {
+        let mut builder = SourceMapBuilder::new(None);
+        builder.add(0, 0, 2, 0, Some(GENERATED), None);
+        builder.add(0, 13, 2, 13, Some(GENERATED), None);
+        builder.add(0, 16, 2, 24, Some(GENERATED), None);
+        let mut raw = Vec::new();
+        builder.into_sourcemap().to_writer(&mut raw).unwrap();
+
+        let mut maps = SourceMaps::new();
+        maps.insert(GENERATED.to_string(), raw);
+        maps
+    }
+
+    fn frame(script_name: &str, line_number: i64, column: i64) -> JSStackFrame {
+        JSStackFrame {
+            line_number,
+            column,
+            script_name: script_name.to_string(),
+            function_name: "add".to_string(),
+            is_eval: false,
+            is_constructor: false,
+            is_async: false,
+        }
+    }
+
+    fn error(frames: Vec<JSStackFrame>) -> JSError {
+        JSError {
+            message: "Uncaught Error".to_string(),
+            source_line: None,
+            script_resource_name: Some(GENERATED.to_string()),
+            line_number: Some(1),
+            start_column: Some(16),
+            end_column: Some(17),
+            frames,
+        }
+    }
+
+    #[test]
+    fn test_remap_generated_position() {
+        let remapped = apply_source_maps(error(vec![frame(GENERATED, 1, 17)]), &source_maps());
+
+        assert_eq!(remapped.frames[0].script_name, GENERATED);
+        assert_eq!(remapped.frames[0].line_number, 3);
+        assert_eq!(remapped.frames[0].column, 25);
+        assert_eq!(remapped.line_number, Some(3));
+        assert_eq!(remapped.start_column, Some(24));
+        assert_eq!(remapped.end_column, Some(25));
+    }
+
+    #[test]
+    fn test_frame_without_mapping_is_kept() {
+        let unmapped = frame("golem:///lib/util.js", 4, 2);
+        let remapped = apply_source_maps(error(vec![unmapped.clone()]), &source_maps());
+
+        assert_eq!(remapped.frames[0], unmapped);
+    }
+}
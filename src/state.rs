@@ -26,6 +26,13 @@ use std::thread::JoinHandle;
 use std::time::Instant;
 use crate::dispatch_json::{json_op, JsonOp};
 use crate::dispatch_minimal::{MinimalOp, minimal_op};
+use crate::events::Events;
+use crate::replies::SharedReplies;
+use crate::sockets::Sockets;
+use crate::storage::Storage;
+use crate::topics::Topics;
+use crate::transaction::Transaction;
+use crate::vfs::Vfs;
 
 #[derive(Clone)]
 pub struct State(Rc<RefCell<StateInner>>);
@@ -44,6 +51,13 @@ pub struct StateInner {
     pub global_timer: GlobalTimer,
     pub start_time: Instant,
     pub seeded_rng: Option<StdRng>,
+    pub events: Events,
+    pub replies: SharedReplies,
+    pub sockets: Sockets,
+    pub storage: Storage,
+    pub topics: Topics,
+    pub transaction: Option<Transaction>,
+    pub vfs: Vfs,
 }
 
 impl State {
@@ -150,13 +164,20 @@ impl State {
 
 impl State {
     /// If `shared_permission` is None then permissions from globa state are used.
-    pub fn new() -> Result<Self, ErrBox> {
+    pub fn new(topics: Topics, replies: SharedReplies) -> Result<Self, ErrBox> {
         let seeded_rng = None;
 
         let state = Rc::new(RefCell::new(StateInner {
             global_timer: GlobalTimer::new(),
             start_time: Instant::now(),
             seeded_rng,
+            events: Events::default(),
+            replies,
+            sockets: Sockets::default(),
+            storage: Storage::new(),
+            topics,
+            transaction: None,
+            vfs: Vfs::default(),
         }));
 
         Ok(Self(state))
@@ -0,0 +1,181 @@
+//! Key-value storage of actors, exposed to handlers as `ctx.storage`.
+//!
+//! Unlike the `state` value, which is serialized in full for every message,
+//! entries are only read and written when a handler asks for them, so an
+//! actor can keep large datasets here. Writes made while `main` runs are
+//! buffered and only committed once it returns successfully. Writes made
+//! outside of an invocation, e.g. from lifecycle hooks or async callbacks,
+//! are applied immediately.
+//!
+//! Storage belongs to the actor ID: it survives restarts and hot swaps and
+//! is deleted when the actor is stopped.
+
+use crate::actor::ActorId;
+use serde_json::Value;
+use std::collections::{BTreeMap, HashMap};
+use std::ops::Bound;
+
+#[derive(Debug, Default)]
+pub struct ActorStorage {
+    entries: BTreeMap<String, Value>,
+    /// Writes of the invocation in progress, `None` values are deletes.
+    pending: Option<BTreeMap<String, Option<Value>>>,
+}
+
+impl ActorStorage {
+    pub fn get(&self, key: &str) -> Option<&Value> {
+        if let Some(write) = self.pending.as_ref().and_then(|pending| pending.get(key)) {
+            return write.as_ref();
+        }
+        self.entries.get(key)
+    }
+
+    pub fn put(&mut self, key: String, value: Value) {
+        match self.pending.as_mut() {
+            Some(pending) => {
+                pending.insert(key, Some(value));
+            }
+            None => {
+                self.entries.insert(key, value);
+            }
+        }
+    }
+
+    /// Returns whether the key existed.
+    pub fn delete(&mut self, key: &str) -> bool {
+        let existed = self.get(key).is_some();
+        match self.pending.as_mut() {
+            Some(pending) => {
+                pending.insert(key.to_string(), None);
+            }
+            None => {
+                self.entries.remove(key);
+            }
+        }
+        existed
+    }
+
+    /// The entries whose key starts with `prefix`, in key order.
+    pub fn list(&self, prefix: &str) -> Vec<(String, Value)> {
+        let mut entries: BTreeMap<&str, &Value> = self
+            .entries
+            .range::<str, _>((Bound::Included(prefix), Bound::Unbounded))
+            .take_while(|(key, _)| key.starts_with(prefix))
+            .map(|(key, value)| (key.as_str(), value))
+            .collect();
+
+        if let Some(pending) = self.pending.as_ref() {
+            let writes = pending
+                .range::<str, _>((Bound::Included(prefix), Bound::Unbounded))
+                .take_while(|(key, _)| key.starts_with(prefix));
+            for (key, write) in writes {
+                match write {
+                    Some(value) => entries.insert(key.as_str(), value),
+                    None => entries.remove(key.as_str()),
+                };
+            }
+        }
+
+        entries
+            .into_iter()
+            .map(|(key, value)| (key.to_string(), value.clone()))
+            .collect()
+    }
+
+    /// Starts buffering writes.
+    pub fn begin(&mut self) {
+        self.pending = Some(BTreeMap::new());
+    }
+
+    pub fn commit(&mut self) {
+        for (key, write) in self.pending.take().unwrap_or_default() {
+            match write {
+                Some(value) => self.entries.insert(key, value),
+                None => self.entries.remove(&key),
+            };
+        }
+    }
+
+    pub fn rollback(&mut self) {
+        self.pending = None;
+    }
+}
+
+/// The storage of all actors owned by a worker.
+#[derive(Debug, Default)]
+pub struct Storage {
+    actors: HashMap<ActorId, ActorStorage>,
+}
+
+impl Storage {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn actor(&mut self, actor_id: &str) -> &mut ActorStorage {
+        self.actors.entry(actor_id.to_string()).or_default()
+    }
+
+    pub fn remove(&mut self, actor_id: &str) {
+        self.actors.remove(actor_id);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_writes_outside_transaction() {
+        let mut storage = ActorStorage::default();
+        storage.put("a".to_string(), json!(1));
+        assert_eq!(storage.get("a"), Some(&json!(1)));
+        assert!(storage.delete("a"));
+        assert!(!storage.delete("a"));
+        assert_eq!(storage.get("a"), None);
+    }
+
+    #[test]
+    fn test_commit() {
+        let mut storage = ActorStorage::default();
+        storage.put("a".to_string(), json!(1));
+
+        storage.begin();
+        storage.put("b".to_string(), json!(2));
+        storage.delete("a");
+        assert_eq!(storage.get("a"), None);
+        assert_eq!(storage.get("b"), Some(&json!(2)));
+
+        storage.commit();
+        assert_eq!(storage.list(""), vec![("b".to_string(), json!(2))]);
+    }
+
+    #[test]
+    fn test_rollback() {
+        let mut storage = ActorStorage::default();
+        storage.put("a".to_string(), json!(1));
+
+        storage.begin();
+        storage.put("a".to_string(), json!(2));
+        storage.put("b".to_string(), json!(3));
+        storage.rollback();
+
+        assert_eq!(storage.get("a"), Some(&json!(1)));
+        assert_eq!(storage.get("b"), None);
+    }
+
+    #[test]
+    fn test_list_prefix() {
+        let mut storage = ActorStorage::default();
+        storage.put("user:1".to_string(), json!("a"));
+        storage.put("user:3".to_string(), json!("c"));
+        storage.put("order:1".to_string(), json!("x"));
+
+        storage.begin();
+        storage.put("user:2".to_string(), json!("b"));
+        storage.delete("user:3");
+
+        let keys: Vec<String> = storage.list("user:").into_iter().map(|(key, _)| key).collect();
+        assert_eq!(keys, vec!["user:1", "user:2"]);
+    }
+}
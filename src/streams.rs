@@ -0,0 +1,178 @@
+//! Bodies streamed by actors, e.g. the upload body of a `fetch` or a reply
+//! sent to the caller of `/actor/{id}` chunk by chunk.
+//!
+//! A stream is a resource the actor writes to with `op_write` and closes
+//! with `op_close`. Its readable end is taken by whatever the stream is
+//! passed to. The channel between both ends is bounded, so an actor writing
+//! faster than the body is consumed waits for the reader.
+//!
+//! Bodies streamed to the actor, such as that of an HTTP request, use the
+//! same channel the other way around and are read with `op_read`.
+
+use crate::op_error::OpError;
+use crate::resources::{ActorResource, Capability};
+use bytes::Bytes;
+use futures::channel::mpsc;
+use futures::sink::Sink;
+use futures::StreamExt;
+use std::cmp::min;
+use std::pin::Pin;
+use std::task::{Context, Poll};
+
+/// The chunks buffered between the actor and the reader.
+pub const CHANNEL_CAPACITY: usize = 16;
+
+/// The readable end of a stream, which ends when the actor closes it.
+pub type BodyReceiver = mpsc::Receiver<Bytes>;
+
+pub struct BodyStream {
+    sender: mpsc::Sender<Bytes>,
+    receiver: Option<BodyReceiver>,
+}
+
+impl BodyStream {
+    pub fn new() -> Self {
+        let (sender, receiver) = mpsc::channel(CHANNEL_CAPACITY);
+        Self {
+            sender,
+            receiver: Some(receiver),
+        }
+    }
+}
+
+impl Default for BodyStream {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+fn closed() -> OpError {
+    OpError::broken_pipe("the reader of the stream has gone away".to_string())
+}
+
+impl ActorResource for BodyStream {
+    fn capability(&self) -> Capability {
+        Capability::ActorStream
+    }
+
+    fn poll_write(&mut self, cx: &mut Context, buf: &[u8]) -> Poll<Result<usize, OpError>> {
+        let mut sender = Pin::new(&mut self.sender);
+        match sender.as_mut().poll_ready(cx) {
+            Poll::Ready(Ok(())) => {}
+            Poll::Ready(Err(_)) => return Poll::Ready(Err(closed())),
+            Poll::Pending => return Poll::Pending,
+        }
+        Poll::Ready(
+            sender
+                .start_send(Bytes::copy_from_slice(buf))
+                .map(|()| buf.len())
+                .map_err(|_| closed()),
+        )
+    }
+
+    /// Only succeeds while the channel has room, async writes wait for it.
+    fn write_sync(&mut self, buf: &[u8]) -> Result<usize, OpError> {
+        match self.sender.try_send(Bytes::copy_from_slice(buf)) {
+            Ok(()) => Ok(buf.len()),
+            Err(error) if error.is_full() => Err(OpError::busy("the stream is full, write to it asynchronously".to_string())),
+            Err(_) => Err(closed()),
+        }
+    }
+
+    fn take_body(&mut self) -> Option<BodyReceiver> {
+        self.receiver.take()
+    }
+}
+
+/// Creates a channel for a body streamed to an actor.
+pub fn body_channel() -> (mpsc::Sender<Bytes>, BodyReceiver) {
+    mpsc::channel(CHANNEL_CAPACITY)
+}
+
+/// A body streamed to the actor.
+pub struct IncomingBody {
+    receiver: BodyReceiver,
+    chunk: Bytes,
+}
+
+impl IncomingBody {
+    pub fn new(receiver: BodyReceiver) -> Self {
+        Self {
+            receiver,
+            chunk: Bytes::new(),
+        }
+    }
+}
+
+impl ActorResource for IncomingBody {
+    fn capability(&self) -> Capability {
+        Capability::ActorStream
+    }
+
+    /// Copies as much of the current chunk as fits into `buf`, a read of 0
+    /// bytes means the body ended.
+    fn poll_read(&mut self, cx: &mut Context, buf: &mut [u8]) -> Poll<Result<usize, OpError>> {
+        while self.chunk.is_empty() {
+            match self.receiver.poll_next_unpin(cx) {
+                Poll::Ready(Some(chunk)) => self.chunk = chunk,
+                Poll::Ready(None) => return Poll::Ready(Ok(0)),
+                Poll::Pending => return Poll::Pending,
+            }
+        }
+        let n = min(buf.len(), self.chunk.len());
+        buf[..n].copy_from_slice(&self.chunk.split_to(n));
+        Poll::Ready(Ok(n))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_chunks_reach_the_receiver() {
+        let mut stream = BodyStream::new();
+        let mut receiver = stream.take_body().unwrap();
+        assert!(stream.take_body().is_none());
+
+        stream.write_sync(b"hello").unwrap();
+        stream.write_sync(b"world").unwrap();
+        drop(stream);
+
+        assert_eq!(receiver.try_next().unwrap(), Some(Bytes::from_static(b"hello")));
+        assert_eq!(receiver.try_next().unwrap(), Some(Bytes::from_static(b"world")));
+        assert_eq!(receiver.try_next().unwrap(), None);
+    }
+
+    #[test]
+    fn test_sync_writes_fail_when_full() {
+        let mut stream = BodyStream::new();
+        let _receiver = stream.take_body().unwrap();
+        let results: Vec<_> = (0..CHANNEL_CAPACITY + 2).map(|_| stream.write_sync(b"x")).collect();
+        assert!(results.iter().any(Result::is_err));
+    }
+
+    #[test]
+    fn test_writes_fail_once_the_reader_is_gone() {
+        let mut stream = BodyStream::new();
+        drop(stream.take_body());
+        assert!(stream.write_sync(b"x").is_err());
+    }
+
+    #[test]
+    fn test_incoming_body_is_read_in_pieces() {
+        let (mut sender, receiver) = body_channel();
+        sender.try_send(Bytes::from_static(b"hello")).unwrap();
+        drop(sender);
+
+        let mut body = IncomingBody::new(receiver);
+        let waker = futures::task::noop_waker();
+        let mut cx = Context::from_waker(&waker);
+        let mut buf = [0; 3];
+        assert!(matches!(body.poll_read(&mut cx, &mut buf), Poll::Ready(Ok(3))));
+        assert_eq!(&buf, b"hel");
+        assert!(matches!(body.poll_read(&mut cx, &mut buf), Poll::Ready(Ok(2))));
+        assert_eq!(&buf[..2], b"lo");
+        assert!(matches!(body.poll_read(&mut cx, &mut buf), Poll::Ready(Ok(0))));
+    }
+}
@@ -0,0 +1,870 @@
+//! Erlang style supervision of actors.
+//!
+//! When a message makes `main` throw, the actor's policy decides what
+//! happens next:
+//!
+//! - `Resume` keeps the actor running with the state it had before the
+//!   failing message, which the failed invocation's transaction restored.
+//! - `Restart` replaces the isolate and recomputes the state with `init`,
+//!   after a backoff delay during which messages are refused.
+//! - `Stop` removes the actor, together with its children.
+//! - `Escalate` treats the failure as a failure of the parent actor.
+//!
+//! Restarts are limited to `max_restarts` within `within`. Exceeding that
+//! intensity escalates to the parent, or stops the actor if it has none.
+//!
+//! Actors are spawned from the version of their type that the registry
+//! resolves them to. When that changes, because of a deploy, rollout or
+//! rollback, an actor is hot-swapped onto the resolved version before its
+//! next message, see `Actor::hot_swap`.
+//!
+//! Messages sent with `tell` are queued in a bounded mailbox and retried
+//! up to `max_attempts` times. Messages that cannot be queued or delivered
+//! end up in the dead-letter store. Messages published to topics are
+//! delivered the same way from the actor's backlog in `crate::topics`.
+//!
+//! A supervisor only knows the actors of its worker, see `crate::runtime`.
+//! What concerns an actor of another worker, e.g. a child of a stopped
+//! parent, is collected as a `Remote` for the runtime to forward.
+
+use crate::actor::{Actor, ActorId};
+use crate::dead_letter::{DeadLetterReason, DeadLetters, ErrorInfo};
+use crate::events::{ActorEvent, EventSender};
+use crate::golem_isolate::{GolemSnapshot, NoFetchHandler};
+use crate::message::Message;
+use crate::registry::{Registry, RegistryError, Version};
+use crate::replies::{ReplyId, SharedReplies};
+use crate::sockets::{SocketEvent, SocketId};
+use crate::state::State;
+use crate::streams::BodyReceiver;
+use crate::topics::{Delivery, Topics};
+use deno_core::ErrBox;
+use futures::task::{self, ArcWake};
+use serde_json::Value;
+use std::collections::{HashMap, HashSet, VecDeque};
+use std::error::Error;
+use std::fmt;
+use std::sync::{Arc, Mutex};
+use std::task::{Context, Poll, Waker};
+use std::time::{Duration, Instant};
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Directive {
+    Resume,
+    Restart,
+    Stop,
+    Escalate,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Backoff {
+    pub initial: Duration,
+    pub max: Duration,
+    pub multiplier: u32,
+}
+
+impl Default for Backoff {
+    fn default() -> Self {
+        Self {
+            initial: Duration::from_millis(100),
+            max: Duration::from_secs(10),
+            multiplier: 2,
+        }
+    }
+}
+
+impl Backoff {
+    /// The delay before the `attempt`th consecutive restart, starting at 0.
+    pub fn delay(&self, attempt: u32) -> Duration {
+        let mut delay = self.initial;
+        for _ in 0..attempt {
+            delay = delay * self.multiplier;
+            if delay >= self.max {
+                return self.max;
+            }
+        }
+        delay
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct SupervisionPolicy {
+    pub directive: Directive,
+    pub max_restarts: usize,
+    pub within: Duration,
+    pub backoff: Backoff,
+    /// How often a queued message is delivered before it is dead-lettered.
+    pub max_attempts: u32,
+    pub mailbox_capacity: usize,
+}
+
+impl Default for SupervisionPolicy {
+    fn default() -> Self {
+        Self {
+            directive: Directive::Restart,
+            max_restarts: 3,
+            within: Duration::from_secs(5),
+            backoff: Backoff::default(),
+            max_attempts: 3,
+            mailbox_capacity: 1000,
+        }
+    }
+}
+
+struct Envelope {
+    message: Message,
+    attempts: u32,
+}
+
+/// Something a supervisor asks of the worker that owns another actor, see
+/// `Supervisor::take_remote`.
+#[derive(Debug, Clone, PartialEq)]
+pub enum Remote {
+    /// Queues a message, e.g. a redriven dead letter.
+    Tell { id: ActorId, msg: Message },
+    /// Stops a child of an actor that was stopped or restarted.
+    Stop { id: ActorId },
+    /// A child escalated its failure to its parent.
+    Escalate { parent: ActorId },
+}
+
+impl Remote {
+    /// The actor whose worker handles this.
+    pub fn target(&self) -> &str {
+        match self {
+            Remote::Tell { id, .. } | Remote::Stop { id } => id,
+            Remote::Escalate { parent } => parent,
+        }
+    }
+}
+
+/// What reached the actor and how, which determines its `ctx`.
+enum Origin<'a> {
+    Tell(&'a Message),
+    Ask(&'a Message, ReplyId),
+    Topic(&'a Delivery),
+    /// An HTTP request for the actor's `fetch` export.
+    Request(&'a Value, ReplyId, BodyReceiver),
+    Socket(&'a Message, SocketId, SocketEvent),
+}
+
+/// Remembers recent restarts to enforce the restart intensity.
+#[derive(Debug, Default)]
+pub struct RestartTracker {
+    restarts: VecDeque<Instant>,
+}
+
+impl RestartTracker {
+    /// Records a restart, returning its position among the restarts in the
+    /// current window, or `None` if the intensity has been exceeded.
+    pub fn record(&mut self, policy: &SupervisionPolicy, now: Instant) -> Option<u32> {
+        while let Some(oldest) = self.restarts.front() {
+            if now.duration_since(*oldest) >= policy.within {
+                self.restarts.pop_front();
+            } else {
+                break;
+            }
+        }
+
+        if self.restarts.len() >= policy.max_restarts {
+            return None;
+        }
+        self.restarts.push_back(now);
+        Some(self.restarts.len() as u32 - 1)
+    }
+}
+
+#[derive(Debug)]
+pub enum SendError {
+    NotFound,
+    /// The actor is waiting out its restart backoff.
+    Unavailable { retry_after: Duration },
+    /// The message failed. The actor's policy has already been applied.
+    Failed(ErrBox),
+    /// No reply arrived before the caller's deadline.
+    TimedOut,
+}
+
+impl Error for SendError {}
+
+impl fmt::Display for SendError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            SendError::NotFound => write!(f, "actor not found"),
+            SendError::Unavailable { retry_after } => {
+                write!(f, "actor is restarting, retry after {}ms", retry_after.as_millis())
+            }
+            SendError::Failed(e) => write!(f, "{}", e),
+            SendError::TimedOut => write!(f, "timed out waiting for a reply"),
+        }
+    }
+}
+
+/// An actor was spawned with the ID of one that is already running.
+#[derive(Debug)]
+pub struct ActorExists(pub ActorId);
+
+impl Error for ActorExists {}
+
+impl fmt::Display for ActorExists {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "actor '{}' already exists", self.0)
+    }
+}
+
+struct Supervised {
+    actor: Option<Actor>,
+    actor_type: String,
+    version: Version,
+    /// A version the actor could not be migrated to, so that the upgrade
+    /// is not attempted again for every message. Cleared by the next deploy
+    /// or registry change, which retries it.
+    failed_upgrade: Option<Version>,
+    policy: SupervisionPolicy,
+    parent: Option<ActorId>,
+    restarts: RestartTracker,
+    restart_at: Option<Instant>,
+    mailbox: VecDeque<Envelope>,
+    /// Passed to the actor's isolate when it is polled, see `IsolateWaker`.
+    waker: Waker,
+}
+
+/// The actors whose isolates have work to do since `poll_isolates` last
+/// polled them, and the worker to wake for them.
+#[derive(Default)]
+struct Woken {
+    ids: HashSet<ActorId>,
+    worker: Option<Waker>,
+}
+
+/// Wakes the worker on behalf of one actor's isolate, e.g. when one of its
+/// async ops completes.
+struct IsolateWaker {
+    id: ActorId,
+    woken: Arc<Mutex<Woken>>,
+}
+
+impl ArcWake for IsolateWaker {
+    fn wake_by_ref(arc_self: &Arc<Self>) {
+        let mut woken = arc_self.woken.lock().unwrap();
+        woken.ids.insert(arc_self.id.clone());
+        if let Some(worker) = woken.worker.as_ref() {
+            worker.wake_by_ref();
+        }
+    }
+}
+
+pub struct Supervisor {
+    actors: HashMap<ActorId, Supervised>,
+    /// Children that live on other workers, by parent.
+    remote_children: HashMap<ActorId, Vec<ActorId>>,
+    outbox: Vec<Remote>,
+    woken: Arc<Mutex<Woken>>,
+    registry: Registry,
+    dead_letters: DeadLetters,
+    topics: Topics,
+    state: State,
+}
+
+impl Supervisor {
+    pub fn new(dead_letters: DeadLetters, topics: Topics, replies: SharedReplies) -> Self {
+        Self {
+            actors: HashMap::new(),
+            remote_children: HashMap::new(),
+            outbox: Vec::new(),
+            woken: Arc::new(Mutex::new(Woken::default())),
+            registry: Registry::new(),
+            dead_letters,
+            state: State::new(topics.clone(), replies).unwrap(),
+            topics,
+        }
+    }
+
+    /// The op state shared by the isolates of all supervised actors.
+    pub fn state(&self) -> &State {
+        &self.state
+    }
+
+    pub fn deploy(&mut self, actor_type: &str, snapshot: GolemSnapshot) -> Version {
+        self.retry_upgrades(Some(actor_type));
+        self.registry.deploy(actor_type, snapshot)
+    }
+
+    /// Applies an upload, rollout, pin or rollback of script versions.
+    /// Upgrades that failed before are attempted again afterwards.
+    pub fn update_registry<F>(&mut self, update: F) -> Result<(), RegistryError>
+        where
+            F: FnOnce(&mut Registry) -> Result<(), RegistryError>,
+    {
+        self.retry_upgrades(None);
+        update(&mut self.registry)
+    }
+
+    fn retry_upgrades(&mut self, actor_type: Option<&str>) {
+        for supervised in self.actors.values_mut() {
+            if actor_type.map_or(true, |actor_type| supervised.actor_type == actor_type) {
+                supervised.failed_upgrade = None;
+            }
+        }
+    }
+
+    /// Fails with `ActorExists` rather than replacing an actor that is
+    /// running with the same ID.
+    pub fn spawn(
+        &mut self,
+        id: ActorId,
+        actor_type: &str,
+        policy: SupervisionPolicy,
+        parent: Option<ActorId>,
+    ) -> Result<(), ErrBox> {
+        if self.actors.contains_key(&id) {
+            return Err(ErrBox::from(ActorExists(id)));
+        }
+        let (version, snapshot) = self.registry.resolve(actor_type, &id).ok_or_else(|| unknown_type(actor_type))?;
+        let actor = Actor::spawn(id.clone(), snapshot.clone(), &self.state)?;
+        let waker = task::waker(Arc::new(IsolateWaker { id: id.clone(), woken: self.woken.clone() }));
+        self.wake(&id);
+        self.actors.insert(id, Supervised {
+            actor: Some(actor),
+            actor_type: actor_type.to_string(),
+            version,
+            failed_upgrade: None,
+            policy,
+            parent,
+            restarts: RestartTracker::default(),
+            restart_at: None,
+            mailbox: VecDeque::new(),
+            waker,
+        });
+        Ok(())
+    }
+
+    /// Queues a message for delivery by `process_mailboxes`.
+    pub fn tell(&mut self, id: &str, msg: Message) {
+        let supervised = match self.actors.get_mut(id) {
+            Some(supervised) => supervised,
+            None => {
+                self.dead_letter(id, msg, DeadLetterReason::ActorNotFound, None, 0);
+                return;
+            }
+        };
+        if supervised.mailbox.len() >= supervised.policy.mailbox_capacity {
+            self.dead_letter(id, msg, DeadLetterReason::MailboxOverflow, None, 0);
+            return;
+        }
+        supervised.mailbox.push_back(Envelope { message: msg, attempts: 0 });
+    }
+
+    /// Restarts the actors whose backoff has elapsed, then delivers queued
+    /// messages and topic backlogs until they are empty or waiting for their
+    /// actor to restart.
+    pub fn process_mailboxes(&mut self) {
+        self.process_restarts(Instant::now());
+        self.process_redrives();
+
+        let ids: Vec<ActorId> = self.actors.keys().cloned().collect();
+        for id in ids {
+            self.process_mailbox(&id);
+            self.process_deliveries(&id);
+        }
+    }
+
+    fn process_mailbox(&mut self, id: &str) {
+        loop {
+            let mut envelope = match self.actors.get_mut(id).and_then(|s| s.mailbox.pop_front()) {
+                Some(envelope) => envelope,
+                None => return,
+            };
+
+            envelope.attempts += 1;
+            match self.send(id, &envelope.message) {
+                Ok(_) => {}
+                Err(SendError::NotFound) => {
+                    self.dead_letter(id, envelope.message, DeadLetterReason::ActorNotFound, None, envelope.attempts);
+                }
+                Err(SendError::Unavailable { .. }) => {
+                    envelope.attempts -= 1;
+                    self.actors.get_mut(id).unwrap().mailbox.push_front(envelope);
+                    return;
+                }
+                Err(SendError::TimedOut) => unreachable!("send does not wait for replies"),
+                Err(SendError::Failed(error)) => {
+                    let max_attempts = match self.actors.get(id) {
+                        Some(supervised) => supervised.policy.max_attempts,
+                        // The failure stopped the actor and dead-lettered its mailbox.
+                        None => 0,
+                    };
+                    if envelope.attempts >= max_attempts {
+                        let error = Some(ErrorInfo::from_err_box(&error));
+                        self.dead_letter(id, envelope.message, DeadLetterReason::RetriesExhausted, error, envelope.attempts);
+                    } else {
+                        self.actors.get_mut(id).unwrap().mailbox.push_front(envelope);
+                    }
+                }
+            }
+        }
+    }
+
+    /// Delivers the actor's topic backlog. A message is only removed from
+    /// the backlog once it was processed, or dead-lettered after failing
+    /// `max_attempts` times.
+    fn process_deliveries(&mut self, id: &str) {
+        loop {
+            let delivery = match self.topics.lock().unwrap().next(id) {
+                Some(delivery) => delivery,
+                None => return,
+            };
+
+            match self.send_at(id, Origin::Topic(&delivery), Instant::now()) {
+                Ok(_) => self.topics.lock().unwrap().acknowledge(id, delivery.id),
+                // The actor is restarting, or was stopped and its backlog
+                // dead-lettered.
+                Err(SendError::NotFound) | Err(SendError::Unavailable { .. }) => return,
+                Err(SendError::TimedOut) => unreachable!("deliveries do not wait for replies"),
+                Err(SendError::Failed(error)) => {
+                    let max_attempts = match self.actors.get(id) {
+                        Some(supervised) => supervised.policy.max_attempts,
+                        None => return,
+                    };
+                    let mut topics = self.topics.lock().unwrap();
+                    let attempts = topics.record_failure(id, delivery.id);
+                    if attempts >= max_attempts {
+                        topics.acknowledge(id, delivery.id);
+                        drop(topics);
+                        let error = Some(ErrorInfo::from_err_box(&error));
+                        self.dead_letter(id, delivery.message, DeadLetterReason::RetriesExhausted, error, attempts);
+                    }
+                }
+            }
+        }
+    }
+
+    /// Ready when one of the actors that can currently receive messages has
+    /// topic messages waiting, otherwise wakes the task on the next publish.
+    pub fn poll_deliveries(&self, cx: &mut Context) -> Poll<()> {
+        let mut topics = self.topics.lock().unwrap();
+        let available = |id: &str| {
+            self.actors
+                .get(id)
+                .map_or(false, |supervised| supervised.restart_at.is_none())
+        };
+        if topics.has_pending(available) {
+            return Poll::Ready(());
+        }
+        topics.watch(cx.waker());
+        Poll::Pending
+    }
+
+    /// Drives the pending ops of the actors whose isolates were woken, or
+    /// invoked, since they were last polled. An exception thrown from an
+    /// async callback counts as a failure of its actor.
+    pub fn poll_isolates(&mut self, cx: &mut Context) {
+        let ids = {
+            let mut woken = self.woken.lock().unwrap();
+            if !woken.worker.as_ref().map_or(false, |worker| worker.will_wake(cx.waker())) {
+                woken.worker = Some(cx.waker().clone());
+            }
+            std::mem::replace(&mut woken.ids, HashSet::new())
+        };
+
+        let mut failed = Vec::new();
+        for id in ids {
+            let supervised = match self.actors.get_mut(&id) {
+                Some(supervised) => supervised,
+                None => continue,
+            };
+            let mut isolate_cx = Context::from_waker(&supervised.waker);
+            if let Some(actor) = supervised.actor.as_mut() {
+                if let Poll::Ready(Err(error)) = actor.isolate().poll(&mut isolate_cx) {
+                    error!("actor {} failed: {}", id, error);
+                    failed.push(id);
+                }
+            }
+        }
+
+        let now = Instant::now();
+        for id in failed {
+            self.handle_failure(&id, now);
+        }
+    }
+
+    /// Marks the actor's isolate to be polled, after an invocation that may
+    /// have started async ops.
+    fn wake(&self, id: &str) {
+        self.woken.lock().unwrap().ids.insert(id.to_string());
+    }
+
+    /// The earliest time at which a restarting actor is due to be started.
+    pub fn next_restart(&self) -> Option<Instant> {
+        self.actors.values().filter_map(|supervised| supervised.restart_at).min()
+    }
+
+    fn process_restarts(&mut self, now: Instant) {
+        let due: Vec<ActorId> = self
+            .actors
+            .iter()
+            .filter(|(_, supervised)| supervised.restart_at.map_or(false, |restart_at| restart_at <= now))
+            .map(|(id, _)| id.clone())
+            .collect();
+        for id in due {
+            // A failed restart has already been handled by the policy.
+            self.ensure_started(&id, now).ok();
+        }
+    }
+
+    /// Redriven dead letters are taken by whichever worker gets to them
+    /// first, and sent on to the worker of their actor.
+    fn process_redrives(&mut self) {
+        let redrives = self.dead_letters.lock().unwrap().take_redrives();
+        for letter in redrives {
+            self.outbox.push(Remote::Tell { id: letter.actor_id, msg: letter.message });
+        }
+    }
+
+    /// Records `child`, which lives on another worker, as a child of the
+    /// local actor `parent`.
+    pub fn adopt(&mut self, parent: &str, child: ActorId) {
+        if self.actors.contains_key(parent) {
+            self.remote_children.entry(parent.to_string()).or_default().push(child);
+        }
+    }
+
+    /// What has to be done by other workers, see `Remote`.
+    pub fn take_remote(&mut self) -> Vec<Remote> {
+        std::mem::replace(&mut self.outbox, Vec::new())
+    }
+
+    pub fn handle_remote(&mut self, remote: Remote) {
+        match remote {
+            Remote::Tell { id, msg } => self.tell(&id, msg),
+            Remote::Stop { id } => self.stop(&id),
+            Remote::Escalate { parent } => self.handle_failure(&parent, Instant::now()),
+        }
+    }
+
+    fn dead_letter(&self, id: &str, msg: Message, reason: DeadLetterReason, error: Option<ErrorInfo>, attempts: u32) {
+        self.dead_letters
+            .lock()
+            .unwrap()
+            .record(id, msg, reason, error, attempts);
+    }
+
+    pub fn contains(&self, id: &str) -> bool {
+        self.actors.contains_key(id)
+    }
+
+    pub fn actor_type(&self, id: &str) -> Option<&str> {
+        self.actors.get(id).map(|supervised| supervised.actor_type.as_str())
+    }
+
+    pub fn send(&mut self, id: &str, msg: &Message) -> Result<Message, SendError> {
+        self.send_at(id, Origin::Tell(msg), Instant::now())
+    }
+
+    /// Delivers a message whose caller waits under `reply_id` in the
+    /// replies table, see `crate::replies`.
+    pub fn ask(&mut self, id: &str, msg: &Message, reply_id: ReplyId) -> Result<Message, SendError> {
+        self.send_at(id, Origin::Ask(msg, reply_id), Instant::now())
+    }
+
+    /// Passes an HTTP request to the actor's `fetch` export, whose caller
+    /// waits under `reply_id`. Requests to an actor without `fetch` fail
+    /// with `NoFetchHandler`, which does not count as a failure of the
+    /// actor.
+    pub fn serve(&mut self, id: &str, request: &Value, body: BodyReceiver, reply_id: ReplyId) -> Result<Value, SendError> {
+        self.send_at(id, Origin::Request(request, reply_id, body), Instant::now()).map(Message::into_json)
+    }
+
+    /// Passes the opening, a message or the closing of a WebSocket to the
+    /// actor it is connected to, see `crate::sockets`.
+    pub fn socket_event(&mut self, id: &str, socket_id: SocketId, event: SocketEvent, msg: &Message) -> Result<Message, SendError> {
+        self.send_at(id, Origin::Socket(msg, socket_id, event), Instant::now())
+    }
+
+    /// The actor's current state. Restarting actors have none until their
+    /// backoff has elapsed.
+    pub fn actor_state(&mut self, id: &str) -> Result<Message, SendError> {
+        self.ensure_started(id, Instant::now())?;
+        let supervised = self.actors.get_mut(id).ok_or(SendError::NotFound)?;
+        supervised.actor.as_mut().unwrap().isolate().get_state().map_err(SendError::Failed)
+    }
+
+    /// Adds a listener for the actor's events, see `crate::events`, which
+    /// starts with the current state unless the actor is restarting.
+    pub fn listen(&mut self, id: &str, mut listener: EventSender) -> Result<(), SendError> {
+        let supervised = self.actors.get_mut(id).ok_or(SendError::NotFound)?;
+        if let Some(actor) = supervised.actor.as_mut() {
+            match actor.isolate().get_state() {
+                Ok(state) => {
+                    listener.try_send(ActorEvent::State(state.into_json())).ok();
+                }
+                Err(error) => warn!("actor {} state could not be sent to a new listener: {}", id, error),
+            }
+        }
+        self.state.borrow_mut().events.listen(id, listener);
+        Ok(())
+    }
+
+    fn send_at(&mut self, id: &str, origin: Origin, now: Instant) -> Result<Message, SendError> {
+        self.ensure_started(id, now)?;
+        self.ensure_upgraded(id);
+        self.wake(id);
+
+        let supervised = self.actors.get_mut(id).ok_or(SendError::NotFound)?;
+        let actor = supervised.actor.as_mut().unwrap();
+
+        let result = match origin {
+            Origin::Tell(msg) => actor.send(msg),
+            Origin::Ask(msg, reply_id) => actor.ask(msg, reply_id),
+            Origin::Topic(delivery) => actor.deliver(delivery),
+            Origin::Request(..) if !actor.isolate().serves_http() => {
+                return Err(SendError::Failed(ErrBox::from(NoFetchHandler)));
+            }
+            Origin::Request(request, reply_id, body) => actor.serve(request, body, reply_id).map(Message::Json),
+            Origin::Socket(msg, socket_id, event) => actor.socket_event(msg, socket_id, event),
+        };
+
+        match result {
+            Ok(response) => Ok(response),
+            Err(error) => {
+                error!("actor {} failed: {}", id, error);
+                self.handle_failure(id, now);
+                Err(SendError::Failed(error))
+            }
+        }
+    }
+
+    /// Performs a pending restart once its backoff has elapsed.
+    fn ensure_started(&mut self, id: &str, now: Instant) -> Result<(), SendError> {
+        let supervised = self.actors.get_mut(id).ok_or(SendError::NotFound)?;
+        let restart_at = match supervised.restart_at {
+            Some(restart_at) => restart_at,
+            None => return Ok(()),
+        };
+        if now < restart_at {
+            return Err(SendError::Unavailable { retry_after: restart_at - now });
+        }
+
+        supervised.restart_at = None;
+        // Restarting recomputes the state with init, so the actor can start
+        // over on its resolved version without a migration.
+        let (version, snapshot) = self.registry.resolve(&supervised.actor_type, id).unwrap();
+        match Actor::spawn(id.to_string(), snapshot.clone(), &self.state) {
+            Ok(actor) => {
+                supervised.actor = Some(actor);
+                supervised.version = version;
+                self.wake(id);
+                Ok(())
+            }
+            Err(error) => {
+                error!("actor {} failed to restart: {}", id, error);
+                self.handle_failure(id, now);
+                Err(SendError::Failed(error))
+            }
+        }
+    }
+
+    /// Hot-swaps the actor onto the version the registry resolves it to.
+    /// Should the migration fail, the actor keeps running on its previous
+    /// version.
+    fn ensure_upgraded(&mut self, id: &str) {
+        let supervised = match self.actors.get_mut(id) {
+            Some(supervised) => supervised,
+            None => return,
+        };
+        let (version, snapshot) = match self.registry.resolve(&supervised.actor_type, id) {
+            Some(current) => current,
+            None => return,
+        };
+        if version == supervised.version || supervised.failed_upgrade == Some(version) {
+            return;
+        }
+
+        let actor = supervised.actor.as_mut().unwrap();
+        match actor.hot_swap(snapshot.clone(), supervised.version) {
+            Ok(()) => {
+                info!("actor {} moved from version {} to {}", id, supervised.version, version);
+                supervised.version = version;
+                supervised.failed_upgrade = None;
+            }
+            Err(error) => {
+                error!("actor {} failed to move to version {}: {}", id, version, error);
+                supervised.failed_upgrade = Some(version);
+            }
+        }
+    }
+
+    fn handle_failure(&mut self, id: &str, now: Instant) {
+        let supervised = match self.actors.get_mut(id) {
+            Some(supervised) => supervised,
+            None => return,
+        };
+
+        let directive = match supervised.policy.directive {
+            Directive::Restart => match supervised.restarts.record(&supervised.policy, now) {
+                Some(attempt) => {
+                    supervised.actor = None;
+                    supervised.restart_at = Some(now + supervised.policy.backoff.delay(attempt));
+                    self.stop_children(id);
+                    return;
+                }
+                None => {
+                    warn!("actor {} exceeded its restart intensity", id);
+                    Directive::Escalate
+                }
+            },
+            directive => directive,
+        };
+
+        match directive {
+            Directive::Resume | Directive::Restart => {}
+            Directive::Stop => self.stop(id),
+            Directive::Escalate => match supervised.parent.clone() {
+                Some(parent) => {
+                    self.stop(id);
+                    if self.actors.contains_key(&parent) {
+                        self.handle_failure(&parent, now);
+                    } else {
+                        self.outbox.push(Remote::Escalate { parent });
+                    }
+                }
+                None => self.stop(id),
+            },
+        }
+    }
+
+    /// Stops an actor and all of its descendants. Stopped actors lose their
+    /// subscriptions, storage, files, sockets, event listeners and
+    /// idempotency keys.
+    pub fn stop(&mut self, id: &str) {
+        self.stop_children(id);
+        if let Some(supervised) = self.actors.remove(id) {
+            for envelope in supervised.mailbox {
+                self.dead_letter(id, envelope.message, DeadLetterReason::ActorStopped, None, envelope.attempts);
+            }
+            let backlog = self.topics.lock().unwrap().remove_subscriber(id);
+            for delivery in backlog {
+                self.dead_letter(id, delivery.message, DeadLetterReason::ActorStopped, None, delivery.attempts);
+            }
+            if let Some(actor) = supervised.actor {
+                if let Err(error) = actor.stop() {
+                    warn!("onStop of actor {} failed: {}", id, error);
+                }
+            }
+            let mut state = self.state.borrow_mut();
+            state.storage.remove(id);
+            state.vfs.remove(id);
+            state.sockets.remove_actor(id);
+            state.events.remove_actor(id);
+            state.replies.lock().unwrap().remove_actor(id);
+        }
+    }
+
+    fn stop_children(&mut self, id: &str) {
+        let children: Vec<ActorId> = self
+            .actors
+            .iter()
+            .filter(|(_, supervised)| supervised.parent.as_deref() == Some(id))
+            .map(|(child, _)| child.clone())
+            .collect();
+        for child in children {
+            self.stop(&child);
+        }
+        for child in self.remote_children.remove(id).unwrap_or_default() {
+            self.outbox.push(Remote::Stop { id: child });
+        }
+    }
+}
+
+fn unknown_type(actor_type: &str) -> ErrBox {
+    ErrBox::from(std::io::Error::new(
+        std::io::ErrorKind::NotFound,
+        format!("actor type '{}' has not been deployed", actor_type),
+    ))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::golem_isolate::GolemIsolate;
+    use crate::replies::Replies;
+    use crate::runtime::testing;
+    use deno_core::Script;
+
+    const COUNTER: &str = "function init() { return { count: 0 }; }\n\
+                           function main(state, msg) { state.count += 1; return state; }";
+
+    /// Migrating fails while the count is zero.
+    const MIGRATED: &str = "function main(state) { return state; }\n\
+                            function migrate(state) { if (!state.count) { throw new Error('empty'); } return { ...state, migrated: true }; }";
+
+    fn supervisor() -> Supervisor {
+        let dead_letters = testing::dead_letters();
+        let topics = testing::topic_store(&dead_letters);
+        Supervisor::new(dead_letters, topics, Replies::shared())
+    }
+
+    fn snapshot(source: &str) -> GolemSnapshot {
+        GolemIsolate::try_create_snapshot(Script { source, filename: "actor.js" }).unwrap()
+    }
+
+    #[test]
+    fn test_failed_upgrade_retried_after_registry_change() {
+        let mut supervisor = supervisor();
+        supervisor.deploy("counter", snapshot(COUNTER));
+        supervisor.spawn("a".to_string(), "counter", SupervisionPolicy::default(), None).unwrap();
+        supervisor.deploy("counter", snapshot(MIGRATED));
+
+        let msg = Message::Json(json!({}));
+        // The migration fails, so the message is handled by the old version.
+        assert_eq!(supervisor.send("a", &msg).unwrap(), Message::Json(json!({ "count": 1 })));
+        // The failed version is not attempted again for every message.
+        assert_eq!(supervisor.send("a", &msg).unwrap(), Message::Json(json!({ "count": 2 })));
+
+        supervisor.update_registry(|_| Ok(())).unwrap();
+        assert_eq!(supervisor.send("a", &msg).unwrap(), Message::Json(json!({ "count": 2, "migrated": true })));
+    }
+
+    #[test]
+    fn test_spawn_keeps_existing_actor() {
+        let mut supervisor = supervisor();
+        supervisor.deploy("counter", snapshot(COUNTER));
+        supervisor.spawn("a".to_string(), "counter", SupervisionPolicy::default(), None).unwrap();
+        let msg = Message::Json(json!({}));
+        supervisor.send("a", &msg).unwrap();
+
+        let error = supervisor.spawn("a".to_string(), "counter", SupervisionPolicy::default(), None).unwrap_err();
+        assert!(error.downcast_ref::<ActorExists>().is_some());
+        assert_eq!(supervisor.send("a", &msg).unwrap(), Message::Json(json!({ "count": 2 })));
+    }
+
+
+    #[test]
+    fn test_backoff_delay() {
+        let backoff = Backoff {
+            initial: Duration::from_millis(100),
+            max: Duration::from_millis(500),
+            multiplier: 2,
+        };
+        assert_eq!(backoff.delay(0), Duration::from_millis(100));
+        assert_eq!(backoff.delay(1), Duration::from_millis(200));
+        assert_eq!(backoff.delay(2), Duration::from_millis(400));
+        assert_eq!(backoff.delay(3), Duration::from_millis(500));
+    }
+
+    #[test]
+    fn test_restart_intensity() {
+        let policy = SupervisionPolicy {
+            max_restarts: 2,
+            within: Duration::from_secs(1),
+            ..SupervisionPolicy::default()
+        };
+        let mut tracker = RestartTracker::default();
+        let start = Instant::now();
+
+        assert_eq!(tracker.record(&policy, start), Some(0));
+        assert_eq!(tracker.record(&policy, start + Duration::from_millis(10)), Some(1));
+        assert_eq!(tracker.record(&policy, start + Duration::from_millis(20)), None);
+        assert_eq!(tracker.record(&policy, start + Duration::from_millis(1005)), Some(1));
+    }
+}
@@ -0,0 +1,256 @@
+//! Topics that actors subscribe to with `ctx.subscribe(topic)`.
+//!
+//! Subscriptions belong to the actor ID rather than to a running isolate,
+//! so they survive restarts and passivation, and are only dropped when the
+//! actor is stopped. Every message published to a topic is appended to the
+//! backlog of each subscriber and stays there until the subscriber has
+//! processed it without throwing, which makes delivery at-least-once:
+//! handlers can recognize redeliveries by `ctx.deliveryId`.
+//!
+//! The store is shared by all workers. Publishing wakes the workers so that
+//! they deliver the backlogs of the actors they own.
+
+use crate::actor::ActorId;
+use crate::dead_letter::{DeadLetterReason, DeadLetters};
+use crate::message::Message;
+use std::collections::{BTreeMap, BTreeSet, HashMap, VecDeque};
+use std::sync::{Arc, Mutex};
+use std::task::Waker;
+
+/// The number of undelivered messages kept per subscriber, further messages
+/// are dead-lettered.
+pub const DEFAULT_BACKLOG: usize = 10_000;
+
+pub type Topics = Arc<Mutex<TopicStore>>;
+
+pub type DeliveryId = u64;
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct Delivery {
+    pub id: DeliveryId,
+    pub topic: String,
+    pub message: Message,
+    /// How often processing the message has failed so far.
+    pub attempts: u32,
+}
+
+pub struct TopicStore {
+    subscriptions: BTreeMap<String, BTreeSet<ActorId>>,
+    backlogs: HashMap<ActorId, VecDeque<Delivery>>,
+    backlog_capacity: usize,
+    next_id: DeliveryId,
+    dead_letters: DeadLetters,
+    watchers: Vec<Waker>,
+}
+
+impl TopicStore {
+    pub fn new(backlog_capacity: usize, dead_letters: DeadLetters) -> Self {
+        Self {
+            subscriptions: BTreeMap::new(),
+            backlogs: HashMap::new(),
+            backlog_capacity,
+            next_id: 1,
+            dead_letters,
+            watchers: Vec::new(),
+        }
+    }
+
+    pub fn shared(backlog_capacity: usize, dead_letters: DeadLetters) -> Topics {
+        Arc::new(Mutex::new(Self::new(backlog_capacity, dead_letters)))
+    }
+
+    /// Returns false if the actor was already subscribed.
+    pub fn subscribe(&mut self, topic: &str, actor_id: &str) -> bool {
+        self.subscriptions
+            .entry(topic.to_string())
+            .or_default()
+            .insert(actor_id.to_string())
+    }
+
+    /// Stops future deliveries. Messages already in the actor's backlog are
+    /// still delivered.
+    pub fn unsubscribe(&mut self, topic: &str, actor_id: &str) -> bool {
+        let subscribers = match self.subscriptions.get_mut(topic) {
+            Some(subscribers) => subscribers,
+            None => return false,
+        };
+        let removed = subscribers.remove(actor_id);
+        if subscribers.is_empty() {
+            self.subscriptions.remove(topic);
+        }
+        removed
+    }
+
+    pub fn subscribers(&self, topic: &str) -> Vec<ActorId> {
+        self.subscriptions
+            .get(topic)
+            .map(|subscribers| subscribers.iter().cloned().collect())
+            .unwrap_or_default()
+    }
+
+    pub fn subscriptions(&self, actor_id: &str) -> Vec<String> {
+        self.subscriptions
+            .iter()
+            .filter(|(_, subscribers)| subscribers.contains(actor_id))
+            .map(|(topic, _)| topic.clone())
+            .collect()
+    }
+
+    /// Queues `message` for every subscriber of `topic`, returning how many
+    /// there are.
+    pub fn publish(&mut self, topic: &str, message: Message) -> usize {
+        let subscribers = self.subscribers(topic);
+        for actor_id in subscribers.iter() {
+            let backlog = self.backlogs.entry(actor_id.clone()).or_default();
+            if backlog.len() >= self.backlog_capacity {
+                self.dead_letters
+                    .lock()
+                    .unwrap()
+                    .record(actor_id, message.clone(), DeadLetterReason::MailboxOverflow, None, 0);
+                continue;
+            }
+            backlog.push_back(Delivery {
+                id: self.next_id,
+                topic: topic.to_string(),
+                message: message.clone(),
+                attempts: 0,
+            });
+            self.next_id += 1;
+        }
+
+        if !subscribers.is_empty() {
+            for waker in self.watchers.drain(..) {
+                waker.wake();
+            }
+        }
+        subscribers.len()
+    }
+
+    /// The oldest message the actor has not processed yet. It stays in the
+    /// backlog until it is acknowledged.
+    pub fn next(&self, actor_id: &str) -> Option<Delivery> {
+        self.backlogs.get(actor_id).and_then(|backlog| backlog.front().cloned())
+    }
+
+    pub fn acknowledge(&mut self, actor_id: &str, id: DeliveryId) {
+        if let Some(backlog) = self.backlogs.get_mut(actor_id) {
+            backlog.retain(|delivery| delivery.id != id);
+            if backlog.is_empty() {
+                self.backlogs.remove(actor_id);
+            }
+        }
+    }
+
+    /// Counts a failed attempt to process a delivery, returning the number
+    /// of attempts so far.
+    pub fn record_failure(&mut self, actor_id: &str, id: DeliveryId) -> u32 {
+        self.backlogs
+            .get_mut(actor_id)
+            .and_then(|backlog| backlog.iter_mut().find(|delivery| delivery.id == id))
+            .map_or(0, |delivery| {
+                delivery.attempts += 1;
+                delivery.attempts
+            })
+    }
+
+    /// Drops all subscriptions of a stopped actor, returning the messages
+    /// it will no longer receive.
+    pub fn remove_subscriber(&mut self, actor_id: &str) -> Vec<Delivery> {
+        for topic in self.subscriptions(actor_id) {
+            self.unsubscribe(&topic, actor_id);
+        }
+        self.backlogs
+            .remove(actor_id)
+            .map(|backlog| backlog.into_iter().collect())
+            .unwrap_or_default()
+    }
+
+    /// Whether any actor selected by `owned` has messages waiting.
+    pub fn has_pending<F>(&self, owned: F) -> bool
+        where
+            F: Fn(&str) -> bool,
+    {
+        self.backlogs
+            .iter()
+            .any(|(actor_id, backlog)| !backlog.is_empty() && owned(actor_id))
+    }
+
+    /// Wakes `waker` the next time a message is published.
+    pub fn watch(&mut self, waker: &Waker) {
+        if !self.watchers.iter().any(|watcher| watcher.will_wake(waker)) {
+            self.watchers.push(waker.clone());
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::runtime::testing;
+
+    fn store(backlog_capacity: usize) -> TopicStore {
+        TopicStore::new(backlog_capacity, testing::dead_letters())
+    }
+
+    #[test]
+    fn test_publish_to_subscribers() {
+        let mut topics = store(10);
+        assert!(topics.subscribe("orders", "a"));
+        assert!(!topics.subscribe("orders", "a"));
+        topics.subscribe("orders", "b");
+        topics.subscribe("invoices", "b");
+
+        assert_eq!(topics.publish("orders", json!(1).into()), 2);
+        assert_eq!(topics.publish("shipments", json!(2).into()), 0);
+        assert_eq!(topics.next("a").unwrap().message, Message::Json(json!(1)));
+        assert_eq!(topics.next("b").unwrap().topic, "orders");
+        assert_eq!(topics.subscriptions("b"), vec!["invoices", "orders"]);
+    }
+
+    #[test]
+    fn test_delivery_until_acknowledged() {
+        let mut topics = store(10);
+        topics.subscribe("orders", "a");
+        topics.publish("orders", json!(1).into());
+        topics.publish("orders", json!(2).into());
+
+        let first = topics.next("a").unwrap();
+        assert_eq!(topics.record_failure("a", first.id), 1);
+        assert_eq!(topics.next("a").unwrap().id, first.id);
+
+        topics.acknowledge("a", first.id);
+        assert_eq!(topics.next("a").unwrap().message, Message::Json(json!(2)));
+        assert!(topics.has_pending(|id| id == "a"));
+        assert!(!topics.has_pending(|id| id == "b"));
+    }
+
+    #[test]
+    fn test_unsubscribe_keeps_backlog() {
+        let mut topics = store(10);
+        topics.subscribe("orders", "a");
+        topics.publish("orders", json!(1).into());
+
+        assert!(topics.unsubscribe("orders", "a"));
+        assert_eq!(topics.publish("orders", json!(2).into()), 0);
+        assert_eq!(topics.next("a").unwrap().message, Message::Json(json!(1)));
+
+        topics.subscribe("orders", "a");
+        assert_eq!(topics.remove_subscriber("a").len(), 1);
+        assert!(topics.subscribers("orders").is_empty());
+        assert!(topics.next("a").is_none());
+    }
+
+    #[test]
+    fn test_full_backlog_dead_letters() {
+        let dead_letters = testing::dead_letters();
+        let mut topics = TopicStore::new(1, dead_letters.clone());
+        topics.subscribe("orders", "a");
+        topics.publish("orders", json!(1).into());
+        topics.publish("orders", json!(2).into());
+
+        let letters = dead_letters.lock().unwrap().list(Some("a"));
+        assert_eq!(letters.len(), 1);
+        assert_eq!(letters[0].message, Message::Json(json!(2)));
+        assert_eq!(letters[0].reason, DeadLetterReason::MailboxOverflow);
+    }
+}
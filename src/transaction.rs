@@ -0,0 +1,155 @@
+//! Every invocation of `main` runs in a transaction. Its storage writes, the
+//! messages it publishes, the frames it sends to sockets, the events it
+//! emits and the replies it sends are held back until `main` returns, and
+//! are committed together or, if it throws, discarded together with its
+//! changes to the actor's state (see `Actor::invoke`). A caller whose reply
+//! was discarded receives the error instead.
+//!
+//! A worker runs one invocation at a time, so there is at most one open
+//! transaction per `State`.
+
+use crate::events::ActorEvent;
+use crate::message::Message;
+use crate::replies::{Reply, ReplyId};
+use crate::sockets::{SocketFrame, SocketId};
+use crate::state::State;
+use serde_json::Value;
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum Effect {
+    Publish { topic: String, message: Message },
+    Socket { socket_id: SocketId, frame: SocketFrame },
+    Emit { event: Value },
+}
+
+pub struct Transaction {
+    actor_id: String,
+    effects: Vec<Effect>,
+    /// Replies sent by the invocation, which are also what a memoized
+    /// reply is taken from.
+    replies: Vec<(ReplyId, Reply)>,
+}
+
+impl Transaction {
+    pub fn push(&mut self, effect: Effect) {
+        self.effects.push(effect);
+    }
+
+    /// Holds back a reply until the transaction commits. Returns false if
+    /// the invocation already replied under `reply_id`.
+    pub fn hold_reply(&mut self, reply_id: ReplyId, reply: Reply) -> bool {
+        if self.replies.iter().any(|(id, _)| *id == reply_id) {
+            return false;
+        }
+        self.replies.push((reply_id, reply));
+        true
+    }
+}
+
+/// The reply the open transaction sent to `reply_id`, if it sent one.
+pub fn sent_reply(state: &State, reply_id: ReplyId) -> Option<Message> {
+    let state = state.borrow();
+    let transaction = state.transaction.as_ref()?;
+    transaction
+        .replies
+        .iter()
+        .find(|(id, _)| *id == reply_id)
+        .and_then(|(_, reply)| match reply {
+            Reply::Value(value) => Some(Message::Json(value.clone())),
+            Reply::Binary(bytes) => Some(Message::Binary(bytes.clone())),
+            Reply::Stream(_) | Reply::Response(_) => None,
+        })
+}
+
+pub fn begin(state: &State, actor_id: &str) {
+    let mut state = state.borrow_mut();
+    debug_assert!(state.transaction.is_none(), "transactions cannot be nested");
+    state.storage.actor(actor_id).begin();
+    state.transaction = Some(Transaction {
+        actor_id: actor_id.to_string(),
+        effects: Vec::new(),
+        replies: Vec::new(),
+    });
+}
+
+pub fn commit(state: &State) {
+    let (transaction, topics, replies) = {
+        let mut state = state.borrow_mut();
+        let transaction = match state.transaction.take() {
+            Some(transaction) => transaction,
+            None => return,
+        };
+        state.storage.actor(&transaction.actor_id).commit();
+        (transaction, state.topics.clone(), state.replies.clone())
+    };
+
+    for effect in transaction.effects {
+        match effect {
+            Effect::Publish { topic, message } => {
+                topics.lock().unwrap().publish(&topic, message);
+            }
+            Effect::Socket { socket_id, frame } => {
+                state.borrow_mut().sockets.send(socket_id, frame);
+            }
+            Effect::Emit { event } => {
+                state.borrow_mut().events.send(&transaction.actor_id, ActorEvent::Emitted(event));
+            }
+        }
+    }
+
+    let mut replies = replies.lock().unwrap();
+    for (reply_id, reply) in transaction.replies {
+        replies.send(reply_id, reply);
+    }
+}
+
+pub fn rollback(state: &State) {
+    let mut state = state.borrow_mut();
+    if let Some(transaction) = state.transaction.take() {
+        state.storage.actor(&transaction.actor_id).rollback();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::runtime::testing;
+
+    fn state() -> State {
+        let state = testing::state();
+        state.borrow().topics.lock().unwrap().subscribe("orders", "b");
+        state
+    }
+
+    fn publish(state: &State, message: Message) {
+        let effect = Effect::Publish { topic: "orders".to_string(), message };
+        state.borrow_mut().transaction.as_mut().unwrap().push(effect);
+    }
+
+    #[test]
+    fn test_commit() {
+        let state = state();
+        begin(&state, "a");
+        state.borrow_mut().storage.actor("a").put("key".to_string(), json!(1));
+        publish(&state, json!(2).into());
+        assert!(state.borrow().topics.lock().unwrap().next("b").is_none());
+
+        commit(&state);
+        assert!(state.borrow().transaction.is_none());
+        assert_eq!(state.borrow_mut().storage.actor("a").get("key"), Some(&json!(1)));
+        assert_eq!(state.borrow().topics.lock().unwrap().next("b").unwrap().message, Message::Json(json!(2)));
+    }
+
+    #[test]
+    fn test_rollback() {
+        let state = state();
+        begin(&state, "a");
+        state.borrow_mut().storage.actor("a").put("key".to_string(), json!(1));
+        publish(&state, json!(2).into());
+
+        rollback(&state);
+        assert!(state.borrow().transaction.is_none());
+        assert_eq!(state.borrow_mut().storage.actor("a").get("key"), None);
+        assert!(state.borrow().topics.lock().unwrap().next("b").is_none());
+    }
+}
@@ -0,0 +1,187 @@
+use crate::module_loader::{ModuleBundle, BUNDLE_ROOT};
+use crate::source_maps::SourceMaps;
+use deno_core::{CoreIsolate, ErrBox, Op};
+use std::cell::RefCell;
+use std::fs;
+use std::io;
+use std::path::Path;
+use std::rc::Rc;
+
+const TRANSPILE_SOURCE: &str = include_str!("js/transpile.js");
+
+#[derive(Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct TranspileResult {
+    code: String,
+    source_map: Option<String>,
+    diagnostics: Vec<String>,
+}
+
+pub struct TranspiledModule {
+    pub code: String,
+    pub source_map: Option<Vec<u8>>,
+}
+
+/// Hosts the TypeScript compiler in its own isolate so that actor sources
+/// can be transpiled without a separate build step. Only syntactic
+/// transforms are applied (`ts.transpileModule`), no type checking happens.
+pub struct TypeScriptCompiler {
+    isolate: Box<CoreIsolate>,
+    result: Rc<RefCell<Option<Vec<u8>>>>,
+}
+
+impl TypeScriptCompiler {
+    pub fn new(typescript_source: &str) -> Result<Self, ErrBox> {
+        let mut isolate = CoreIsolate::new(deno_core::StartupData::None, false);
+        let result = Rc::new(RefCell::new(None));
+
+        let result_slot = result.clone();
+        isolate.register_op("op_transpile_result", move |_isolate, control, _buf| {
+            result_slot.borrow_mut().replace(control.to_vec());
+            Op::Sync(Box::from([]))
+        });
+
+        isolate.execute("typescript.js", typescript_source)?;
+        isolate.execute("golem_transpile.js", TRANSPILE_SOURCE)?;
+
+        Ok(Self { isolate, result })
+    }
+
+    pub fn from_file(path: &Path) -> Result<Self, ErrBox> {
+        let source = fs::read_to_string(path)?;
+        Self::new(&source)
+    }
+
+    pub fn transpile(&mut self, file_name: &str, source: &str) -> Result<TranspiledModule, ErrBox> {
+        let call = format!(
+            "golemTranspile({}, {});",
+            serde_json::to_string(file_name)?,
+            serde_json::to_string(source)?
+        );
+        self.isolate.execute("golem_transpile_call.js", &call)?;
+
+        let raw = self.result.borrow_mut().take().ok_or_else(|| {
+            ErrBox::from(io::Error::new(
+                io::ErrorKind::Other,
+                format!("the compiler produced no output for '{}'", file_name),
+            ))
+        })?;
+        let result: TranspileResult = serde_json::from_slice(&raw)?;
+
+        if !result.diagnostics.is_empty() {
+            return Err(ErrBox::from(io::Error::new(
+                io::ErrorKind::InvalidData,
+                format!("{}: {}", file_name, result.diagnostics.join("\n")),
+            )));
+        }
+
+        Ok(TranspiledModule {
+            code: result.code,
+            source_map: result.source_map.map(String::into_bytes),
+        })
+    }
+
+    /// Transpiles every `.ts` module of the bundle. JavaScript modules are
+    /// passed through untouched. Module paths, and therefore the import
+    /// specifiers that refer to them, are left as they are.
+    pub fn transpile_bundle(&mut self, bundle: &ModuleBundle) -> Result<(ModuleBundle, SourceMaps), ErrBox> {
+        let mut output = ModuleBundle::new(&bundle.main);
+        let mut source_maps = SourceMaps::new();
+
+        for (path, source) in bundle.modules.iter() {
+            if !path.ends_with(".ts") {
+                output = output.with_module(path, source);
+                continue;
+            }
+
+            let url = format!("{}{}", BUNDLE_ROOT, path);
+            let transpiled = self.transpile(&url, source)?;
+            if let Some(source_map) = transpiled.source_map {
+                source_maps.insert(url, source_map);
+            }
+            output = output.with_module(path, &transpiled.code);
+        }
+
+        Ok((output, source_maps))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::source_maps::apply_source_maps;
+    use deno_core::{JSError, JSStackFrame};
+
+    /// Stands in for `typescript.js`: drops `: number` annotations and maps
+    /// the first line of the output, `export function add(a, b) {`, back
+    /// to the source token by token.
+    const STUB_TYPESCRIPT: &str = r#"
+        var ts = {
+            ModuleKind: { ESNext: 99 },
+            ScriptTarget: { ES2019: 6 },
+            flattenDiagnosticMessageText: (text) => text,
+            transpileModule(source, options) {
+                const diagnostics = source.includes("@error") ? [{ messageText: "';' expected." }] : [];
+                return {
+                    outputText: source.replace(/: number/g, ""),
+                    sourceMapText: JSON.stringify({
+                        version: 3,
+                        sources: [options.fileName],
+                        names: [],
+                        mappings: "AAAA,oBAAoB,GAAW",
+                    }),
+                    diagnostics,
+                };
+            },
+        };
+    "#;
+
+    fn frame(line_number: i64, column: i64) -> JSStackFrame {
+        JSStackFrame {
+            line_number,
+            column,
+            script_name: "golem:///math.ts".to_string(),
+            function_name: "add".to_string(),
+            is_eval: false,
+            is_constructor: false,
+            is_async: false,
+        }
+    }
+
+    #[test]
+    fn test_transpile_bundle() {
+        let mut compiler = TypeScriptCompiler::new(STUB_TYPESCRIPT).unwrap();
+        let bundle = ModuleBundle::new("main.js")
+            .with_module("main.js", "export { add } from './math.ts';")
+            .with_module("math.ts", "export function add(a: number, b: number) {\n  return a + b;\n}");
+
+        let (output, source_maps) = compiler.transpile_bundle(&bundle).unwrap();
+
+        assert_eq!(output.modules["main.js"], bundle.modules["main.js"]);
+        assert_eq!(output.modules["math.ts"], "export function add(a, b) {\n  return a + b;\n}");
+        assert_eq!(source_maps.len(), 1);
+        assert!(source_maps.contains_key("golem:///math.ts"));
+
+        // `b` is at column 24 of the output and column 32 of the source,
+        // nothing on the second line of the output is mapped.
+        let error = JSError {
+            message: "Uncaught Error".to_string(),
+            source_line: None,
+            script_resource_name: None,
+            line_number: None,
+            start_column: None,
+            end_column: None,
+            frames: vec![frame(1, 24), frame(2, 3)],
+        };
+        let remapped = apply_source_maps(error, &source_maps);
+        assert_eq!((remapped.frames[0].line_number, remapped.frames[0].column), (1, 32));
+        assert_eq!(remapped.frames[1], frame(2, 3));
+    }
+
+    #[test]
+    fn test_diagnostics_fail_the_transpilation() {
+        let mut compiler = TypeScriptCompiler::new(STUB_TYPESCRIPT).unwrap();
+        let result = compiler.transpile("golem:///main.ts", "// @error");
+        assert!(result.unwrap_err().to_string().contains("';' expected."));
+    }
+}
@@ -0,0 +1,391 @@
+//! Sandboxed filesystems that give actors scratch space, exposed to
+//! handlers as `ctx.fs`.
+//!
+//! Every actor gets its own directory below the worker's VFS root. Paths
+//! passed by actors are always resolved relative to that directory, and
+//! paths that would leave it, through `..` or a symlink, are rejected. The
+//! bytes stored in a sandbox are limited by a quota, which writes to files
+//! opened from it are checked against. Every file and directory counts for
+//! `ENTRY_SIZE` bytes besides its contents, so that empty ones are not
+//! free.
+//!
+//! Unlike storage, files are not part of the invocation's transaction:
+//! writes are visible immediately and are kept if `main` throws.
+//!
+//! Only actors whose manifest asks for the `fs` permission have a sandbox.
+
+use crate::actor::ActorId;
+use crate::op_error::OpError;
+use crate::resources::{ActorResource, Capability};
+use std::cell::Cell;
+use std::collections::HashMap;
+use std::fs;
+use std::io::{self, Read, Seek, SeekFrom, Write};
+use std::path::{Component, Path, PathBuf};
+use std::process;
+use std::rc::Rc;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::task::{Context, Poll};
+
+pub const DEFAULT_QUOTA: u64 = 64 * 1024 * 1024;
+
+/// What a file or directory is charged on top of its contents.
+pub const ENTRY_SIZE: u64 = 1024;
+
+static NEXT_ROOT: AtomicU64 = AtomicU64::new(0);
+
+/// The bytes an actor may store, shared by its sandbox and the files opened
+/// from it.
+#[derive(Debug)]
+pub struct Quota {
+    limit: u64,
+    used: Cell<u64>,
+}
+
+impl Quota {
+    pub fn new(limit: u64, used: u64) -> Self {
+        Self {
+            limit,
+            used: Cell::new(used),
+        }
+    }
+
+    pub fn used(&self) -> u64 {
+        self.used.get()
+    }
+
+    /// Fails if growing the sandbox by `bytes` would exceed the quota.
+    pub fn check(&self, bytes: u64) -> Result<(), OpError> {
+        if self.used.get().saturating_add(bytes) > self.limit {
+            return Err(OpError::permission_denied(format!(
+                "writing {} bytes would exceed the filesystem quota of {} bytes",
+                bytes, self.limit
+            )));
+        }
+        Ok(())
+    }
+
+    pub fn charge(&self, bytes: u64) {
+        self.used.set(self.used.get().saturating_add(bytes));
+    }
+
+    pub fn release(&self, bytes: u64) {
+        self.used.set(self.used.get().saturating_sub(bytes));
+    }
+}
+
+pub struct Sandbox {
+    root: PathBuf,
+    quota: Rc<Quota>,
+}
+
+impl Sandbox {
+    /// Creates the sandbox directory if needed, counting the entries already
+    /// in it, but not the directory itself, against the quota.
+    pub fn open(root: PathBuf, limit: u64) -> io::Result<Self> {
+        fs::create_dir_all(&root)?;
+        let root = root.canonicalize()?;
+        let used = disk_usage(&root)?.saturating_sub(ENTRY_SIZE);
+        Ok(Self {
+            root,
+            quota: Rc::new(Quota::new(limit, used)),
+        })
+    }
+
+    pub fn root(&self) -> &Path {
+        &self.root
+    }
+
+    pub fn quota(&self) -> &Rc<Quota> {
+        &self.quota
+    }
+
+    /// Maps a path inside the sandbox, where `/` is the sandbox directory,
+    /// to a path on disk.
+    pub fn resolve(&self, path: &str) -> Result<PathBuf, OpError> {
+        let mut resolved = self.root.clone();
+        let mut depth = 0;
+        for component in Path::new(path).components() {
+            match component {
+                Component::RootDir | Component::CurDir => {}
+                Component::Normal(part) => {
+                    resolved.push(part);
+                    depth += 1;
+                }
+                Component::ParentDir if depth > 0 => {
+                    resolved.pop();
+                    depth -= 1;
+                }
+                Component::ParentDir | Component::Prefix(_) => return Err(escapes_sandbox(path)),
+            }
+        }
+
+        // Catch symlinks pointing out of the sandbox, also when they are the
+        // parent of a file that is about to be created.
+        let existing = resolved.ancestors().find(|ancestor| ancestor.exists());
+        if let Some(canonical) = existing.and_then(|ancestor| ancestor.canonicalize().ok()) {
+            if !canonical.starts_with(&self.root) {
+                return Err(escapes_sandbox(path));
+            }
+        }
+        Ok(resolved)
+    }
+
+    /// Creates a directory at a resolved path, with its missing parents if
+    /// `recursive`, charging each one that is created against the quota.
+    pub fn create_dir(&self, path: &Path, recursive: bool) -> Result<(), OpError> {
+        let created = if recursive {
+            path.ancestors().take_while(|ancestor| !ancestor.exists()).count() as u64
+        } else {
+            1
+        };
+        self.quota.check(created * ENTRY_SIZE)?;
+        if recursive {
+            fs::create_dir_all(path)?;
+        } else {
+            fs::create_dir(path)?;
+        }
+        self.quota.charge(created * ENTRY_SIZE);
+        Ok(())
+    }
+}
+
+/// A file opened from a sandbox. Files are local, so reads and writes are
+/// performed synchronously even when requested by an async op.
+pub struct VirtualFile {
+    file: fs::File,
+    quota: Rc<Quota>,
+}
+
+impl VirtualFile {
+    pub fn new(file: fs::File, quota: Rc<Quota>) -> Self {
+        Self { file, quota }
+    }
+}
+
+impl ActorResource for VirtualFile {
+    fn capability(&self) -> Capability {
+        Capability::VirtualFile
+    }
+
+    fn poll_read(&mut self, _cx: &mut Context, buf: &mut [u8]) -> Poll<Result<usize, OpError>> {
+        Poll::Ready(self.read_sync(buf))
+    }
+
+    fn poll_write(&mut self, _cx: &mut Context, buf: &[u8]) -> Poll<Result<usize, OpError>> {
+        Poll::Ready(self.write_sync(buf))
+    }
+
+    fn read_sync(&mut self, buf: &mut [u8]) -> Result<usize, OpError> {
+        Ok(self.file.read(buf)?)
+    }
+
+    /// Checks the growth of the file against the quota before writing.
+    fn write_sync(&mut self, buf: &[u8]) -> Result<usize, OpError> {
+        let length = self.file.metadata()?.len();
+        let position = self.file.seek(SeekFrom::Current(0))?;
+        self.quota.check((position + buf.len() as u64).saturating_sub(length))?;
+        let nwritten = self.file.write(buf)?;
+        self.quota.charge(self.file.metadata()?.len().saturating_sub(length));
+        Ok(nwritten)
+    }
+}
+
+fn escapes_sandbox(path: &str) -> OpError {
+    OpError::permission_denied(format!("'{}' is outside of the actor's filesystem", path))
+}
+
+/// What `path` and everything below it count for against the quota: the
+/// size of the files, and `ENTRY_SIZE` for every entry.
+pub fn disk_usage(path: &Path) -> io::Result<u64> {
+    let metadata = fs::symlink_metadata(path)?;
+    if !metadata.is_dir() {
+        return Ok(metadata.len() + ENTRY_SIZE);
+    }
+    let mut total = ENTRY_SIZE;
+    for entry in fs::read_dir(path)? {
+        total += disk_usage(&entry?.path())?;
+    }
+    Ok(total)
+}
+
+/// The sandboxes of all actors owned by a worker.
+pub struct Vfs {
+    root: PathBuf,
+    quota: u64,
+    sandboxes: HashMap<ActorId, Sandbox>,
+    /// Whether the root is deleted with the VFS, see `Vfs::default`.
+    temporary: bool,
+}
+
+impl Vfs {
+    pub fn new(root: PathBuf, quota: u64) -> Self {
+        Self {
+            root,
+            quota,
+            sandboxes: HashMap::new(),
+            temporary: false,
+        }
+    }
+
+    pub fn sandbox(&mut self, actor_id: &str) -> Result<&Sandbox, OpError> {
+        if !self.sandboxes.contains_key(actor_id) {
+            let sandbox = Sandbox::open(self.root.join(directory_name(actor_id)), self.quota)?;
+            self.sandboxes.insert(actor_id.to_string(), sandbox);
+        }
+        Ok(&self.sandboxes[actor_id])
+    }
+
+    /// Deletes the sandbox of a stopped actor.
+    pub fn remove(&mut self, actor_id: &str) {
+        self.sandboxes.remove(actor_id);
+        let directory = self.root.join(directory_name(actor_id));
+        if let Err(error) = fs::remove_dir_all(&directory) {
+            if error.kind() != io::ErrorKind::NotFound {
+                warn!("failed to delete the filesystem of actor {}: {}", actor_id, error);
+            }
+        }
+    }
+}
+
+/// A VFS in a temporary directory of its own, so that the files of
+/// different runtimes, or of tests running in parallel, are kept apart. The
+/// directory is deleted when the VFS is dropped.
+impl Default for Vfs {
+    fn default() -> Self {
+        let mut vfs = Self::new(temporary_root("golem-vfs"), DEFAULT_QUOTA);
+        vfs.temporary = true;
+        vfs
+    }
+}
+
+impl Drop for Vfs {
+    fn drop(&mut self) {
+        if self.temporary {
+            self.sandboxes.clear();
+            if let Err(error) = fs::remove_dir_all(&self.root) {
+                if error.kind() != io::ErrorKind::NotFound {
+                    warn!("failed to delete the filesystem root {}: {}", self.root.display(), error);
+                }
+            }
+        }
+    }
+}
+
+/// A path in the temporary directory that no other VFS uses, in this
+/// process or another.
+fn temporary_root(prefix: &str) -> PathBuf {
+    let index = NEXT_ROOT.fetch_add(1, Ordering::Relaxed);
+    std::env::temp_dir().join(format!("{}-{}-{}", prefix, process::id(), index))
+}
+
+/// Actor IDs are arbitrary strings, so everything but ASCII letters, digits,
+/// `-` and `_` is escaped to get a valid and unique directory name.
+fn directory_name(actor_id: &str) -> String {
+    let mut name = String::with_capacity(actor_id.len());
+    for byte in actor_id.bytes() {
+        match byte {
+            b'a'..=b'z' | b'A'..=b'Z' | b'0'..=b'9' | b'-' | b'_' => name.push(byte as char),
+            _ => name.push_str(&format!("%{:02x}", byte)),
+        }
+    }
+    name
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn sandbox() -> Sandbox {
+        Sandbox::open(temporary_root("golem-vfs-test"), 100).unwrap()
+    }
+
+    #[test]
+    fn test_resolve() {
+        let sandbox = sandbox();
+        assert_eq!(sandbox.resolve("/a/b.txt").unwrap(), sandbox.root.join("a/b.txt"));
+        assert_eq!(sandbox.resolve("a/../b.txt").unwrap(), sandbox.root.join("b.txt"));
+        assert_eq!(sandbox.resolve("").unwrap(), sandbox.root);
+    }
+
+    #[test]
+    fn test_traversal_is_rejected() {
+        let sandbox = sandbox();
+        assert!(sandbox.resolve("..").is_err());
+        assert!(sandbox.resolve("/a/../../etc/passwd").is_err());
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn test_symlink_escape_is_rejected() {
+        let sandbox = sandbox();
+        std::os::unix::fs::symlink(std::env::temp_dir(), sandbox.root.join("tmp")).unwrap();
+        assert!(sandbox.resolve("tmp").is_err());
+        assert!(sandbox.resolve("tmp/new.txt").is_err());
+    }
+
+    #[test]
+    fn test_quota() {
+        let quota = Quota::new(10, 4);
+        assert!(quota.check(6).is_ok());
+        assert!(quota.check(7).is_err());
+        quota.charge(6);
+        assert!(quota.check(1).is_err());
+        quota.release(2);
+        assert_eq!(quota.used(), 8);
+    }
+
+    #[test]
+    fn test_writes_are_checked_against_quota() {
+        let sandbox = sandbox();
+        let path = sandbox.resolve("a.txt").unwrap();
+        let file = fs::OpenOptions::new().read(true).write(true).create(true).open(&path).unwrap();
+        let mut file = VirtualFile::new(file, sandbox.quota().clone());
+
+        assert_eq!(file.write_sync(&[0; 60]).unwrap(), 60);
+        file.file.seek(SeekFrom::Start(0)).unwrap();
+        assert_eq!(file.write_sync(&[1; 60]).unwrap(), 60);
+        assert_eq!(sandbox.quota().used(), 60);
+        assert!(file.write_sync(&[2; 41]).is_err());
+    }
+
+    #[test]
+    fn test_existing_files_count_against_quota() {
+        let root = temporary_root("golem-vfs-test");
+        fs::create_dir_all(root.join("a")).unwrap();
+        fs::write(root.join("a/b.txt"), b"hello").unwrap();
+
+        let sandbox = Sandbox::open(root, 100).unwrap();
+        assert_eq!(sandbox.quota().used(), 5 + 2 * ENTRY_SIZE);
+    }
+
+    #[test]
+    fn test_directories_count_against_quota() {
+        let sandbox = Sandbox::open(temporary_root("golem-vfs-test"), 3 * ENTRY_SIZE).unwrap();
+        sandbox.create_dir(&sandbox.resolve("a/b").unwrap(), true).unwrap();
+        assert_eq!(sandbox.quota().used(), 2 * ENTRY_SIZE);
+        assert_eq!(disk_usage(&sandbox.resolve("a").unwrap()).unwrap(), 2 * ENTRY_SIZE);
+
+        sandbox.create_dir(&sandbox.resolve("c").unwrap(), false).unwrap();
+        assert!(sandbox.create_dir(&sandbox.resolve("d").unwrap(), false).is_err());
+        assert!(!sandbox.resolve("d").unwrap().exists());
+    }
+
+    #[test]
+    fn test_default_roots_are_unique_and_deleted() {
+        let mut first = Vfs::default();
+        let second = Vfs::default();
+        assert_ne!(first.root, second.root);
+
+        let root = first.sandbox("a").unwrap().root.clone();
+        assert!(root.exists());
+        drop(first);
+        assert!(!root.exists());
+    }
+
+    #[test]
+    fn test_directory_name() {
+        assert_eq!(directory_name("counter-1"), "counter-1");
+        assert_eq!(directory_name("../a/b"), "%2e%2e%2fa%2fb");
+    }
+}